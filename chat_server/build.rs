@@ -0,0 +1,4 @@
+fn main() {
+    // the migrations are embedded to check the database schema version on readiness
+    println!("cargo:rerun-if-changed=../migrations");
+}
@@ -9,6 +9,9 @@ INSERT INTO users (ws_id, fullname, email, password_hash) VALUES
 (1, 'David', 'David@test.org', '$argon2id$v=19$m=19456,t=2,p=1$CsXTc5LX86DPIp0OFbLg/w$aZhy+a3yHCqmd39zYQnY+V/WZX+T5UcHJadv4A8v2/0'),
 (1, 'Eve', 'Eve@test.org', '$argon2id$v=19$m=19456,t=2,p=1$CsXTc5LX86DPIp0OFbLg/w$aZhy+a3yHCqmd39zYQnY+V/WZX+T5UcHJadv4A8v2/0');
 
+-- every user is a member of its workspace
+INSERT INTO workspace_members (ws_id, user_id) SELECT ws_id, id FROM users ON CONFLICT DO NOTHING;
+
 -- insert chats
 INSERT INTO chats (ws_id, name, type, members) VALUES
 (1, 'Private Channel', 'private_channel', '{1, 2}'),
@@ -1,12 +1,47 @@
-use std::{env, fs::File, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{bail, Result};
-use serde::{Deserialize, Serialize};
+use lettre::message::Mailbox;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_yaml::{Mapping, Value};
+use sqlx::postgres::PgConnectOptions;
+use tracing_subscriber::{filter::ParseError, EnvFilter};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::{DecodingKey, EncodingKey};
+
+// env vars overriding the config are named `CHAT_<SECTION>__<KEY>`, e.g. `CHAT_SERVER__PORT`
+const ENV_PREFIX: &str = "CHAT_";
+const ENV_SEPARATOR: &str = "__";
+// `CHAT_AUTH__SK_FILE=/run/secrets/sk` reads the value of `auth.sk` from the file
+const ENV_FILE_SUFFIX: &str = "_FILE";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub scheduled: ScheduledConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,28 +49,667 @@ pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
     pub base_dir: PathBuf,
+    /// seconds to wait for the in-flight requests on shutdown
+    pub drain_timeout: u64,
+    /// port of the `/metrics` endpoint, not served when unset
+    #[serde(default, deserialize_with = "optional_from_str")]
+    pub metrics_port: Option<u16>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AuthConfig {
     pub sk: String,
     pub pk: String,
+    /// ids of the users allowed to call the `/api/admin` endpoints
+    #[serde(default)]
+    pub admins: Vec<i64>,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+}
+
+/// brute-force protection of signin, all durations are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// failed signins of an account before it is locked
+    pub max_failures: u32,
+    /// failed signins from an ip, whatever the account, before the ip is locked
+    pub max_ip_failures: u32,
+    /// failures older than this are forgotten
+    pub window: u64,
+    /// wait after the first failure of an account, doubled by each further failure
+    pub base_delay: u64,
+    /// how long an account or ip stays locked
+    pub duration: u64,
+}
+
+/// orphaned file garbage collection, all durations are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GcConfig {
+    /// interval between two background runs, 0 disables the background task. The runs also
+    /// prune the expired rate limit buckets and signin failures
+    pub interval: u64,
+    /// files modified more recently than this are never collected
+    pub grace_period: u64,
+}
+
+/// purge of the messages older than the retention of their chat
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// interval in seconds between two background runs, 0 disables the background task
+    pub interval: u64,
+    /// messages deleted per statement, keeps the transactions short
+    pub batch_size: u64,
+}
+
+/// delivery of the scheduled messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScheduledConfig {
+    /// interval in seconds between two polls of the due messages, 0 disables the worker
+    pub interval: u64,
+}
+
+/// cleanup of the workspace exports, all durations are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    /// interval between two background runs, 0 disables the background task
+    pub interval: u64,
+    /// the archive of a done export is removed this long after it was written
+    pub ttl: u64,
+    /// an export not done after this long was interrupted, e.g. by a restart, and is failed
+    pub timeout: u64,
+}
+
+/// token bucket rate limits, keyed by client ip on the public routes and by user id on the
+/// authenticated ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub store: RateLimitStoreKind,
+    /// take the client ip from the first `x-forwarded-for` address, only behind a trusted proxy
+    pub trust_proxy: bool,
+    /// limit of the routes without their own entry, unlimited if unset
+    pub default: Option<RateLimit>,
+    /// limits by method and matched route, e.g. `POST /api/signin`
+    pub routes: BTreeMap<String, RateLimit>,
+}
+
+/// a bucket holds up to burst requests and is refilled at burst requests per period seconds
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub period: u64,
+}
+
+/// where the buckets are kept, the postgres store shares the limits between replicas
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitStoreKind {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// `RUST_LOG` style directives, e.g. `info,chat_server=debug`, the `RUST_LOG` env var wins
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// otlp/http traces endpoint, e.g. `http://localhost:4318/v1/traces`, spans are only
+    /// logged if unset
+    pub otlp_endpoint: Option<String>,
+}
+
+/// outgoing emails, e.g. the email verification and password reset links
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// sender of the emails, e.g. `Chat <noreply@example.com>`
+    pub from: String,
+    /// the links in the emails point to the pages under this url
+    pub base_url: String,
+    /// where the file transport writes the `.eml` files
+    pub dir: PathBuf,
+    pub smtp: SmtpConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MailTransport {
+    Smtp,
+    /// write the emails to files instead of sending them, for development
+    #[default]
+    File,
+    /// keep the emails in memory, for tests
+    Memory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: SmtpTls,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// plain text, only for a local relay
+    None,
+    #[default]
+    Starttls,
+    /// implicit tls, usually on port 465
+    Tls,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 6688,
+            db_url: "postgres://localhost:5432/chat".to_string(),
+            base_dir: PathBuf::from("/tmp/chat_server"),
+            drain_timeout: 30,
+            metrics_port: None,
+        }
+    }
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            max_ip_failures: 20,
+            window: 15 * 60,
+            base_delay: 1,
+            duration: 15 * 60,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let routes = [
+            ("POST /api/signin", RateLimit::new(10, 60)),
+            ("POST /api/signin/mfa", RateLimit::new(10, 60)),
+            ("POST /api/signup", RateLimit::new(5, 60 * 60)),
+            ("POST /api/password/forgot", RateLimit::new(5, 60 * 60)),
+            ("POST /api/chats/:id", RateLimit::new(30, 10)),
+        ];
+        Self {
+            store: RateLimitStoreKind::Memory,
+            trust_proxy: false,
+            default: None,
+            routes: routes
+                .into_iter()
+                .map(|(route, limit)| (route.to_string(), limit))
+                .collect(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::File,
+            from: "Chat <noreply@localhost>".to_string(),
+            base_url: "http://localhost:6688".to_string(),
+            dir: PathBuf::from("/tmp/chat_server/mails"),
+            smtp: SmtpConfig::default(),
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 587,
+            username: None,
+            password: None,
+            tls: SmtpTls::Starttls,
+        }
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60 * 60,
+            grace_period: 60 * 60 * 24,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60 * 60,
+            batch_size: 1000,
+        }
+    }
+}
+
+impl Default for ScheduledConfig {
+    fn default() -> Self {
+        Self { interval: 5 }
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60 * 60,
+            ttl: 60 * 60 * 24 * 7,
+            timeout: 60 * 60 * 6,
+        }
+    }
 }
 
 impl AppConfig {
+    /// load the config in layers: defaults, then the yaml file, then the `CHAT_` env vars,
+    /// then the `_FILE` secrets, and validate the result
     pub fn try_load() -> Result<Self> {
-        // read from /etc/config/app.yml, or ./app.yml or from env CHAT_CONFIG
-        let ret = match (
-            env::var("CHAT_CONFIG"),
-            File::open("app.yml"),
-            File::open("/etc/config/app.yml"),
-        ) {
-            (Ok(file), _, _) => serde_yaml::from_reader(File::open(file)?),
-            (_, Ok(file), _) => serde_yaml::from_reader(file),
-            (_, _, Ok(file)) => serde_yaml::from_reader(file),
-            // _ => return Err(anyhow::anyhow!("Config file not found")),
-            _ => bail!("Config file not found"),
+        // read from env CHAT_CONFIG, or ./app.yml or /etc/config/app.yml
+        let file = match env::var("CHAT_CONFIG") {
+            Ok(file) => Some(fs::File::open(file)?),
+            Err(_) => fs::File::open("app.yml")
+                .or_else(|_| fs::File::open("/etc/config/app.yml"))
+                .ok(),
+        };
+        let yaml = match file {
+            Some(file) => Some(serde_yaml::from_reader(file)?),
+            None => None,
         };
-        Ok(ret?)
+        let config = Self::from_layers(yaml, env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_layers(
+        yaml: Option<Value>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut value = serde_yaml::to_value(Self::default())?;
+        if let Some(yaml) = yaml {
+            merge(&mut value, yaml);
+        }
+
+        let mut errors = vec![];
+        let (secrets, vars): (Vec<_>, Vec<_>) = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.contains(ENV_SEPARATOR))
+            .partition(|(name, _)| name.ends_with(ENV_FILE_SUFFIX));
+        for (name, v) in vars {
+            if let Err(e) = set_env_value(&mut value, &name, v) {
+                errors.push(e);
+            }
+        }
+        for (name, path) in secrets {
+            let key = name.trim_end_matches(ENV_FILE_SUFFIX);
+            // secret files usually end with a newline, which isn't part of the value
+            let ret = fs::read_to_string(&path)
+                .map_err(|e| format!("{}: failed to read {}: {}", name, path, e))
+                .and_then(|v| {
+                    let v = v.trim_end_matches(['\r', '\n']).to_string();
+                    set_env_value(&mut value, key, v)
+                });
+            if let Err(e) = ret {
+                errors.push(e);
+            }
+        }
+        if !errors.is_empty() {
+            bail!("invalid config:\n  {}", errors.join("\n  "));
+        }
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    /// check every field and report all the invalid ones at once
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        if self.server.port == 0 {
+            errors.push("server.port: must not be 0".to_string());
+        }
+        if self.server.metrics_port == Some(self.server.port) {
+            errors.push("server.metrics_port: must not be server.port".to_string());
+        }
+        if let Err(e) = PgConnectOptions::from_str(&self.server.db_url) {
+            errors.push(format!("server.db_url: {}", e));
+        }
+        if let Err(e) = check_dir(&self.server.base_dir) {
+            errors.push(format!(
+                "server.base_dir: {}: {}",
+                self.server.base_dir.display(),
+                e
+            ));
+        }
+        if let Err(e) = EncodingKey::load(&self.auth.sk) {
+            errors.push(format!("auth.sk: {}", e));
+        }
+        if let Err(e) = DecodingKey::load(&self.auth.pk) {
+            errors.push(format!("auth.pk: {}", e));
+        }
+        if self.auth.lockout.max_failures == 0 || self.auth.lockout.max_ip_failures == 0 {
+            errors.push("auth.lockout: max failures must not be 0".to_string());
+        }
+        if self.export.timeout == 0 {
+            errors.push("export.timeout: must not be 0".to_string());
+        }
+        if self.retention.batch_size == 0 {
+            errors.push("retention.batch_size: must not be 0".to_string());
+        }
+        for (route, limit) in self.rate_limit.limits() {
+            if limit.burst == 0 || limit.period == 0 {
+                errors.push(format!(
+                    "rate_limit.{}: burst and period must not be 0",
+                    route
+                ));
+            }
+        }
+        if let Err(e) = self.log.validate() {
+            errors.push(format!("log.filter: {}", e));
+        }
+        if let Err(e) = self.mail.from.parse::<Mailbox>() {
+            errors.push(format!("mail.from: {}", e));
+        }
+        if self.mail.transport == MailTransport::Smtp && self.mail.smtp.host.is_empty() {
+            errors.push("mail.smtp.host: must not be empty".to_string());
+        }
+        if !errors.is_empty() {
+            bail!("invalid config:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+impl RateLimitConfig {
+    /// limit of the route, e.g. `POST /api/signin`
+    pub fn limit(&self, route: &str) -> Option<RateLimit> {
+        self.routes.get(route).or(self.default.as_ref()).copied()
+    }
+
+    /// longest period of the limits, any bucket is full again after it
+    pub fn max_period(&self) -> u64 {
+        self.limits().map(|(_, l)| l.period).max().unwrap_or(0)
+    }
+
+    // every configured limit, with the config key it is found under
+    fn limits(&self) -> impl Iterator<Item = (String, &RateLimit)> {
+        let default = self.default.iter().map(|l| ("default".to_string(), l));
+        let routes = self
+            .routes
+            .iter()
+            .map(|(route, l)| (format!("routes.{:?}", route), l));
+        default.chain(routes)
+    }
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, period: u64) -> Self {
+        Self { burst, period }
+    }
+
+    /// tokens added to the bucket per second
+    pub fn rate(&self) -> f64 {
+        self.burst as f64 / self.period as f64
+    }
+}
+
+impl LogConfig {
+    pub fn validate(&self) -> Result<(), ParseError> {
+        EnvFilter::try_new(&self.filter).map(|_| ())
+    }
+}
+
+// deep merge the mappings of other into value
+fn merge(value: &mut Value, other: Value) {
+    match (value, other) {
+        (Value::Mapping(value), Value::Mapping(other)) => {
+            for (k, v) in other {
+                match value.get_mut(&k) {
+                    Some(old) => merge(old, v),
+                    None => {
+                        value.insert(k, v);
+                    }
+                }
+            }
+        }
+        (value, other) => *value = other,
+    }
+}
+
+// set the field named by the env var, keeping the type of the current value
+fn set_env_value(value: &mut Value, name: &str, v: String) -> Result<(), String> {
+    let path = name[ENV_PREFIX.len()..]
+        .split(ENV_SEPARATOR)
+        .map(|k| Value::String(k.to_lowercase()));
+    let mut field = value;
+    for key in path {
+        field = field
+            .as_mapping_mut()
+            .and_then(|m: &mut Mapping| m.get_mut(&key))
+            .ok_or_else(|| format!("{}: unknown config key", name))?;
+    }
+    // optional fields are unset by default, their type is only known from the struct: the value
+    // is kept as is, e.g. a password of digits, and the optional numbers parse it
+    if field.is_null() || field.is_string() {
+        *field = Value::String(v);
+        return Ok(());
+    }
+    match serde_yaml::from_str::<Value>(&v) {
+        Ok(new) if std::mem::discriminant(&new) == std::mem::discriminant(field) => *field = new,
+        _ => return Err(format!("{}: invalid value {:?}", name, v)),
+    }
+    Ok(())
+}
+
+// an optional field set by an env var is a string, see set_env_value
+fn optional_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw<T> {
+        Value(T),
+        Str(String),
+    }
+    match Option::<Raw<T>>::deserialize(deserializer)? {
+        Some(Raw::Value(v)) => Ok(Some(v)),
+        Some(Raw::Str(s)) => s.parse().map(Some).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn check_dir(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::read_dir(dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn config_layers_should_work() -> Result<()> {
+        let yaml = serde_yaml::from_str(include_str!("../app.yml"))?;
+        let sk_file = env::temp_dir().join(format!("chat-sk-{}", uuid::Uuid::now_v7()));
+        fs::write(&sk_file, "secret\n")?;
+        let password_file = env::temp_dir().join(format!("chat-pw-{}", uuid::Uuid::now_v7()));
+        fs::write(&password_file, "123456\n")?;
+
+        let config = AppConfig::from_layers(
+            Some(yaml),
+            vars(&[
+                ("CHAT_SERVER__PORT", "9090"),
+                ("CHAT_SERVER__DB_URL", "postgres://db:5432/chat"),
+                ("CHAT_AUTH__SK_FILE", sk_file.to_str().unwrap()),
+                ("CHAT_GC__INTERVAL", "0"),
+                ("CHAT_AUTH__ADMINS", "[1, 2]"),
+                ("CHAT_LOG__FORMAT", "json"),
+                ("CHAT_RATE_LIMIT__STORE", "postgres"),
+                ("CHAT_MAIL__SMTP__PORT", "465"),
+                ("CHAT_MAIL__SMTP__USERNAME", "007"),
+                (
+                    "CHAT_MAIL__SMTP__PASSWORD_FILE",
+                    password_file.to_str().unwrap(),
+                ),
+                (
+                    "CHAT_TELEMETRY__OTLP_ENDPOINT",
+                    "http://collector:4318/v1/traces",
+                ),
+                ("CHAT_CONFIG", "ignored.yml"),
+                ("HOME", "/root"),
+            ]),
+        )?;
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.server.db_url, "postgres://db:5432/chat");
+        assert_eq!(config.server.base_dir, PathBuf::from("/tmp/chat-server"));
+        assert_eq!(config.auth.sk, "secret");
+        assert!(config.auth.pk.starts_with("-----BEGIN PUBLIC KEY-----"));
+        assert_eq!(config.gc.interval, 0);
+        assert_eq!(config.gc.grace_period, 86400);
+        assert_eq!(config.auth.admins, vec![1, 2]);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.filter, "info");
+        assert_eq!(config.rate_limit.store, RateLimitStoreKind::Postgres);
+        assert_eq!(
+            config.rate_limit.limit("POST /api/signin"),
+            Some(RateLimit::new(10, 60))
+        );
+        assert_eq!(config.rate_limit.limit("GET /api/users"), None);
+        assert_eq!(config.mail.transport, MailTransport::File);
+        assert_eq!(config.mail.smtp.port, 465);
+        assert_eq!(
+            config.telemetry.otlp_endpoint.as_deref(),
+            Some("http://collector:4318/v1/traces")
+        );
+        // unset by default, still strings
+        assert_eq!(config.mail.smtp.username.as_deref(), Some("007"));
+        assert_eq!(config.mail.smtp.password.as_deref(), Some("123456"));
+
+        fs::remove_file(&sk_file)?;
+        fs::remove_file(&password_file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn config_layers_should_parse_unset_numbers() -> Result<()> {
+        let config = AppConfig::from_layers(None, vars(&[("CHAT_SERVER__METRICS_PORT", "9100")]))?;
+        assert_eq!(config.server.metrics_port, Some(9100));
+        let err = AppConfig::from_layers(None, vars(&[("CHAT_SERVER__METRICS_PORT", "http")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid digit"));
+        Ok(())
+    }
+
+    #[test]
+    fn config_layers_should_report_every_invalid_env_var() {
+        let err = AppConfig::from_layers(
+            None,
+            vars(&[
+                ("CHAT_SERVER__PORT", "http"),
+                ("CHAT_SERVER__HOST", "localhost"),
+                ("CHAT_AUTH__PK_FILE", "/nonexistent/pk"),
+            ]),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("CHAT_SERVER__PORT"));
+        assert!(err.contains("CHAT_SERVER__HOST: unknown config key"));
+        assert!(err.contains("CHAT_AUTH__PK_FILE: failed to read /nonexistent/pk"));
+    }
+
+    #[test]
+    fn validate_should_report_every_invalid_field() -> Result<()> {
+        let yaml = serde_yaml::from_str(include_str!("../app.yml"))?;
+        let config = AppConfig::from_layers(Some(yaml), vec![])?;
+        assert!(config.validate().is_ok());
+
+        let file = env::temp_dir().join(format!("chat-base-{}", uuid::Uuid::now_v7()));
+        fs::write(&file, "not a dir")?;
+        let config = AppConfig {
+            server: ServerConfig {
+                port: 8080,
+                db_url: "not a url".to_string(),
+                base_dir: file.clone(),
+                drain_timeout: 30,
+                metrics_port: Some(8080),
+            },
+            auth: AuthConfig {
+                sk: "bad pem".to_string(),
+                pk: config.auth.pk,
+                admins: vec![],
+                lockout: LockoutConfig::default(),
+            },
+            gc: GcConfig::default(),
+            retention: RetentionConfig::default(),
+            scheduled: ScheduledConfig::default(),
+            export: ExportConfig::default(),
+            rate_limit: RateLimitConfig {
+                default: Some(RateLimit::new(0, 60)),
+                ..Default::default()
+            },
+            log: LogConfig {
+                filter: "chat_server=loud".to_string(),
+                format: LogFormat::Text,
+            },
+            telemetry: TelemetryConfig::default(),
+            mail: MailConfig {
+                from: "not an address".to_string(),
+                ..Default::default()
+            },
+        };
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("server.metrics_port"));
+        assert!(err.contains("server.db_url"));
+        assert!(err.contains("server.base_dir"));
+        assert!(err.contains("auth.sk"));
+        assert!(!err.contains("auth.pk"));
+        assert!(err.contains("log.filter"));
+        assert!(err.contains("rate_limit.default"));
+        assert!(err.contains("mail.from"));
+
+        fs::remove_file(&file)?;
+        Ok(())
     }
 }
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -8,6 +8,9 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AppError {
+    #[error("account locked, retry after {0} seconds")]
+    AccountLocked(u64),
+
     #[error("argon2 error: {0}")]
     Argon2(#[from] argon2::password_hash::Error),
 
@@ -20,18 +23,42 @@ pub enum AppError {
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("invalid email or password")]
+    InvalidCredentials,
+
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("invalid 2fa code")]
+    InvalidMfaCode,
+
+    #[error("invalid or expired token")]
+    InvalidToken,
+
     #[error("http header parse error: {0}")]
     HttpHeader(#[from] axum::http::header::InvalidHeaderValue),
 
     #[error("std io error: {0}")]
     IOError(#[from] std::io::Error),
 
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("jwt error: {0}")]
     Jwt(#[from] jwt_simple::Error),
 
+    #[error("mail error: {0}")]
+    Mail(String),
+
     #[error("not found: {0}")]
     NotFound(String),
 
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("too many requests, retry after {0} seconds")]
+    RateLimited(u64),
+
     #[error("sqlx error: {0}")]
     Sqlx(#[from] sqlx::Error),
 
@@ -42,21 +69,63 @@ pub enum AppError {
     VerifyChatError(String),
 }
 
+impl AppError {
+    /// name of the variant, used as the error metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppError::AccountLocked(_) => "AccountLocked",
+            AppError::Argon2(_) => "Argon2",
+            AppError::ChatFileError(_) => "ChatFileError",
+            AppError::CreateChatError(_) => "CreateChatError",
+            AppError::CreateMessageError(_) => "CreateMessageError",
+            AppError::EmailAlreadyExists(_) => "EmailAlreadyExists",
+            AppError::HttpHeader(_) => "HttpHeader",
+            AppError::InvalidCredentials => "InvalidCredentials",
+            AppError::InvalidInput(_) => "InvalidInput",
+            AppError::InvalidMfaCode => "InvalidMfaCode",
+            AppError::InvalidToken => "InvalidToken",
+            AppError::IOError(_) => "IOError",
+            AppError::Json(_) => "Json",
+            AppError::Jwt(_) => "Jwt",
+            AppError::Mail(_) => "Mail",
+            AppError::NotFound(_) => "NotFound",
+            AppError::PermissionDenied(_) => "PermissionDenied",
+            AppError::RateLimited(_) => "RateLimited",
+            AppError::Sqlx(_) => "Sqlx",
+            AppError::VerifyChatError(_) => "VerifyChatError",
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        metrics::counter!("app_errors_total", "error" => self.name()).increment(1);
         let status = match &self {
+            AppError::AccountLocked(_) => StatusCode::LOCKED,
             AppError::Argon2(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::ChatFileError(_) => StatusCode::BAD_REQUEST,
             AppError::CreateChatError(_) => StatusCode::BAD_REQUEST,
             AppError::CreateMessageError(_) => StatusCode::BAD_REQUEST,
             AppError::EmailAlreadyExists(_) => StatusCode::CONFLICT,
             AppError::HttpHeader(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidCredentials => StatusCode::FORBIDDEN,
+            AppError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidMfaCode => StatusCode::FORBIDDEN,
+            AppError::InvalidToken => StatusCode::BAD_REQUEST,
             AppError::IOError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Jwt(_) => StatusCode::FORBIDDEN,
+            AppError::Mail(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
             AppError::Sqlx(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::VerifyChatError(_) => StatusCode::FORBIDDEN,
         };
-        (status, Json(json!({"error": self.to_string()}))).into_response()
+        let mut res = (status, Json(json!({"error": self.to_string()}))).into_response();
+        if let AppError::RateLimited(retry_after) | AppError::AccountLocked(retry_after) = self {
+            res.headers_mut().insert(RETRY_AFTER, retry_after.into());
+        }
+        res
     }
 }
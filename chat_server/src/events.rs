@@ -0,0 +1,263 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+
+use crate::{
+    error::AppError,
+    models::{MentionKind, PresenceStatus, UserProfile},
+    utils::current_traceparent,
+    Message,
+};
+
+/// postgres channel the chat server publishes notifications to
+pub const NOTIFY_CHANNEL: &str = "chat_events";
+
+// postgres rejects NOTIFY payloads of 8000 bytes or more, publish adds the id to the payloads
+const MAX_PAYLOAD: usize = 7900;
+// room kept for the user ids of every payload, a few hundred of them
+const MIN_USER_IDS_SIZE: usize = 2000;
+
+/// an event delivered by notify_server to every connected user of `user_ids`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Notification {
+    /// the event id, assigned by publish so that every notify server replica uses the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub user_ids: Vec<i64>,
+    pub event: AppEvent,
+    /// W3C traceparent of the publishing span, continued by notify_server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event")]
+pub enum AppEvent {
+    NewMessage(Message),
+    Mentioned(MentionEvent),
+    Typing(TypingEvent),
+    PresenceChanged(PresenceEvent),
+    AccountLocked(AccountLockedEvent),
+    /// the profile of a member of the workspace changed
+    UserUpdated(UserProfile),
+    /// the user was deactivated in or removed from the workspace, its streams are closed
+    MembershipRevoked(MembershipEvent),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MentionEvent {
+    pub kind: MentionKind,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TypingEvent {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PresenceEvent {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MembershipEvent {
+    pub ws_id: i64,
+    pub user_id: i64,
+}
+
+/// too many failed signins, sent to the locked user and to the admins
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AccountLockedEvent {
+    pub user_id: i64,
+    pub locked_until: DateTime<Utc>,
+}
+
+impl AppEvent {
+    /// name of the event, used as the sse event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::Mentioned(_) => "Mentioned",
+            AppEvent::Typing(_) => "Typing",
+            AppEvent::PresenceChanged(_) => "PresenceChanged",
+            AppEvent::AccountLocked(_) => "AccountLocked",
+            AppEvent::UserUpdated(_) => "UserUpdated",
+            AppEvent::MembershipRevoked(_) => "MembershipRevoked",
+        }
+    }
+
+    /// the chat the event belongs to, if any
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            AppEvent::NewMessage(message) => Some(message.chat_id),
+            AppEvent::Mentioned(event) => Some(event.message.chat_id),
+            AppEvent::Typing(event) => Some(event.chat_id),
+            AppEvent::PresenceChanged(_)
+            | AppEvent::AccountLocked(_)
+            | AppEvent::UserUpdated(_)
+            | AppEvent::MembershipRevoked(_) => None,
+        }
+    }
+
+    // the content is the only unbounded field, validate_message caps the files. Clients fetch
+    // the full message when truncated
+    fn truncate_content(&mut self, overflow: usize) {
+        let message = match self {
+            AppEvent::NewMessage(message) => message,
+            AppEvent::Mentioned(event) => &mut event.message,
+            _ => return,
+        };
+        let mut len = message.content.len().saturating_sub(overflow);
+        while !message.content.is_char_boundary(len) {
+            len -= 1;
+        }
+        message.content.truncate(len);
+    }
+}
+
+impl Notification {
+    pub fn new(user_ids: Vec<i64>, event: AppEvent) -> Self {
+        Self {
+            id: None,
+            user_ids,
+            event,
+            traceparent: current_traceparent(),
+        }
+    }
+
+    /// serialize the notification to fit the NOTIFY limit: the message content is truncated and
+    /// the users are split across as many payloads as needed
+    pub fn payloads(&self) -> Result<Vec<String>, AppError> {
+        let mut notification = Notification {
+            id: None,
+            user_ids: vec![],
+            ..self.clone()
+        };
+        let size = serde_json::to_string(&notification)?.len();
+        if size > MAX_PAYLOAD - MIN_USER_IDS_SIZE {
+            // every content byte removed shrinks the payload by at least one byte, keep some
+            // margin
+            let overflow = (size + MIN_USER_IDS_SIZE - MAX_PAYLOAD) * 2;
+            notification.event.truncate_content(overflow);
+        }
+        let base = serde_json::to_string(&notification)?.len();
+
+        let mut payloads = vec![];
+        let mut size = base;
+        for &user_id in &self.user_ids {
+            // the digits and the separating comma
+            let len = user_id.to_string().len() + 1;
+            if size + len > MAX_PAYLOAD {
+                payloads.push(serde_json::to_string(&notification)?);
+                notification.user_ids.clear();
+                size = base;
+            }
+            notification.user_ids.push(user_id);
+            size += len;
+        }
+        if !notification.user_ids.is_empty() {
+            payloads.push(serde_json::to_string(&notification)?);
+        }
+        Ok(payloads)
+    }
+
+    /// publish the notification under the next event id, its payloads share the id. Inside a
+    /// transaction it is only delivered after commit
+    pub async fn publish<'e>(&self, executor: impl PgExecutor<'e>) -> Result<(), AppError> {
+        if self.user_ids.is_empty() {
+            return Ok(());
+        }
+        // the payloads are json objects, the id goes first
+        sqlx::query(
+            r#"
+            WITH e AS (SELECT nextval('notify_event_id_seq') AS id)
+            SELECT pg_notify($1, '{"id":' || e.id || ',' || substr(payload, 2))
+            FROM e, unnest($2::text[]) AS payload
+            "#,
+        )
+        .bind(NOTIFY_CHANNEL)
+        .bind(self.payloads()?)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use chrono::Utc;
+    use sqlx::postgres::PgListener;
+
+    #[test]
+    fn notification_payload_should_fit_notify_limit() -> anyhow::Result<()> {
+        let message = Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 1,
+            content: "你好".repeat(4000),
+            files: vec![],
+            created_at: Utc::now(),
+        };
+        let notification = Notification::new(vec![2, 3], AppEvent::NewMessage(message));
+        let payloads = notification.payloads()?;
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].len() <= MAX_PAYLOAD);
+        let ret: Notification = serde_json::from_str(&payloads[0])?;
+        assert_eq!(ret.user_ids, vec![2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn notification_payloads_should_split_users() -> anyhow::Result<()> {
+        let event = AppEvent::Typing(TypingEvent {
+            chat_id: 1,
+            user_id: 1,
+        });
+        let user_ids: Vec<i64> = (1_000_000..1_005_000).collect();
+        let notification = Notification::new(user_ids.clone(), event.clone());
+        let payloads = notification.payloads()?;
+        assert!(payloads.len() > 1);
+
+        let mut ret = vec![];
+        for payload in payloads {
+            assert!(payload.len() <= MAX_PAYLOAD);
+            let notification: Notification = serde_json::from_str(&payload)?;
+            assert_eq!(notification.event, event);
+            ret.extend(notification.user_ids);
+        }
+        assert_eq!(ret, user_ids);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn publish_should_assign_one_id_per_event() -> anyhow::Result<()> {
+        let (tdb, state) = AppState::new_for_test().await?;
+        let mut listener = PgListener::connect(&tdb.url()).await?;
+        listener.listen(NOTIFY_CHANNEL).await?;
+        let event = AppEvent::Typing(TypingEvent {
+            chat_id: 1,
+            user_id: 1,
+        });
+        let notification = Notification::new((1_000_000..1_005_000).collect(), event.clone());
+        notification.publish(&state.pool).await?;
+        Notification::new(vec![1], event)
+            .publish(&state.pool)
+            .await?;
+
+        let mut ids = vec![];
+        for _ in 0..notification.payloads()?.len() + 1 {
+            let payload = listener.recv().await?;
+            let notification: Notification = serde_json::from_str(payload.payload())?;
+            ids.push(notification.id.expect("id should be assigned"));
+        }
+        let last = ids.pop().unwrap();
+        assert!(ids.iter().all(|id| *id == ids[0]));
+        assert!(last > ids[0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    error::AppError,
+    log_handle,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, LogFilter, SlackImportOptions, User,
+};
+
+/// slack exports are much larger than the default body limit
+pub(crate) const MAX_IMPORT_SIZE: usize = 1024 * 1024 * 1024;
+
+/// current log filter directives
+pub(crate) async fn get_log_filter_handler() -> Result<impl IntoResponse, AppError> {
+    let filter = current_log_filter()?;
+    Ok((StatusCode::OK, Json(filter)))
+}
+
+/// replace the log filter directives at runtime, e.g. `{"filter": "info,chat_server=debug"}`
+pub(crate) async fn set_log_filter_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<LogFilter>,
+) -> Result<impl IntoResponse, AppError> {
+    let handle = log_handle().ok_or_else(not_installed)?;
+    handle
+        .set_filter(&input.filter)
+        .map_err(|e| AppError::InvalidInput(format!("log filter {:?}: {}", input.filter, e)))?;
+    tracing::warn!("Log filter set to {}", input.filter);
+    let event = NewAuditEvent::new(AuditAction::LogFilterUpdate, &user)
+        .details(json!({ "filter": input.filter }));
+    state.audit(&meta, event).await;
+    let filter = current_log_filter()?;
+    Ok((StatusCode::OK, Json(filter)))
+}
+
+/// clear the signin failures and the lock of a user
+pub(crate) async fn unlock_user_handler(
+    Extension(admin): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.unlock_user(id).await?;
+    tracing::warn!("User {} unlocked", id);
+    // recorded in the workspace of the user, where its admins see it
+    let event = NewAuditEvent::new(AuditAction::UserUnlock, &admin)
+        .workspace(user.ws_id)
+        .target("user", user.id);
+    state.audit(&meta, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// upload the slack export zip of the `file` field, it is imported into the workspace in the
+/// background
+pub(crate) async fn import_slack_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<i64>,
+    Query(opts): Query<SlackImportOptions>,
+    multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let job = state.create_slack_import(id, Some(user.id)).await?;
+    let event = NewAuditEvent::new(AuditAction::SlackImport, &user)
+        .workspace(id)
+        .target("slack_import", job.id)
+        .details(json!({ "download_files": opts.download_files }));
+    state.audit(&meta, event).await;
+    let path = job.path(&state.config.server.base_dir);
+    if let Err(e) = save_upload(multipart, &path).await {
+        let _ = tokio::fs::remove_file(&path).await;
+        state.finish_slack_import(job.id, Err(&e)).await?;
+        return Err(e);
+    }
+
+    let job_id = job.id;
+    tokio::spawn(async move {
+        if let Err(e) = state.run_slack_import(job_id, &opts).await {
+            tracing::warn!("Failed to run slack import {}: {}", job_id, e);
+        }
+    });
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// the status of a slack import, with its report once done
+pub(crate) async fn get_slack_import_handler(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let job = state.find_slack_import(id).await?;
+    Ok((StatusCode::OK, Json(job)))
+}
+
+// streamed to the file, an export does not have to fit in memory
+async fn save_upload(mut multipart: Multipart, path: &std::path::Path) -> Result<(), AppError> {
+    while let Some(mut field) = multipart.next_field().await.map_err(invalid_upload)? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        tokio::fs::create_dir_all(path.parent().expect("import path should have a parent")).await?;
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = field.chunk().await.map_err(invalid_upload)? {
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        return Ok(());
+    }
+    Err(AppError::InvalidInput("missing file field".to_string()))
+}
+
+fn invalid_upload(e: axum::extract::multipart::MultipartError) -> AppError {
+    AppError::InvalidInput(e.to_string())
+}
+
+fn current_log_filter() -> Result<LogFilter, AppError> {
+    let filter = log_handle()
+        .and_then(|handle| handle.filter())
+        .ok_or_else(not_installed)?;
+    Ok(LogFilter { filter })
+}
+
+fn not_installed() -> AppError {
+    AppError::NotFound("log subscriber is not installed".to_string())
+}
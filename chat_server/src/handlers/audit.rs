@@ -0,0 +1,17 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{error::AppError, AppState, ListAudit, User};
+
+pub(crate) async fn list_audit_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListAudit>,
+) -> Result<impl IntoResponse, AppError> {
+    let events = state.list_audit_events(&user, &input).await?;
+    Ok((StatusCode::OK, Json(events)))
+}
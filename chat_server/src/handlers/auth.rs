@@ -1,34 +1,124 @@
 use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
 use serde::{Deserialize, Serialize};
 
-use crate::{error::AppError, AppState, SigninUser, UserInput};
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, ForgotPassword, MfaStatus, ResetPassword, SigninUser, TokenScope, UserInput,
+    VerifyEmail,
+};
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthOutput {
     token: String,
 }
 
+/// the password is verified, but 2fa has to be completed before getting a full token
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MfaOutput {
+    mfa_token: String,
+    mfa: MfaStep,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MfaStep {
+    /// send a code to `/api/signin/mfa`
+    Verify,
+    /// the workspace requires 2fa, enroll with `/api/mfa/enroll` and `/api/mfa/verify`
+    Enroll,
+}
+
+impl AuthOutput {
+    pub fn new(token: String) -> Self {
+        Self { token }
+    }
+}
+
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
+    meta: RequestMeta,
     Json(input): Json<UserInput>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
+    let event = NewAuditEvent::new(AuditAction::Signup, &user).target("user", user.id);
+    state.audit(&meta, event).await;
+    // the account is usable right away, the email is verified later
+    if let Err(e) = state.send_email_verification(&user).await {
+        tracing::warn!(
+            "Failed to mail the verification link to {}: {}",
+            user.email,
+            e
+        );
+    }
+    // a workspace requiring 2fa only lets the new account enroll
+    if state.mfa_status(&user).await? == MfaStatus::EnrollRequired {
+        let mfa_token = state.sk.encode_scoped(user, TokenScope::MfaEnroll)?;
+        let body = MfaOutput {
+            mfa_token,
+            mfa: MfaStep::Enroll,
+        };
+        return Ok((StatusCode::CREATED, Json(body)).into_response());
+    }
     let token = state.sk.encode(user)?;
     let body = Json(AuthOutput { token });
-    Ok((StatusCode::CREATED, body))
+    Ok((StatusCode::CREATED, body).into_response())
 }
 
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    meta: RequestMeta,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
-    let user = state.verify_user(&input).await?;
-    match user {
-        Some(user) => {
+    let user = match state.signin(&input, meta.ip).await {
+        Ok(user) => user,
+        Err(e) => {
+            state.audit_signin_failure(&meta, &input.email, &e).await;
+            return Err(e);
+        }
+    };
+    let (scope, mfa) = match state.mfa_status(&user).await? {
+        MfaStatus::Disabled => {
+            let event = NewAuditEvent::new(AuditAction::Signin, &user);
+            state.audit(&meta, event).await;
             let token = state.sk.encode(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
+            return Ok((StatusCode::OK, Json(AuthOutput { token })).into_response());
         }
-        None => Ok((StatusCode::FORBIDDEN, "Invalid email or password").into_response()),
-    }
+        MfaStatus::Enabled => (TokenScope::MfaPending, MfaStep::Verify),
+        MfaStatus::EnrollRequired => (TokenScope::MfaEnroll, MfaStep::Enroll),
+    };
+    let mfa_token = state.sk.encode_scoped(user, scope)?;
+    Ok((StatusCode::OK, Json(MfaOutput { mfa_token, mfa })).into_response())
+}
+
+pub(crate) async fn forgot_password_handler(
+    State(state): State<AppState>,
+    Json(input): Json<ForgotPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.forgot_password(&input.email);
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub(crate) async fn reset_password_handler(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<ResetPassword>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.reset_password(&input).await?;
+    let event = NewAuditEvent::new(AuditAction::PasswordReset, &user).target("user", user.id);
+    state.audit(&meta, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn verify_email_handler(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<VerifyEmail>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.verify_email(&input.token).await?;
+    let event = NewAuditEvent::new(AuditAction::EmailVerify, &user).target("user", user.id);
+    state.audit(&meta, event).await;
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[cfg(test)]
@@ -39,9 +129,9 @@ mod tests {
 
     #[tokio::test]
     pub async fn signup_should_work() -> Result<()> {
-        let (_tdb, state) = AppState::new_for_test().await?;
-        let input = UserInput::new("firsteor", "firstero@email", "acme", "password");
-        let ret = signup_handler(State(state), Json(input))
+        let (_tdb, state, mailer) = AppState::new_for_test_with_mailer(|_| {}).await?;
+        let input = UserInput::new("firsteor", "firstero@email", "firstero", "password");
+        let ret = signup_handler(State(state), RequestMeta::default(), Json(input))
             .await?
             .into_response();
         // check status
@@ -50,6 +140,44 @@ mod tests {
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: AuthOutput = serde_json::from_slice(&body)?;
         assert_ne!(ret.token, "");
+        // the verification link is mailed
+        let mails = mailer.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, "firstero@email");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_should_not_join_existing_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UserInput::new("firsteor", "firstero@email", "acme", "password");
+        let ret = signup_handler(State(state.clone()), RequestMeta::default(), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+        assert!(state.find_user_by_email("firstero@email").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_email_should_be_audited() -> Result<()> {
+        let (_tdb, state, mailer) = AppState::new_for_test_with_mailer(|_| {}).await?;
+        let input = UserInput::new("firsteor", "firstero@email", "firstero", "password");
+        signup_handler(State(state.clone()), RequestMeta::default(), Json(input)).await?;
+        let body = &mailer.mails()[0].body;
+        let start = body.find("?token=").expect("mail should have a link") + 7;
+        let token = body[start..].split_whitespace().next().unwrap().to_string();
+
+        let input = VerifyEmail { token };
+        let ret = verify_email_handler(State(state.clone()), RequestMeta::default(), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        let actions: Vec<AuditAction> =
+            sqlx::query_scalar("SELECT action FROM audit_events ORDER BY id")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(actions, [AuditAction::Signup, AuditAction::EmailVerify]);
         Ok(())
     }
 
@@ -61,13 +189,34 @@ mod tests {
         let password = "123456";
 
         let sign_input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(sign_input))
-            .await?
-            .into_response();
+        let ret = signin_handler(
+            State(state.clone()),
+            RequestMeta::default(),
+            Json(sign_input),
+        )
+        .await?
+        .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
         let body = ret.into_body().into_response().collect().await?.to_bytes();
         let ret: AuthOutput = serde_json::from_slice(&body)?;
         assert_ne!(ret.token, "");
+
+        let sign_input = SigninUser::new(email, "bad password");
+        let ret = signin_handler(
+            State(state.clone()),
+            RequestMeta::default(),
+            Json(sign_input),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        // both attempts are audited
+        let actions: Vec<AuditAction> =
+            sqlx::query_scalar("SELECT action FROM audit_events ORDER BY id")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(actions, [AuditAction::Signin, AuditAction::SigninFailed]);
         Ok(())
     }
 }
@@ -4,8 +4,14 @@ use axum::{
     response::IntoResponse,
     Extension, Json,
 };
+use serde_json::json;
 
-use crate::{error::AppError, AppState, CreateChat, User};
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, CreateChat, User,
+};
 
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
@@ -18,9 +24,14 @@ pub(crate) async fn list_chat_handler(
 pub(crate) async fn create_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    meta: RequestMeta,
     Json(chat): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
     let chat = state.create_chat(&chat, user.ws_id as _).await?;
+    let event = NewAuditEvent::new(AuditAction::ChatCreate, &user)
+        .target("chat", chat.id)
+        .details(json!({ "type": chat.r#type, "members": chat.members }));
+    state.audit(&meta, event).await;
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
@@ -33,6 +44,16 @@ pub(crate) async fn get_chat_handler(
         .unwrap_or_else(|| Err(AppError::NotFound(format!("chat id: {}", id))))
 }
 
+pub(crate) async fn typing_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    // typing events are best effort, throttled ones are silently dropped
+    state.send_typing(id, user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 // TODO: implement the following handlers
 pub(crate) async fn update_chat_handler() -> impl IntoResponse {
     todo!()
@@ -0,0 +1,59 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{header::CONTENT_DISPOSITION, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use tower_http::services::ServeFile;
+
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, User,
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DownloadExport {
+    token: String,
+}
+
+pub(crate) async fn export_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+) -> Result<impl IntoResponse, AppError> {
+    let export = state.start_workspace_export(&user).await?;
+    let event = NewAuditEvent::new(AuditAction::WorkspaceExport, &user).target("export", export.id);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::ACCEPTED, Json(export)))
+}
+
+pub(crate) async fn get_export_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let export = state.get_workspace_export(&user, id).await?;
+    Ok((StatusCode::OK, Json(export)))
+}
+
+pub(crate) async fn download_export_handler(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(input): Query<DownloadExport>,
+) -> Result<impl IntoResponse, AppError> {
+    let export = state.verify_export_download(id, &input.token).await?;
+    let path = export.path(&state.config.server.base_dir);
+    let req = Request::new(Body::empty());
+    let mut res = ServeFile::new(path).try_call(req).await?.into_response();
+    let disposition = format!(
+        "attachment; filename=\"workspace-{}-{}.zip\"",
+        export.ws_id, id
+    );
+    res.headers_mut()
+        .insert(CONTENT_DISPOSITION, HeaderValue::from_str(&disposition)?);
+    Ok(res)
+}
@@ -0,0 +1,87 @@
+use axum::{extract::State, response::IntoResponse};
+use sqlx::migrate::Migrator;
+use tokio::fs;
+
+use crate::{AppState, HealthReport};
+
+// the migrations the server was built with
+static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
+
+/// liveness: the process is up and serving requests
+pub(crate) async fn healthz_handler() -> impl IntoResponse {
+    HealthReport::new()
+}
+
+/// readiness: the dependencies needed to serve requests are available
+pub(crate) async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    HealthReport::new()
+        .check("database", check_database(&state))
+        .await
+        .check("base_dir", check_base_dir(&state))
+        .await
+        .check("migrations", check_migrations(&state))
+        .await
+}
+
+async fn check_database(state: &AppState) -> anyhow::Result<()> {
+    sqlx::query("SELECT 1").execute(&state.pool).await?;
+    Ok(())
+}
+
+async fn check_base_dir(state: &AppState) -> anyhow::Result<()> {
+    let path = state
+        .config
+        .server
+        .base_dir
+        .join(format!(".readyz-{}", uuid::Uuid::now_v7()));
+    fs::write(&path, b"").await?;
+    fs::remove_file(&path).await?;
+    Ok(())
+}
+
+async fn check_migrations(state: &AppState) -> anyhow::Result<()> {
+    let expected = MIGRATOR.iter().map(|m| m.version).max();
+    let (applied,): (Option<i64>,) =
+        sqlx::query_as("SELECT max(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&state.pool)
+            .await?;
+    if applied != expected {
+        anyhow::bail!(
+            "database schema at version {:?}, expect {:?}",
+            applied,
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HealthStatus;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn readyz_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let report = HealthReport::new()
+            .check("migrations", check_migrations(&state))
+            .await
+            .check("base_dir", check_base_dir(&state))
+            .await;
+        assert_eq!(report.status, HealthStatus::Ok);
+
+        // the database is behind the compiled migrations
+        sqlx::query(
+            r#"
+            DELETE FROM _sqlx_migrations
+            WHERE version = (SELECT max(version) FROM _sqlx_migrations)
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        let ret = readyz_handler(State(state)).await.into_response();
+        assert_eq!(ret.status(), axum::http::StatusCode::SERVICE_UNAVAILABLE);
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{error::AppError, AppState, ListMessage, User};
+
+pub(crate) async fn list_mentions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.list_mentions(user.id as _, &input).await?;
+    Ok(Json(messages))
+}
@@ -1,14 +1,21 @@
 use axum::{
     body::Body,
     extract::{Multipart, Path, Query, Request, State},
+    http::StatusCode,
     response::IntoResponse,
     Extension, Json,
 };
-use tokio::fs;
+use chrono::Utc;
+use serde_json::json;
 use tower_http::services::ServeFile;
 use tracing::{info, warn};
 
-use crate::{error::AppError, AppState, ChatFile, CreateMessage, ListMessage, User};
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, ChatFile, CreateMessage, ListMessage, User,
+};
 
 pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
@@ -16,8 +23,13 @@ pub(crate) async fn send_message_handler(
     Path(id): Path<u64>,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
+    // a send_at in the past is sent right away
+    if input.send_at.is_some_and(|send_at| send_at > Utc::now()) {
+        let scheduled = state.schedule_message(input, id, user.id as _).await?;
+        return Ok((StatusCode::ACCEPTED, Json(scheduled)).into_response());
+    }
     let message = state.create_message(input, id, user.id as _).await?;
-    Ok(Json(message))
+    Ok(Json(message).into_response())
 }
 
 pub(crate) async fn list_message_handler(
@@ -32,6 +44,7 @@ pub(crate) async fn list_message_handler(
 pub(crate) async fn download_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    meta: RequestMeta,
     Path((ws_id, path)): Path<(i64, String)>,
 ) -> Result<impl IntoResponse, AppError> {
     if user.ws_id != ws_id {
@@ -40,18 +53,22 @@ pub(crate) async fn download_handler(
         ));
     }
     let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
-    if !path.exists() {
+    let file_path = base_dir.join(&path);
+    if !file_path.exists() {
         return Err(AppError::NotFound("File doesn't exist".to_string()));
     }
+    let event = NewAuditEvent::new(AuditAction::FileDownload, &user)
+        .target("file", format!("/files/{ws_id}/{path}"));
+    state.audit(&meta, event).await;
     let req = Request::new(Body::empty());
-    let res = ServeFile::new(path).try_call(req).await?;
+    let res = ServeFile::new(file_path).try_call(req).await?;
     Ok(res.into_response())
 }
 
 pub(crate) async fn upload_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    meta: RequestMeta,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let ws_id = user.ws_id as u64;
@@ -64,14 +81,20 @@ pub(crate) async fn upload_handler(
             continue;
         };
 
+        let data_len = data.len();
+        metrics::counter!("chat_upload_bytes_total").increment(data_len as u64);
         let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
+        if !file.store(base_dir, &data).await? {
+            info!(
+                "File {} already exists: {:?}",
+                filename,
+                file.path(base_dir)
+            );
         }
+        let event = NewAuditEvent::new(AuditAction::FileUpload, &user)
+            .target("file", file.url())
+            .details(json!({ "name": filename, "size": data_len }));
+        state.audit(&meta, event).await;
         files.push(file.url());
     }
 
@@ -0,0 +1,148 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, MfaCode, TokenScope, User,
+};
+
+use super::AuthOutput;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SigninMfa {
+    mfa_token: String,
+    code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MfaVerifyOutput {
+    recovery_codes: Vec<String>,
+    // the enroll token of a workspace requiring 2fa is exchanged for a full one
+    token: String,
+}
+
+pub(crate) async fn enroll_mfa_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let enrollment = state.enroll_mfa(&user).await?;
+    Ok((StatusCode::OK, Json(enrollment)))
+}
+
+pub(crate) async fn verify_mfa_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<MfaCode>,
+) -> Result<impl IntoResponse, AppError> {
+    let recovery_codes = state.confirm_mfa(&user, &input.code).await?;
+    let event = NewAuditEvent::new(AuditAction::MfaEnable, &user).target("user", user.id);
+    state.audit(&meta, event).await;
+    let token = state.sk.encode(user)?;
+    let body = MfaVerifyOutput {
+        recovery_codes,
+        token,
+    };
+    Ok((StatusCode::OK, Json(body)))
+}
+
+pub(crate) async fn disable_mfa_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<MfaCode>,
+) -> Result<impl IntoResponse, AppError> {
+    state.disable_mfa(&user, &input.code).await?;
+    let event = NewAuditEvent::new(AuditAction::MfaDisable, &user).target("user", user.id);
+    state.audit(&meta, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn signin_mfa_handler(
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<SigninMfa>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state
+        .pk
+        .verify_scoped(&input.mfa_token, &[TokenScope::MfaPending])?;
+    if let Err(e) = state.signin_mfa(&user, &input.code).await {
+        state.audit_signin_failure(&meta, &user.email, &e).await;
+        return Err(e);
+    }
+    let event = NewAuditEvent::new(AuditAction::Signin, &user).details(json!({ "mfa": true }));
+    state.audit(&meta, event).await;
+    let token = state.sk.encode(user)?;
+    Ok((StatusCode::OK, Json(AuthOutput::new(token))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handlers::signin_handler, SigninUser};
+    use anyhow::Result;
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn signin_with_mfa_should_need_a_code() -> Result<()> {
+        // no delay after the failed codes
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.base_delay = 0;
+        })
+        .await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        state.enroll_mfa(&user).await?;
+        let (secret,): (String,) = sqlx::query_as("SELECT secret FROM user_mfa WHERE user_id = 1")
+            .fetch_one(&state.pool)
+            .await?;
+        let totp = totp_rs::TOTP::new(
+            totp_rs::Algorithm::SHA1,
+            6,
+            1,
+            30,
+            totp_rs::Secret::Encoded(secret).to_bytes()?,
+            None,
+            user.email.clone(),
+        )?;
+        let codes = state.confirm_mfa(&user, &totp.generate_current()?).await?;
+
+        let input = SigninUser::new("Alice@test.org", "123456");
+        let ret = signin_handler(State(state.clone()), RequestMeta::default(), Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: Value = serde_json::from_slice(&body)?;
+        assert!(ret.get("token").is_none());
+        assert_eq!(ret["mfa"], "verify");
+        let mfa_token = ret["mfa_token"].as_str().unwrap().to_string();
+        // the pending token doesn't grant the api
+        assert!(state.pk.verify(&mfa_token).is_err());
+
+        let input = SigninMfa {
+            mfa_token: mfa_token.clone(),
+            code: "000000".to_string(),
+        };
+        let ret = signin_mfa_handler(State(state.clone()), RequestMeta::default(), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let input = SigninMfa {
+            mfa_token,
+            code: codes[0].clone(),
+        };
+        let ret = signin_mfa_handler(State(state.clone()), RequestMeta::default(), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: Value = serde_json::from_slice(&body)?;
+        let token = ret["token"].as_str().unwrap();
+        assert_eq!(state.pk.verify(token)?.id, 1);
+        Ok(())
+    }
+}
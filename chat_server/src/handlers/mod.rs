@@ -1,13 +1,31 @@
+mod admin;
+mod audit;
 mod auth;
 mod chat;
+mod export;
+mod health;
+mod mention;
 mod message;
+mod mfa;
+mod presence;
+mod profile;
+mod scheduled;
 mod workspace;
 
 use axum::response::IntoResponse;
 
+pub(crate) use admin::*;
+pub(crate) use audit::*;
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use export::*;
+pub(crate) use health::*;
+pub(crate) use mention::*;
 pub(crate) use message::*;
+pub(crate) use mfa::*;
+pub(crate) use presence::*;
+pub(crate) use profile::*;
+pub(crate) use scheduled::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
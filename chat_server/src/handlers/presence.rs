@@ -0,0 +1,20 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use serde_json::json;
+
+use crate::{error::AppError, AppState, User};
+
+pub(crate) async fn list_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let presence = state.list_presence(user.ws_id as _).await?;
+    Ok((StatusCode::OK, Json(presence)))
+}
+
+pub(crate) async fn heartbeat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let status = state.heartbeat(&user).await?;
+    Ok((StatusCode::OK, Json(json!({ "status": status }))))
+}
@@ -0,0 +1,37 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, ChangePassword, UpdateProfile, User,
+};
+
+pub(crate) async fn get_me_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let profile = state.get_profile(user.id).await?;
+    Ok((StatusCode::OK, Json(profile)))
+}
+
+pub(crate) async fn update_me_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateProfile>,
+) -> Result<impl IntoResponse, AppError> {
+    let profile = state.update_profile(&user, input).await?;
+    Ok((StatusCode::OK, Json(profile)))
+}
+
+pub(crate) async fn change_password_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<ChangePassword>,
+) -> Result<impl IntoResponse, AppError> {
+    state.change_password(&user, &input).await?;
+    let event = NewAuditEvent::new(AuditAction::PasswordChange, &user).target("user", user.id);
+    state.audit(&meta, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
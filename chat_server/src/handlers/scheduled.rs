@@ -0,0 +1,26 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{error::AppError, AppState, User};
+
+/// the pending scheduled messages of the user
+pub(crate) async fn list_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.list_scheduled_messages(user.id as _).await?;
+    Ok((StatusCode::OK, Json(messages)))
+}
+
+pub(crate) async fn cancel_scheduled_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let scheduled = state.cancel_scheduled_message(id, user.id as _).await?;
+    Ok((StatusCode::OK, Json(scheduled)))
+}
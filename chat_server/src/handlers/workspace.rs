@@ -1,6 +1,18 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{error::AppError, AppState, User};
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState, UpdateChatRetention, UpdateWorkspace, User, WorkspaceRole,
+};
 
 pub(crate) async fn list_all_users_handler(
     Extension(user): Extension<User>,
@@ -9,3 +21,192 @@ pub(crate) async fn list_all_users_handler(
     let users = state.fetch_all_chat_users(user.ws_id as _).await?;
     Ok((StatusCode::OK, Json(users)))
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddMember {
+    email: String,
+}
+
+pub(crate) async fn list_workspaces_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.list_user_workspaces(user.id).await?;
+    Ok((StatusCode::OK, Json(workspaces)))
+}
+
+/// issue a token for the workspace, the token's `ws_id` is the active workspace of the handlers
+pub(crate) async fn switch_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(ws_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.switch_workspace(&user, ws_id).await?;
+    let token = state.sk.encode(user)?;
+    Ok((StatusCode::OK, Json(json!({ "token": token }))))
+}
+
+pub(crate) async fn add_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<AddMember>,
+) -> Result<impl IntoResponse, AppError> {
+    let invitation = state.invite_workspace_member(&user, &input.email).await?;
+    let event =
+        NewAuditEvent::new(AuditAction::MemberInvite, &user).target("email", &invitation.email);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::ACCEPTED, Json(invitation)))
+}
+
+pub(crate) async fn list_invitations_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let invitations = state.list_invitations(&user).await?;
+    Ok((StatusCode::OK, Json(invitations)))
+}
+
+pub(crate) async fn accept_invitation_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.accept_invitation(&user, id).await?;
+    let event = NewAuditEvent::new(AuditAction::MemberAdd, &user)
+        .workspace(ws.id)
+        .target("user", user.id);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(ws)))
+}
+
+pub(crate) async fn decline_invitation_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.decline_invitation(&user, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TransferOwnership {
+    user_id: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetMemberRole {
+    role: WorkspaceRole,
+}
+
+pub(crate) async fn get_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.get_workspace(&user).await?;
+    Ok((StatusCode::OK, Json(ws)))
+}
+
+pub(crate) async fn update_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let details = json!(input);
+    let ws = state.update_workspace(&user, input).await?;
+    let event = NewAuditEvent::new(AuditAction::WorkspaceUpdate, &user)
+        .target("workspace", ws.id)
+        .details(details);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(ws)))
+}
+
+pub(crate) async fn transfer_ownership_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Json(input): Json<TransferOwnership>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.transfer_ownership(&user, input.user_id).await?;
+    let event =
+        NewAuditEvent::new(AuditAction::OwnershipTransfer, &user).target("user", input.user_id);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(ws)))
+}
+
+pub(crate) async fn list_members_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let members = state.list_workspace_members(&user).await?;
+    Ok((StatusCode::OK, Json(members)))
+}
+
+pub(crate) async fn set_member_role_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    meta: RequestMeta,
+    Json(input): Json<SetMemberRole>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.set_member_role(&user, id, input.role).await?;
+    let event = NewAuditEvent::new(AuditAction::RoleChange, &user)
+        .target("user", id)
+        .details(json!({ "role": input.role }));
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(member)))
+}
+
+pub(crate) async fn deactivate_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.deactivate_member(&user, id).await?;
+    let event = NewAuditEvent::new(AuditAction::MemberDeactivate, &user).target("user", id);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(member)))
+}
+
+pub(crate) async fn reactivate_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state.reactivate_member(&user, id).await?;
+    let event = NewAuditEvent::new(AuditAction::MemberReactivate, &user).target("user", id);
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(member)))
+}
+
+pub(crate) async fn remove_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.remove_member(&user, id).await?;
+    let event = NewAuditEvent::new(AuditAction::MemberRemove, &user).target("user", id);
+    state.audit(&meta, event).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// override the retention of a chat or put it under legal hold
+pub(crate) async fn update_chat_retention_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    meta: RequestMeta,
+    Path(id): Path<u64>,
+    Json(input): Json<UpdateChatRetention>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.update_chat_retention(&user, id, &input).await?;
+    let event = NewAuditEvent::new(AuditAction::RetentionUpdate, &user)
+        .target("chat", id)
+        .details(json!(input));
+    state.audit(&meta, event).await;
+    Ok((StatusCode::OK, Json(chat)))
+}
@@ -1,32 +1,70 @@
 mod config;
 mod error;
+mod events;
 mod handlers;
 mod middlewares;
 mod models;
+mod tasks;
 mod utils;
 
 use anyhow::Context;
+use dashmap::DashMap;
 use sqlx::PgPool;
 
-use std::{fmt, fs, ops::Deref, sync::Arc};
+use std::{
+    fmt, fs,
+    future::Future,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use error::AppError;
-use middlewares::{set_layer, verify_chat, verify_token};
-use utils::{DecodingKey, EncodingKey};
+use middlewares::{set_layer, verify_chat, verify_enroll_token, verify_token, RateLimitLayer};
+
+pub use middlewares::{
+    request_span, set_request_id, track_metrics, verify_admin, Acquire, AdminList, MemoryStore,
+    PgStore, RateLimitStore,
+};
 
+pub use events::{
+    AccountLockedEvent, AppEvent, MembershipEvent, MentionEvent, Notification, PresenceEvent,
+    TypingEvent, NOTIFY_CHANNEL,
+};
 pub use models::{
-    Chat, ChatFile, ChatUser, CreateChat, CreateMessage, ListMessage, Message, SigninUser, User,
-    UserInput, Workspace,
+    heartbeat_presence, AuditAction, AuditEvent, ChangePassword, Chat, ChatFile, ChatUser,
+    CreateChat, CreateMessage, ForgotPassword, ListAudit, ListMessage, MentionKind,
+    MentionedMessage, Message, MfaCode, MfaEnrollment, MfaStatus, PresenceStatus, ResetPassword,
+    ScheduledMessage, ScheduledStatus, SigninUser, UpdateChatRetention, UpdateProfile,
+    UpdateWorkspace, User, UserInput, UserPresence, UserProfile, VerifyEmail, Workspace,
+    WorkspaceInvitation, WorkspaceMember, WorkspaceRole, KEEP_FOREVER,
+};
+pub use utils::{
+    client_ip, current_traceparent, init_tracing, log_handle, metrics_handle, new_mailer,
+    render_metrics, serve_with_graceful_shutdown, set_traceparent, shutdown_signal,
+    spawn_metrics_server, CheckResult, ClientIp, DecodingKey, EncodingKey, FileMailer,
+    HealthReport, HealthStatus, LogFilter, LogHandle, Mail, Mailer, MemoryMailer, SmtpMailer,
+    TelemetryGuard, TokenScope,
 };
 
 use axum::{
+    extract::DefaultBodyLimit,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use handlers::*;
+use tokio::net::TcpListener;
 
-pub use config::AppConfig;
+pub use config::{
+    AppConfig, ExportConfig, LogConfig, LogFormat, MailConfig, MailTransport, RateLimit,
+    RateLimitConfig, RateLimitStoreKind, SmtpConfig, SmtpTls, TelemetryConfig,
+};
+pub use tasks::{
+    ExportManifest, ExportStatus, ExportSweepReport, GcOptions, GcReport, ImportStatus,
+    ManifestEntry, MigrateFilesReport, RetentionReport, SlackImportJob, SlackImportOptions,
+    SlackImportReport, WorkspaceExport, EXPORT_SCHEMA_VERSION,
+};
 
 #[derive(Debug, Clone)]
 pub(crate) struct AppState {
@@ -41,11 +79,67 @@ pub(crate) struct AppStateInner {
     pub(crate) pk: DecodingKey,
     // db config
     pub(crate) pool: PgPool,
+    // last typing event sent per (chat_id, user_id)
+    pub(crate) typing: DashMap<(u64, u64), Instant>,
+    // token buckets of the rate limit layer
+    pub(crate) rate_limiter: Arc<dyn RateLimitStore>,
+    pub(crate) mailer: Arc<dyn Mailer>,
 }
 
+/// the routes of the app, the background tasks are only started by serve
 pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
     let state = AppState::try_new(config).await?;
+    Ok(build_router(state))
+}
+
+/// serve the app until shutdown resolves, then drain the in-flight requests and close the db pool
+pub async fn serve(
+    listener: TcpListener,
+    config: AppConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), AppError> {
+    let state = AppState::try_new(config).await?;
+    serve_state(state, listener, shutdown).await
+}
+
+async fn serve_state(
+    state: AppState,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), AppError> {
+    let drain_timeout = Duration::from_secs(state.config.server.drain_timeout);
+    let metrics = match state.config.server.metrics_port {
+        Some(port) => Some(spawn_metrics_server(port, state.pool.clone()).await?),
+        None => None,
+    };
+    spawn_tasks(&state);
+    let router = build_router(state.clone());
+    serve_with_graceful_shutdown(listener, router, shutdown, drain_timeout).await?;
+    if let Some(metrics) = metrics {
+        metrics.abort();
+    }
+    state.pool.close().await;
+    Ok(())
+}
 
+// the background tasks run once per served app, not per router
+fn spawn_tasks(state: &AppState) {
+    if state.config.gc.interval > 0 {
+        tasks::spawn_gc_task(state.clone());
+    }
+    if state.config.retention.interval > 0 {
+        tasks::spawn_retention_task(state.clone());
+    }
+    if state.config.scheduled.interval > 0 {
+        tasks::spawn_scheduled_task(state.clone());
+    }
+    if state.config.export.interval > 0 {
+        tasks::spawn_export_sweeper(state.clone());
+    }
+}
+
+fn build_router(state: AppState) -> Router {
+    metrics_handle();
     let chat = Router::new()
         .route(
             "/:id",
@@ -55,25 +149,140 @@ pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
                 .post(send_message_handler),
         )
         .route("/:id/message", get(list_message_handler))
+        .route("/:id/typing", post(typing_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat))
         .route("/", get(list_chat_handler).post(create_chat_handler));
 
+    let admin = Router::new()
+        .route(
+            "/log",
+            get(get_log_filter_handler).put(set_log_filter_handler),
+        )
+        .route("/users/:id/unlock", post(unlock_user_handler))
+        .route(
+            "/workspaces/:id/import/slack",
+            post(import_slack_handler).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)),
+        )
+        .route("/imports/slack/:id", get(get_slack_import_handler))
+        .layer(from_fn_with_state(state.clone(), verify_admin::<AppState>));
+
+    let rate_limit = RateLimitLayer::new(state.clone());
+    // routes doesn't need token verification layer, limited by client ip
+    let public = Router::new()
+        .route("/signin", post(signin_handler))
+        .route("/signin/mfa", post(signin_mfa_handler))
+        .route("/signup", post(signup_handler))
+        .route("/password/forgot", post(forgot_password_handler))
+        .route("/password/reset", post(reset_password_handler))
+        .route("/email/verify", post(verify_email_handler))
+        // signed by the token of the link
+        .route("/exports/:id/download", get(download_export_handler))
+        .layer(rate_limit.clone());
+
+    // also open to the users who have to enroll 2fa before using the rest of the api
+    let mfa = Router::new()
+        .route("/mfa/enroll", post(enroll_mfa_handler))
+        .route("/mfa/verify", post(verify_mfa_handler))
+        .layer(rate_limit.clone())
+        .layer(from_fn_with_state(state.clone(), verify_enroll_token));
+
     let api = Router::new()
         .route("/users", get(list_all_users_handler))
+        .route("/users/presence", get(list_presence_handler))
+        .route("/users/presence/heartbeat", post(heartbeat_handler))
+        .route("/me", get(get_me_handler).patch(update_me_handler))
+        .route("/me/password", post(change_password_handler))
+        .route("/mentions", get(list_mentions_handler))
+        .route("/audit", get(list_audit_handler))
+        .route("/mfa/disable", post(disable_mfa_handler))
+        .route(
+            "/workspace",
+            get(get_workspace_handler).patch(update_workspace_handler),
+        )
+        .route("/workspace/transfer", post(transfer_ownership_handler))
+        .route("/workspace/export", post(export_workspace_handler))
+        .route("/workspace/exports/:id", get(get_export_handler))
+        .route(
+            "/workspace/members",
+            get(list_members_handler).post(add_member_handler),
+        )
+        .route("/workspace/members/:id", delete(remove_member_handler))
+        .route("/workspace/members/:id/role", put(set_member_role_handler))
+        .route(
+            "/workspace/members/:id/deactivate",
+            post(deactivate_member_handler),
+        )
+        .route(
+            "/workspace/members/:id/reactivate",
+            post(reactivate_member_handler),
+        )
+        .route(
+            "/workspace/chats/:id/retention",
+            put(update_chat_retention_handler),
+        )
+        .route("/scheduled", get(list_scheduled_handler))
+        .route("/scheduled/:id", delete(cancel_scheduled_handler))
+        .route("/invitations", get(list_invitations_handler))
+        .route("/invitations/:id/accept", post(accept_invitation_handler))
+        .route("/invitations/:id", delete(decline_invitation_handler))
+        .route("/workspaces", get(list_workspaces_handler))
+        .route("/workspaces/:id/switch", post(switch_workspace_handler))
         .nest("/chats", chat)
+        .nest("/admin", admin)
         .route("/upload", post(upload_handler))
         .route("/files/:ws_id/*path", get(download_handler))
+        // limited by user id, so after the token is verified
+        .layer(rate_limit)
         .layer(from_fn_with_state(state.clone(), verify_token))
-        // routes doesn't need token verification layer
-        .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .merge(public)
+        .merge(mfa);
 
     let router = Router::new()
         .route("/", get(index_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
         .nest("/api", api)
         .with_state(state.clone());
 
-    Ok(set_layer(router))
+    set_layer(router)
+}
+
+/// run the orphaned file garbage collection once, used by the `gc` subcommand
+pub async fn collect_garbage(config: AppConfig, dry_run: bool) -> Result<GcReport, AppError> {
+    let opts = GcOptions {
+        grace_period: Duration::from_secs(config.gc.grace_period),
+        dry_run,
+    };
+    let state = AppState::try_new(config).await?;
+    state.collect_orphan_files(&opts).await
+}
+
+/// move the legacy sha1 addressed files to sha256, used by the `migrate-files` subcommand
+pub async fn migrate_files(
+    config: AppConfig,
+    dry_run: bool,
+) -> Result<MigrateFilesReport, AppError> {
+    let state = AppState::try_new(config).await?;
+    state.migrate_legacy_files(dry_run).await
+}
+
+/// export a workspace to an archive under `base_dir/exports`, used by the `export` subcommand
+pub async fn export_workspace(config: AppConfig, ws_id: i64) -> Result<WorkspaceExport, AppError> {
+    let state = AppState::try_new(config).await?;
+    let export = state.create_workspace_export(ws_id, None).await?;
+    state.run_workspace_export(export.id).await
+}
+
+/// import a slack export archive into a workspace, used by the `import-slack` subcommand
+pub async fn import_slack(
+    config: AppConfig,
+    ws_id: i64,
+    path: &std::path::Path,
+    opts: &SlackImportOptions,
+) -> Result<SlackImportReport, AppError> {
+    let state = AppState::try_new(config).await?;
+    let archive = fs::File::open(path)?;
+    state.import_slack(ws_id, archive, opts).await
 }
 
 // 给 AppState 实现 Dereference trait
@@ -94,15 +303,33 @@ impl AppState {
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("connect db failed")?;
+        let mailer = new_mailer(&config.mail)?;
+
+        Ok(Self::new(config, sk, pk, pool, mailer))
+    }
 
-        Ok(Self {
+    fn new(
+        config: AppConfig,
+        sk: EncodingKey,
+        pk: DecodingKey,
+        pool: PgPool,
+        mailer: Arc<dyn Mailer>,
+    ) -> Self {
+        let rate_limiter: Arc<dyn RateLimitStore> = match config.rate_limit.store {
+            RateLimitStoreKind::Memory => Arc::new(MemoryStore::default()),
+            RateLimitStoreKind::Postgres => Arc::new(PgStore::new(pool.clone())),
+        };
+        Self {
             inner: Arc::new(AppStateInner {
                 config,
                 sk,
                 pk,
                 pool,
+                typing: DashMap::new(),
+                rate_limiter,
+                mailer,
             }),
-        })
+        }
     }
 }
 
@@ -115,9 +342,7 @@ impl fmt::Debug for AppStateInner {
 }
 
 #[cfg(test)]
-
 mod test_utils {
-
     use super::*;
     use anyhow::Result;
     use sqlx::Executor;
@@ -127,23 +352,28 @@ mod test_utils {
     impl AppState {
         #[cfg(test)]
         pub async fn new_for_test() -> Result<(TestPg, Self)> {
-            let config = AppConfig::try_load()?;
+            Self::new_for_test_with(|_| {}).await
+        }
+
+        pub async fn new_for_test_with(f: impl FnOnce(&mut AppConfig)) -> Result<(TestPg, Self)> {
+            let (tdb, state, _) = Self::new_for_test_with_mailer(f).await?;
+            Ok((tdb, state))
+        }
+
+        /// the emails of the state are kept by the returned mailer
+        pub async fn new_for_test_with_mailer(
+            f: impl FnOnce(&mut AppConfig),
+        ) -> Result<(TestPg, Self, MemoryMailer)> {
+            let mut config = AppConfig::try_load()?;
+            f(&mut config);
             let sk = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
             let pk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
             let pos = config.server.db_url.rfind('/').expect("invalid db url");
             let server_url = &config.server.db_url[..pos];
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
-            Ok((
-                tdb,
-                Self {
-                    inner: Arc::new(AppStateInner {
-                        config,
-                        sk,
-                        pk,
-                        pool,
-                    }),
-                },
-            ))
+            let mailer = MemoryMailer::default();
+            let state = Self::new(config, sk, pk, pool, Arc::new(mailer.clone()));
+            Ok((tdb, state, mailer))
         }
     }
 
@@ -166,3 +396,50 @@ mod test_utils {
         (tdb, pool)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+
+    #[tokio::test]
+    async fn graceful_shutdown_should_complete_inflight_requests() -> Result<()> {
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.server.metrics_port = None).await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_state(state, listener, async {
+            rx.await.ok();
+        }));
+
+        // send the request body in two parts, shutting down in between
+        let body = r#"{"email":"Alice@test.org","password":"123456"}"#;
+        let mut stream = TcpStream::connect(addr).await?;
+        let head = format!(
+            "POST /api/signin HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body.as_bytes()[..10]).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stream.write_all(&body.as_bytes()[10..]).await?;
+
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("token"));
+
+        server.await??;
+        // no new connection is accepted
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
+}
@@ -1,22 +1,155 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 
-use chat_server::AppConfig;
+use chat_server::{init_tracing, shutdown_signal, AppConfig, SlackImportOptions};
+use clap::{Parser, Subcommand};
 use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter, warn};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use tracing::{info, warn};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Opts {
+    #[command(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// start the chat server (default)
+    Serve,
+    /// delete uploaded files which are not referenced by any message
+    Gc {
+        /// only report the files that would be deleted
+        #[arg(long)]
+        dry_run: bool,
+        /// override the grace period (in seconds) from the config
+        #[arg(long)]
+        grace_period: Option<u64>,
+    },
+    /// rehash the legacy sha1 addressed files with sha256 and rewrite message references
+    MigrateFiles {
+        /// only report the files that would be migrated
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// export the data of a workspace to a zip archive
+    Export {
+        /// id of the workspace
+        ws_id: i64,
+        /// copy the archive to this path, it is kept under `base_dir/exports` anyway
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// import a slack export zip into a workspace, a re-run only imports what is new
+    ImportSlack {
+        /// id of the workspace
+        ws_id: i64,
+        /// path of the slack export zip
+        path: PathBuf,
+        /// download the files which are not in the archive from slack
+        #[arg(long)]
+        download_files: bool,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
+    let opts = Opts::parse();
+
+    let mut config = AppConfig::try_load()?;
+    let _guard = init_tracing(&config.log, &config.telemetry, env!("CARGO_PKG_NAME"))?;
+    warn!("Loaded {} config", env!("CARGO_PKG_NAME"));
+
+    match opts.cmd.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Gc {
+            dry_run,
+            grace_period,
+        } => {
+            if let Some(grace_period) = grace_period {
+                config.gc.grace_period = grace_period;
+            }
+            let report = chat_server::collect_garbage(config, dry_run).await?;
+            for file in &report.files {
+                println!("{}", file);
+            }
+            let action = if report.dry_run {
+                "would reclaim"
+            } else {
+                "reclaimed"
+            };
+            println!(
+                "scanned {} files, {} orphaned, {} {} bytes",
+                report.scanned, report.removed, action, report.reclaimed_bytes
+            );
+            Ok(())
+        }
+        Command::MigrateFiles { dry_run } => {
+            let report = chat_server::migrate_files(config, dry_run).await?;
+            for file in &report.corrupted {
+                println!("hash mismatch, skipped: {}", file);
+            }
+            let action = if report.dry_run {
+                "would migrate"
+            } else {
+                "migrated"
+            };
+            println!(
+                "{} {} files, {} message and {} avatar references",
+                action, report.migrated, report.rewritten_messages, report.rewritten_avatars
+            );
+            Ok(())
+        }
+        Command::Export { ws_id, output } => {
+            let base_dir = config.server.base_dir.clone();
+            let export = chat_server::export_workspace(config, ws_id).await?;
+            if let Some(error) = export.error {
+                anyhow::bail!("export {} failed: {}", export.id, error);
+            }
+            let mut path = export.path(&base_dir);
+            if let Some(output) = output {
+                std::fs::copy(&path, &output)?;
+                path = output;
+            }
+            println!(
+                "exported workspace {} to {} ({} bytes)",
+                ws_id,
+                path.display(),
+                export.size.unwrap_or_default()
+            );
+            Ok(())
+        }
+        Command::ImportSlack {
+            ws_id,
+            path,
+            download_files,
+        } => {
+            let opts = SlackImportOptions { download_files };
+            let report = chat_server::import_slack(config, ws_id, &path, &opts).await?;
+            println!(
+                "imported {} users ({} matched, {} invited), {} chats, {} messages ({} skipped), {} files ({} missing)",
+                report.users_created,
+                report.users_matched,
+                report.invited,
+                report.chats_created,
+                report.messages_imported,
+                report.messages_skipped,
+                report.files_stored,
+                report.files_missing
+            );
+            Ok(())
+        }
+    }
+}
 
-    warn!("Prepare Starting to load {} config", env!("CARGO_PKG_NAME"));
-    let config = AppConfig::try_load()?;
+async fn serve(config: AppConfig) -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
-    let app = chat_server::get_router(config).await?;
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    chat_server::serve(listener, config, shutdown_signal()).await?;
+    info!("Server stopped");
     Ok(())
 }
@@ -0,0 +1,95 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{error::AppError, AppState, User};
+
+/// state of a server with admin endpoints
+pub trait AdminList {
+    /// ids of the users allowed to call the admin endpoints
+    fn admins(&self) -> &[i64];
+}
+
+impl AdminList for AppState {
+    fn admins(&self) -> &[i64] {
+        &self.config.auth.admins
+    }
+}
+
+/// only let the listed admins through, must be layered after `verify_token`
+pub async fn verify_admin<S: AdminList>(
+    State(state): State<S>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(user) = req.extensions().get::<User>() else {
+        let err = AppError::PermissionDenied("User is not authenticated".to_string());
+        return err.into_response();
+    };
+    if !state.admins().contains(&user.id) {
+        let err = AppError::PermissionDenied(format!("User {} is not an admin", user.id));
+        return err.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::middlewares::verify_token;
+
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    async fn handler() -> impl IntoResponse {
+        (StatusCode::OK, "ok")
+    }
+
+    #[tokio::test]
+    async fn verify_admin_middleware_should_work() -> Result<()> {
+        let (_tdb, state) =
+            AppState::new_for_test_with(|config| config.auth.admins = vec![1]).await?;
+        let app = Router::new()
+            .route("/admin", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_admin::<AppState>))
+            .layer(from_fn_with_state(state.clone(), verify_token))
+            .with_state(state.clone());
+
+        // user is admin
+        let token = state.sk.encode(User::new(1, "Alice", "alice@test.org"))?;
+        let req = Request::builder()
+            .uri("/admin")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // user is not admin
+        let token = state.sk.encode(User::new(2, "Bob", "bob@test.org"))?;
+        let req = Request::builder()
+            .uri("/admin")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // verify_token is missing
+        let app = Router::new()
+            .route("/admin", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_admin::<AppState>))
+            .with_state(state);
+        let req = Request::builder().uri("/admin").body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
@@ -9,21 +9,65 @@ use axum_extra::{
     TypedHeader,
 };
 
-use crate::AppState;
+use crate::{AppState, MfaStatus, TokenScope};
 
+/// a full token, of a user who doesn't have to enroll 2fa first. A workspace requiring 2fa
+/// also locks out the full tokens issued before
 pub async fn verify_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    verify_scoped_token(state, req, next, &[TokenScope::Full], true).await
+}
+
+/// also accept the tokens of the users who have to enroll 2fa before using the api
+pub async fn verify_enroll_token(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let scopes = [TokenScope::Full, TokenScope::MfaEnroll];
+    verify_scoped_token(state, req, next, &scopes, false).await
+}
+
+async fn verify_scoped_token(
+    state: AppState,
+    req: Request,
+    next: Next,
+    scopes: &[TokenScope],
+    check_mfa: bool,
+) -> Response {
     let (mut parts, body) = req.into_parts();
     match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
         Ok(TypedHeader(Authorization(bearer))) => {
             let token = bearer.token();
-            match state.pk.verify(token) {
+            match state.pk.verify_scoped(token, scopes) {
                 Ok(user) => {
+                    // a deactivated or removed member is locked out of the workspace at once
+                    match state.is_active_member(user.ws_id, user.id).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            let msg = format!("User {} is not an active member", user.id);
+                            tracing::warn!(msg);
+                            return (StatusCode::FORBIDDEN, msg).into_response();
+                        }
+                        Err(e) => return e.into_response(),
+                    }
+                    if check_mfa {
+                        match state.mfa_status(&user).await {
+                            Ok(MfaStatus::EnrollRequired) => {
+                                let msg = format!("User {} has to enroll 2fa", user.id);
+                                tracing::warn!(msg);
+                                return (StatusCode::FORBIDDEN, msg).into_response();
+                            }
+                            Ok(_) => {}
+                            Err(e) => return e.into_response(),
+                        }
+                    }
+                    tracing::Span::current().record("user_id", user.id);
                     parts.extensions.insert(user);
                     let req = Request::from_parts(parts, body);
                     next.run(req).await
                 }
                 Err(e) => {
-                    let msg = format!("Failed to verify token: {}, {:?}", e, token);
+                    let msg = format!("Failed to verify token: {}", e);
                     tracing::warn!(msg);
                     (StatusCode::FORBIDDEN, msg).into_response()
                 }
@@ -85,6 +129,51 @@ mod tests {
             )
             .await?;
         assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // a deactivated member's token is rejected
+        sqlx::query("UPDATE workspace_members SET deactivated_at = now() WHERE user_id = 1")
+            .execute(&state.pool)
+            .await?;
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_token_should_require_mfa_enrollment() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.sk.encode(User::new(1, "Alice", "alice@test.org"))?;
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_token))
+            .route(
+                "/enroll",
+                get(handler).layer(from_fn_with_state(state.clone(), verify_enroll_token)),
+            );
+        let get = |uri| {
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+        assert_eq!(
+            app.clone().oneshot(get("/")?).await?.status(),
+            StatusCode::OK
+        );
+
+        // the token was issued before the workspace required 2fa
+        sqlx::query("UPDATE workspaces SET require_mfa = TRUE WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let res = app.clone().oneshot(get("/")?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // enrolling is still allowed
+        let res = app.oneshot(get("/enroll")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
         Ok(())
     }
 }
@@ -0,0 +1,58 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use tokio::time::Instant;
+
+/// count the requests and record their latency, labelled by method, matched route and status
+pub async fn track_metrics(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    // the matched route keeps the label cardinality bounded, unlike the raw path
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+
+    let res = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", res.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics_handle;
+    use anyhow::Result;
+    use axum::{body::Body, middleware::from_fn, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn track_metrics_should_label_by_matched_route() -> Result<()> {
+        let handle = metrics_handle();
+        let app = Router::new()
+            .route("/metrics-test/:id", get(|| async { "ok" }))
+            .layer(from_fn(track_metrics));
+        let req = Request::builder()
+            .uri("/metrics-test/42")
+            .body(Body::empty())?;
+        app.oneshot(req).await?;
+
+        let text = handle.render();
+        assert!(text.contains(
+            r#"http_requests_total{method="GET",route="/metrics-test/:id",status="200"} 1"#
+        ));
+        assert!(text.contains("http_request_duration_seconds_bucket"));
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
+mod admin;
 mod auth;
 mod chat;
+mod metrics;
+mod rate_limit;
 mod request_id;
 mod server_time;
 
@@ -7,26 +10,32 @@ use axum::{middleware::from_fn, Router};
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
-    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
     LatencyUnit,
 };
 use tracing::Level;
 
-use self::request_id::set_request_id;
 use self::server_time::ServerTimeLayer;
 
-pub use self::auth::verify_token;
+pub use self::admin::{verify_admin, AdminList};
+pub use self::auth::{verify_enroll_token, verify_token};
 pub use self::chat::verify_chat;
+pub use self::metrics::track_metrics;
+pub(crate) use self::rate_limit::RateLimitLayer;
+pub use self::rate_limit::{Acquire, MemoryStore, PgStore, RateLimitStore};
+pub use self::request_id::{request_span, set_request_id};
 
-const REQUEST_ID_HEADER: &str = "x-request-id";
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
 const SERVER_TIME: &str = "x-server-time";
 
 pub(crate) fn set_layer(router: Router) -> Router {
     router.layer(
         ServiceBuilder::new()
+            // the request id is set before the span is created
+            .layer(from_fn(set_request_id))
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                    .make_span_with(request_span)
                     .on_request(DefaultOnRequest::new().level(Level::INFO))
                     .on_response(
                         DefaultOnResponse::new()
@@ -41,7 +50,7 @@ pub(crate) fn set_layer(router: Router) -> Router {
                     .zstd(true)
                     .deflate(true),
             )
-            .layer(from_fn(set_request_id))
+            .layer(from_fn(track_metrics))
             .layer(ServerTimeLayer),
     )
 }
@@ -0,0 +1,385 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use sqlx::PgPool;
+use tower::{Layer, Service};
+
+use crate::{config::RateLimit, error::AppError, utils::client_ip, AppState, User};
+
+// prune the memory store once it grows beyond this many buckets
+const MAX_BUCKETS: usize = 10_000;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// keeps the token buckets, shared by all the requests of a server
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// take a token from the bucket of key, or tell how long to wait for the next one
+    fn acquire<'a>(
+        &'a self,
+        key: &'a str,
+        limit: RateLimit,
+    ) -> BoxFuture<'a, Result<Acquire, AppError>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Acquire {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// buckets of this process only, each replica enforces its own limits
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    buckets: DashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    // refilled to the burst from then on, the bucket can be dropped
+    full_at: Instant,
+}
+
+/// buckets in the `rate_limits` table, shared by the replicas
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pool: PgPool,
+}
+
+/// limit the requests by the `rate_limit` config of their matched route. Layered inside
+/// `verify_token` the requests are keyed by user id, otherwise by client ip
+#[derive(Clone)]
+pub(crate) struct RateLimitLayer {
+    state: AppState,
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimitMiddleware<S> {
+    inner: S,
+    state: AppState,
+}
+
+impl MemoryStore {
+    fn take(&self, key: &str, limit: RateLimit) -> Acquire {
+        let now = Instant::now();
+        if self.buckets.len() > MAX_BUCKETS {
+            // a full bucket is the same as no bucket
+            self.buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let burst = limit.burst as f64;
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+            full_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate()).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.full_at = now + Duration::from_secs_f64((burst - bucket.tokens) / limit.rate());
+            return Acquire::Allowed;
+        }
+        Acquire::Limited {
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate()),
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire<'a>(
+        &'a self,
+        key: &'a str,
+        limit: RateLimit,
+    ) -> BoxFuture<'a, Result<Acquire, AppError>> {
+        let ret = self.take(key, limit);
+        Box::pin(async move { Ok(ret) })
+    }
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    async fn take(&self, key: &str, limit: RateLimit) -> Result<Acquire, AppError> {
+        // refill and take a token in one statement, the row is left untouched if empty
+        let taken: Option<f64> = sqlx::query_scalar(
+            r#"
+            INSERT INTO rate_limits (key, tokens, updated_at)
+            VALUES ($1, $2 - 1, now())
+            ON CONFLICT (key) DO UPDATE
+            SET tokens = LEAST($2, rate_limits.tokens
+                    + EXTRACT(EPOCH FROM now() - rate_limits.updated_at) * $3) - 1,
+                updated_at = now()
+            WHERE LEAST($2, rate_limits.tokens
+                    + EXTRACT(EPOCH FROM now() - rate_limits.updated_at) * $3) >= 1
+            RETURNING tokens
+            "#,
+        )
+        .bind(key)
+        .bind(limit.burst as f64)
+        .bind(limit.rate())
+        .fetch_optional(&self.pool)
+        .await?;
+        if taken.is_some() {
+            return Ok(Acquire::Allowed);
+        }
+
+        let tokens: f64 = sqlx::query_scalar(
+            r#"
+            SELECT LEAST($2, tokens + EXTRACT(EPOCH FROM now() - updated_at) * $3)
+            FROM rate_limits
+            WHERE key = $1
+            "#,
+        )
+        .bind(key)
+        .bind(limit.burst as f64)
+        .bind(limit.rate())
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Acquire::Limited {
+            retry_after: Duration::from_secs_f64((1.0 - tokens).max(0.0) / limit.rate()),
+        })
+    }
+}
+
+impl AppState {
+    /// drop the buckets of the pg store refilled to their burst, the same as no bucket
+    pub(crate) async fn prune_rate_limits(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            "DELETE FROM rate_limits WHERE updated_at < now() - make_interval(secs => $1)",
+        )
+        .bind(self.config.rate_limit.max_period() as f64)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+impl RateLimitStore for PgStore {
+    fn acquire<'a>(
+        &'a self,
+        key: &'a str,
+        limit: RateLimit,
+    ) -> BoxFuture<'a, Result<Acquire, AppError>> {
+        Box::pin(self.take(key, limit))
+    }
+}
+
+impl RateLimitLayer {
+    pub(crate) fn new(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitMiddleware {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S> Service<Request> for RateLimitMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // the clone may not be ready, keep the ready one to serve this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|path| format!("{} {}", req.method(), path.as_str()));
+        let config = &self.state.config.rate_limit;
+        let Some((route, limit)) = route.and_then(|r| config.limit(&r).map(|l| (r, l))) else {
+            return Box::pin(inner.call(req));
+        };
+        let key = match req.extensions().get::<User>() {
+            Some(user) => format!("{}|user:{}", route, user.id),
+            None => {
+                let ip = client_ip(req.headers(), req.extensions(), config.trust_proxy)
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                format!("{}|ip:{}", route, ip)
+            }
+        };
+
+        let state = self.state.clone();
+        Box::pin(async move {
+            match state.rate_limiter.acquire(&key, limit).await {
+                Ok(Acquire::Allowed) => inner.call(req).await,
+                Ok(Acquire::Limited { retry_after }) => {
+                    tracing::info!("Rate limited {}, retry after {:?}", key, retry_after);
+                    // round up, retrying earlier would be limited again
+                    let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                    Ok(AppError::RateLimited(retry_after).into_response())
+                }
+                Err(e) => {
+                    // a broken store must not take the server down with it
+                    tracing::warn!("Failed to check rate limit of {}: {}", key, e);
+                    inner.call(req).await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middlewares::verify_token;
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        middleware::from_fn_with_state,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn memory_store_should_refill() {
+        let store = MemoryStore::default();
+        let limit = RateLimit::new(2, 1);
+        assert_eq!(store.take("key", limit), Acquire::Allowed);
+        assert_eq!(store.take("key", limit), Acquire::Allowed);
+        let Acquire::Limited { retry_after } = store.take("key", limit) else {
+            panic!("third request should be limited");
+        };
+        assert!(retry_after <= Duration::from_millis(500));
+        // buckets are independent
+        assert_eq!(store.take("other", limit), Acquire::Allowed);
+
+        tokio::time::sleep(retry_after).await;
+        assert_eq!(store.take("key", limit), Acquire::Allowed);
+    }
+
+    #[tokio::test]
+    async fn pg_store_should_share_buckets() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let limit = RateLimit::new(2, 60);
+        // two stores over the same table, as two replicas
+        let s1 = PgStore::new(state.pool.clone());
+        let s2 = PgStore::new(state.pool.clone());
+        assert_eq!(s1.acquire("key", limit).await?, Acquire::Allowed);
+        assert_eq!(s2.acquire("key", limit).await?, Acquire::Allowed);
+        let Acquire::Limited { retry_after } = s1.acquire("key", limit).await? else {
+            panic!("third request should be limited");
+        };
+        assert!(retry_after > Duration::from_secs(25));
+        assert!(retry_after <= Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_rate_limits_should_drop_full_buckets() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.rate_limit.routes = [("POST /signin".to_string(), RateLimit::new(1, 60))].into();
+        })
+        .await?;
+        let store = PgStore::new(state.pool.clone());
+        let limit = RateLimit::new(1, 60);
+        store.acquire("old", limit).await?;
+        store.acquire("new", limit).await?;
+        sqlx::query(
+            "UPDATE rate_limits SET updated_at = now() - interval '61 seconds' WHERE key = 'old'",
+        )
+        .execute(&state.pool)
+        .await?;
+
+        assert_eq!(state.prune_rate_limits().await?, 1);
+        let keys: Vec<String> = sqlx::query_scalar("SELECT key FROM rate_limits")
+            .fetch_all(&state.pool)
+            .await?;
+        assert_eq!(keys, ["new"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit_layer_should_key_by_ip_or_user() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.rate_limit.routes = [
+                ("POST /public".to_string(), RateLimit::new(1, 60)),
+                ("GET /private".to_string(), RateLimit::new(1, 60)),
+            ]
+            .into();
+        })
+        .await?;
+        let limit = RateLimitLayer::new(state.clone());
+        let app = Router::new()
+            .route("/private", get(|| async { "ok" }))
+            .layer(limit.clone())
+            .layer(from_fn_with_state(state.clone(), verify_token))
+            .merge(
+                Router::new()
+                    .route("/public", post(|| async { "ok" }))
+                    .route("/unlimited", get(|| async { "ok" }))
+                    .layer(limit),
+            )
+            .with_state(state.clone());
+
+        let public = || {
+            Request::builder()
+                .method("POST")
+                .uri("/public")
+                .body(Body::empty())
+        };
+        assert_eq!(
+            app.clone().oneshot(public()?).await?.status(),
+            StatusCode::OK
+        );
+        let res = app.clone().oneshot(public()?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "60");
+
+        let unlimited = || Request::builder().uri("/unlimited").body(Body::empty());
+        for _ in 0..3 {
+            let res = app.clone().oneshot(unlimited()?).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let private = |id: i64| -> Result<Request<Body>> {
+            let token = state.sk.encode(User::new(id, "test", "test@test.org"))?;
+            Ok(Request::builder()
+                .uri("/private")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())?)
+        };
+        assert_eq!(
+            app.clone().oneshot(private(1)?).await?.status(),
+            StatusCode::OK
+        );
+        let res = app.clone().oneshot(private(1)?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // another user has their own bucket
+        assert_eq!(app.oneshot(private(2)?).await?.status(), StatusCode::OK);
+        Ok(())
+    }
+}
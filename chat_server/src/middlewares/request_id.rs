@@ -1,6 +1,60 @@
-use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use axum::{
+    extract::Request,
+    http::{
+        self,
+        header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE},
+        HeaderMap, HeaderName, HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use tracing::{field, Span};
 
 use super::REQUEST_ID_HEADER;
+use crate::utils::set_traceparent;
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+// credentials which must never reach the logs
+const SENSITIVE_HEADERS: [HeaderName; 4] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE];
+
+/// span of a request, carrying its request id and the user id once the token is verified.
+/// it continues the trace of the W3C `traceparent` header
+pub fn request_span<B>(req: &http::Request<B>) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    // the path only, the query may carry an access token
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        version = ?req.version(),
+        request_id,
+        user_id = field::Empty,
+        headers = ?redact_headers(req.headers()),
+    );
+    if let Some(traceparent) = req
+        .headers()
+        .get(TRACEPARENT_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        set_traceparent(&span, traceparent);
+    }
+    span
+}
+
+/// copy of the headers with the credentials marked sensitive, `Debug` prints them as `Sensitive`
+fn redact_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for (name, value) in headers.iter_mut() {
+        if SENSITIVE_HEADERS.contains(name) {
+            value.set_sensitive(true);
+        }
+    }
+    headers
+}
 
 pub async fn set_request_id(mut req: Request, next: Next) -> Response {
     // if x-request-id is already set, do nothing, otherwise generate a new one uuid_v7
@@ -28,3 +82,25 @@ pub async fn set_request_id(mut req: Request, next: Next) -> Response {
     res.headers_mut().insert(REQUEST_ID_HEADER, id);
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_headers_should_hide_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret-token"),
+        );
+        headers.insert(COOKIE, HeaderValue::from_static("session=secret-cookie"));
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("request-id"));
+
+        let logged = format!("{:?}", redact_headers(&headers));
+        assert!(!logged.contains("secret-token"));
+        assert!(!logged.contains("secret-cookie"));
+        assert!(logged.contains("Sensitive"));
+        assert!(logged.contains("request-id"));
+    }
+}
@@ -0,0 +1,300 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::FromRow;
+use tracing::warn;
+
+use crate::{error::AppError, utils::RequestMeta, AppState, User};
+
+const DEFAULT_AUDIT_LIMIT: u64 = 50;
+const MAX_AUDIT_LIMIT: u64 = 500;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "audit_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Signup,
+    Signin,
+    SigninFailed,
+    ChatCreate,
+    MemberAdd,
+    MemberRemove,
+    MemberDeactivate,
+    MemberReactivate,
+    RoleChange,
+    OwnershipTransfer,
+    WorkspaceUpdate,
+    FileUpload,
+    FileDownload,
+    WorkspaceExport,
+    RetentionUpdate,
+    RetentionPurge,
+    MemberInvite,
+    MfaEnable,
+    MfaDisable,
+    PasswordReset,
+    PasswordChange,
+    EmailVerify,
+    UserUnlock,
+    SlackImport,
+    LogFilterUpdate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub ws_id: Option<i64>,
+    pub actor_id: Option<i64>,
+    pub action: AuditAction,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub details: Value,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// an event to record with `AppState::audit`
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+    ws_id: Option<i64>,
+    actor_id: Option<i64>,
+    action: AuditAction,
+    target: Option<(&'static str, String)>,
+    details: Value,
+}
+
+/// filters of `GET /api/audit`, newest first, pages go on from `last_id`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListAudit {
+    pub last_id: Option<u64>,
+    pub limit: Option<u64>,
+    pub action: Option<AuditAction>,
+    pub actor_id: Option<i64>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl NewAuditEvent {
+    /// an action of the user in its active workspace
+    pub fn new(action: AuditAction, user: &User) -> Self {
+        Self {
+            ws_id: Some(user.ws_id),
+            actor_id: Some(user.id),
+            action,
+            target: None,
+            details: Value::Object(Default::default()),
+        }
+    }
+
+    /// an action without an authenticated user, e.g. a failed signin
+    pub fn anonymous(action: AuditAction, ws_id: Option<i64>) -> Self {
+        Self {
+            ws_id,
+            actor_id: None,
+            action,
+            target: None,
+            details: Value::Object(Default::default()),
+        }
+    }
+
+    /// record it in another workspace than the active one of the user
+    pub fn workspace(mut self, ws_id: i64) -> Self {
+        self.ws_id = Some(ws_id);
+        self
+    }
+
+    pub fn target(mut self, kind: &'static str, id: impl ToString) -> Self {
+        self.target = Some((kind, id.to_string()));
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+impl AppState {
+    /// record the event, a failure is logged but doesn't fail the audited action
+    pub(crate) async fn audit(&self, meta: &RequestMeta, event: NewAuditEvent) {
+        let action = event.action;
+        if let Err(e) = self.record_audit_event(meta, event).await {
+            warn!("Failed to record audit event {:?}: {}", action, e);
+        }
+    }
+
+    /// the events of the active workspace of the user, workspace admins only
+    pub async fn list_audit_events(
+        &self,
+        user: &User,
+        input: &ListAudit,
+    ) -> Result<Vec<AuditEvent>, AppError> {
+        let ws = self.require_workspace_admin(user).await?;
+        let last_id = input.last_id.unwrap_or(i64::MAX as u64);
+        let limit = input
+            .limit
+            .unwrap_or(DEFAULT_AUDIT_LIMIT)
+            .clamp(1, MAX_AUDIT_LIMIT);
+        let events = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, actor_id, action, target_type, target_id, details, ip, user_agent,
+                request_id, created_at
+            FROM audit_events
+            WHERE ws_id = $1 AND id < $2
+                AND ($4::audit_action IS NULL OR action = $4)
+                AND ($5::bigint IS NULL OR actor_id = $5)
+                AND ($6::varchar IS NULL OR target_type = $6)
+                AND ($7::varchar IS NULL OR target_id = $7)
+                AND ($8::timestamptz IS NULL OR created_at >= $8)
+                AND ($9::timestamptz IS NULL OR created_at < $9)
+            ORDER BY id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(ws.id)
+        .bind(last_id as i64)
+        .bind(limit as i64)
+        .bind(input.action)
+        .bind(input.actor_id)
+        .bind(&input.target_type)
+        .bind(&input.target_id)
+        .bind(input.since)
+        .bind(input.until)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(events)
+    }
+
+    /// a failed signin is recorded in the workspace of the account, if the account exists
+    pub(crate) async fn audit_signin_failure(
+        &self,
+        meta: &RequestMeta,
+        email: &str,
+        err: &AppError,
+    ) {
+        let user = self.find_user_by_email(email).await.ok().flatten();
+        let event = NewAuditEvent::anonymous(AuditAction::SigninFailed, user.map(|u| u.ws_id))
+            .target("email", email.to_lowercase())
+            .details(serde_json::json!({ "reason": err.name() }));
+        self.audit(meta, event).await
+    }
+
+    async fn record_audit_event(
+        &self,
+        meta: &RequestMeta,
+        event: NewAuditEvent,
+    ) -> Result<(), AppError> {
+        let (target_type, target_id) = event.target.unzip();
+        sqlx::query(
+            r#"
+            INSERT INTO audit_events (ws_id, actor_id, action, target_type, target_id, details,
+                ip, user_agent, request_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(event.ws_id)
+        .bind(event.actor_id)
+        .bind(event.action)
+        .bind(target_type)
+        .bind(target_id)
+        .bind(event.details)
+        .bind(meta.ip.map(|ip| ip.to_string()))
+        .bind(&meta.user_agent)
+        .bind(&meta.request_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn meta() -> RequestMeta {
+        RequestMeta {
+            ip: Some("10.0.0.1".parse().unwrap()),
+            user_agent: Some("test".to_string()),
+            request_id: Some("request-id".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn audit_events_should_be_listed_by_admins() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state
+            .find_workspace_by_id(1)
+            .await?
+            .expect("ws should exist");
+        state.update_workspace_owner(ws, 1).await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let bob = state.find_user_by_id(2).await?.expect("user should exist");
+
+        let event = NewAuditEvent::new(AuditAction::ChatCreate, &bob).target("chat", 1);
+        state.audit(&meta(), event).await;
+        let err = AppError::InvalidCredentials;
+        state
+            .audit_signin_failure(&meta(), "Bob@test.org", &err)
+            .await;
+        // unknown accounts belong to no workspace
+        state
+            .audit_signin_failure(&meta(), "nobody@test.org", &err)
+            .await;
+
+        let err = state
+            .list_audit_events(&bob, &ListAudit::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let events = state
+            .list_audit_events(&alice, &ListAudit::default())
+            .await?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, AuditAction::SigninFailed);
+        assert_eq!(events[0].target_id.as_deref(), Some("bob@test.org"));
+        assert_eq!(events[1].actor_id, Some(bob.id));
+        assert_eq!(events[1].ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(events[1].request_id.as_deref(), Some("request-id"));
+
+        // filtered and paged
+        let input = ListAudit {
+            action: Some(AuditAction::ChatCreate),
+            ..Default::default()
+        };
+        let filtered = state.list_audit_events(&alice, &input).await?;
+        assert_eq!(filtered, events[1..]);
+        let input = ListAudit {
+            last_id: Some(events[0].id as _),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let page = state.list_audit_events(&alice, &input).await?;
+        assert_eq!(page, events[1..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn audit_events_should_be_append_only() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        state
+            .audit(&meta(), NewAuditEvent::new(AuditAction::Signin, &alice))
+            .await;
+        sqlx::query("UPDATE audit_events SET actor_id = 2")
+            .execute(&state.pool)
+            .await?;
+        sqlx::query("DELETE FROM audit_events")
+            .execute(&state.pool)
+            .await?;
+        let actors: Vec<Option<i64>> = sqlx::query_scalar("SELECT actor_id FROM audit_events")
+            .fetch_all(&state.pool)
+            .await?;
+        assert_eq!(actors, [Some(alice.id)]);
+        Ok(())
+    }
+}
@@ -50,7 +50,7 @@ impl AppState {
             r#"
             INSERT INTO chats (ws_id, name, members, type)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, members, type, created_at
+            RETURNING id, ws_id, name, members, type, retention_days, legal_hold, created_at
             "#,
         )
         .bind(ws_id as i64)
@@ -66,7 +66,7 @@ impl AppState {
     pub async fn find_chat_by_name(&self, name: &str) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, members, type, created_at
+            SELECT id, ws_id, name, members, type, retention_days, legal_hold, created_at
             FROM chats
             WHERE name = $1
             "#,
@@ -80,7 +80,7 @@ impl AppState {
     pub async fn find_chat_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, members, type, created_at
+            SELECT id, ws_id, name, members, type, retention_days, legal_hold, created_at
             FROM chats
             WHERE id = $1
             "#,
@@ -94,7 +94,7 @@ impl AppState {
     pub async fn fetch_all_chat(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, members, type, created_at
+            SELECT id, ws_id, name, members, type, retention_days, legal_hold, created_at
             FROM chats
             WHERE ws_id = $1
             "#,
@@ -1,18 +1,47 @@
 use std::{
+    fs::File,
     path::{Path, PathBuf},
     str::FromStr,
+    time::SystemTime,
 };
 
+use serde::{Deserialize, Serialize};
 use sha1::Digest;
 
 use crate::{error::AppError, ChatFile};
 
+const V2_PREFIX: &str = "v2";
+
+/// content addressing scheme of a stored file
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashVersion {
+    /// legacy sha1 addressing: /files/{ws_id}/{aaa}/{bbb}/{rest}.{ext}
+    V1,
+    /// sha256 addressing: /files/{ws_id}/v2/{aaa}/{bbb}/{rest}.{ext}
+    V2,
+}
+
 impl ChatFile {
     pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
-        let hash = sha1::Sha1::digest(data);
+        // the name comes from the client or an archive, only letters and digits reach the path
+        let ext: String = filename
+            .split('.')
+            .next_back()
+            .unwrap()
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect();
+        let ext = if ext.is_empty() { "bin" } else { &ext };
+        Self::with_ext(ws_id, ext, data)
+    }
+
+    pub(crate) fn with_ext(ws_id: u64, ext: &str, data: &[u8]) -> Self {
+        let hash = sha2::Sha256::digest(data);
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap().to_string(),
+            version: HashVersion::V2,
+            ext: ext.to_string(),
             hash: hex::encode(hash),
         }
     }
@@ -29,7 +58,42 @@ impl ChatFile {
     pub fn hash_to_path(&self) -> String {
         let (part1, part2) = self.hash.split_at(3);
         let (part2, part3) = part2.split_at(3);
-        format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
+        match self.version {
+            HashVersion::V1 => format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext),
+            HashVersion::V2 => format!(
+                "{}/{V2_PREFIX}/{}/{}/{}.{}",
+                self.ws_id, part1, part2, part3, self.ext
+            ),
+        }
+    }
+
+    /// write the blob under base_dir, returns false if it was already there. A blob stored
+    /// again is touched, so that the gc grace period starts over for the new reference
+    pub(crate) async fn store(&self, base_dir: &Path, data: &[u8]) -> Result<bool, AppError> {
+        let path = self.path(base_dir);
+        if path.exists() {
+            tokio::task::spawn_blocking(move || {
+                File::options()
+                    .append(true)
+                    .open(path)?
+                    .set_modified(SystemTime::now())
+            })
+            .await
+            .map_err(|e| AppError::IOError(std::io::Error::other(e)))??;
+            return Ok(false);
+        }
+        tokio::fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
+        tokio::fs::write(&path, data).await?;
+        Ok(true)
+    }
+
+    /// check that data is the content this file is addressed by
+    pub fn verify(&self, data: &[u8]) -> bool {
+        let hash = match self.version {
+            HashVersion::V1 => hex::encode(sha1::Sha1::digest(data)),
+            HashVersion::V2 => hex::encode(sha2::Sha256::digest(data)),
+        };
+        hash == self.hash
     }
 }
 
@@ -41,10 +105,15 @@ impl FromStr for ChatFile {
             return Err(AppError::ChatFileError(format!("{s} not match prefix")));
         };
 
-        let parts: Vec<&str> = s.split('/').collect();
-        if parts.len() != 4 {
-            return Err(AppError::ChatFileError(format!("{s} not match parts")));
-        }
+        let mut parts: Vec<&str> = s.split('/').collect();
+        let version = match parts.len() {
+            4 => HashVersion::V1,
+            5 if parts[1] == V2_PREFIX => {
+                parts.remove(1);
+                HashVersion::V2
+            }
+            _ => return Err(AppError::ChatFileError(format!("{s} not match parts"))),
+        };
         let Ok(ws_id) = parts[0].parse() else {
             return Err(AppError::ChatFileError(format!("{s} not match ws_id")));
         };
@@ -53,9 +122,22 @@ impl FromStr for ChatFile {
         };
 
         let hash = format!("{}{}{}", parts[1], parts[2], part3);
+        // hex of a sha1 or a sha256, split 3/3/rest by hash_to_path
+        let len = match version {
+            HashVersion::V1 => 40,
+            HashVersion::V2 => 64,
+        };
+        if parts[1].len() != 3
+            || parts[2].len() != 3
+            || hash.len() != len
+            || !hash.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(AppError::ChatFileError(format!("{s} not match hash")));
+        }
 
         Ok(Self {
             ws_id,
+            version,
             ext: ext.to_string(),
             hash,
         })
@@ -73,12 +155,44 @@ mod tests {
         let file = ChatFile::new(1, "test.txt", data);
         assert_eq!(file.ws_id, 1);
         assert_eq!(file.ext, "txt");
-        assert_eq!(file.hash.len(), 40);
-        assert_eq!(file.hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+        assert_eq!(file.version, HashVersion::V2);
+        assert_eq!(file.hash.len(), 64);
+        assert_eq!(
+            file.hash,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
         assert_eq!(
             file.url(),
-            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt"
+            "/files/1/v2/b94/d27/b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9.txt"
         );
+        assert!(file.verify(data));
+
+        let file = ChatFile::new(1, "x./../../..", data);
+        assert_eq!(file.ext, "bin");
+        let file = ChatFile::new(1, "x./../../etc", data);
+        assert_eq!(file.ext, "etc");
+        Ok(())
+    }
+
+    #[test]
+    fn chat_file_from_str_should_work() -> Result<()> {
+        let url = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt";
+        let file = ChatFile::from_str(url)?;
+        assert_eq!(file.version, HashVersion::V1);
+        assert_eq!(file.hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+        assert_eq!(file.url(), url);
+        assert!(file.verify(b"hello world"));
+
+        let url = ChatFile::new(1, "test.txt", b"hello world").url();
+        let file = ChatFile::from_str(&url)?;
+        assert_eq!(file.version, HashVersion::V2);
+        assert_eq!(file.url(), url);
+
+        assert!(ChatFile::from_str("/files/1/v3/b94/d27/b99.txt").is_err());
+        assert!(ChatFile::from_str("/files/1/v2/b94/d27/b99.txt").is_err());
+        assert!(ChatFile::from_str("/files/1/a/b/c.txt").is_err());
+        let url = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846eZ.txt";
+        assert!(ChatFile::from_str(url).is_err());
         Ok(())
     }
 }
@@ -0,0 +1,322 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::FromRow;
+use tracing::warn;
+
+use crate::{
+    error::AppError,
+    events::{AccountLockedEvent, AppEvent, Notification},
+    AppState, SigninUser, User,
+};
+
+// the progressive delay stops doubling after this many failures
+const MAX_DELAY_SHIFT: u32 = 10;
+
+#[derive(Debug, Clone, FromRow)]
+struct SigninFailures {
+    key: String,
+    failures: i32,
+    last_failed_at: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// verify the credentials unless the account or the ip is locked or has to wait after a
+    /// failure. Failures are recorded, and lock the account or the ip past the threshold
+    pub async fn signin(&self, input: &SigninUser, ip: Option<IpAddr>) -> Result<User, AppError> {
+        let account = account_key(&input.email);
+        let ip = ip.map(|ip| format!("ip:{}", ip));
+        let keys: Vec<_> = [Some(account.clone()), ip.clone()]
+            .into_iter()
+            .flatten()
+            .collect();
+        self.check_lockout(&keys).await?;
+
+        if let Some(user) = self.verify_user(input).await? {
+            sqlx::query("DELETE FROM signin_failures WHERE key = $1")
+                .bind(&account)
+                .execute(&self.pool)
+                .await?;
+            return Ok(user);
+        }
+
+        self.record_account_failure(&input.email).await?;
+        if let Some(ip) = ip {
+            let max_failures = self.config.auth.lockout.max_ip_failures;
+            if let Some(locked_until) = self.record_failure(&ip, max_failures).await? {
+                warn!("{} locked until {}", ip, locked_until);
+            }
+        }
+        Err(AppError::InvalidCredentials)
+    }
+
+    /// count a failed signin of the account, the user and the admins are notified on lock
+    pub(crate) async fn record_account_failure(&self, email: &str) -> Result<(), AppError> {
+        let max_failures = self.config.auth.lockout.max_failures;
+        let Some(locked_until) = self
+            .record_failure(&account_key(email), max_failures)
+            .await?
+        else {
+            return Ok(());
+        };
+        warn!("Account {} locked until {}", email, locked_until);
+        if let Some(user) = self.find_user_by_email(email).await? {
+            self.notify_locked(user.id, locked_until).await?;
+        }
+        Ok(())
+    }
+
+    /// clear the failures and the lock of an account
+    pub async fn unlock_user(&self, id: u64) -> Result<User, AppError> {
+        let user = self
+            .find_user_by_id(id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("user id: {id}")))?;
+        sqlx::query("DELETE FROM signin_failures WHERE key = $1")
+            .bind(account_key(&user.email))
+            .execute(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    /// drop the failures out of the window which neither lock nor delay a signin anymore
+    pub(crate) async fn prune_signin_failures(&self) -> Result<u64, AppError> {
+        let lockout = &self.config.auth.lockout;
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM signin_failures
+            WHERE (locked_until IS NULL OR locked_until < now())
+                AND last_failed_at < now() - make_interval(secs => GREATEST($1,
+                    $2 * 2 ^ LEAST(GREATEST(failures - 1, 0), $3)))
+            "#,
+        )
+        .bind(lockout.window as f64)
+        .bind(lockout.base_delay as f64)
+        .bind(MAX_DELAY_SHIFT as i32)
+        .execute(&self.pool)
+        .await?;
+        Ok(ret.rows_affected())
+    }
+
+    pub(crate) async fn check_lockout(&self, keys: &[String]) -> Result<(), AppError> {
+        let rows: Vec<SigninFailures> = sqlx::query_as(
+            r#"
+            SELECT key, failures, last_failed_at, locked_until
+            FROM signin_failures
+            WHERE key = ANY($1)
+            "#,
+        )
+        .bind(keys)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let now = Utc::now();
+        let lockout = &self.config.auth.lockout;
+        for row in rows {
+            if let Some(locked_until) = row.locked_until.filter(|until| *until > now) {
+                return Err(AppError::AccountLocked(seconds_until(now, locked_until)));
+            }
+            // the progressive delay only applies to accounts, an ip may be shared by many users
+            if !row.key.starts_with("email:") || row.failures == 0 {
+                continue;
+            }
+            let shift = (row.failures as u32 - 1).min(MAX_DELAY_SHIFT);
+            let delay = Duration::seconds((lockout.base_delay << shift) as i64);
+            if row.last_failed_at + delay > now {
+                return Err(AppError::RateLimited(seconds_until(
+                    now,
+                    row.last_failed_at + delay,
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // count the failure within the window, returns the end of the lock once the threshold is hit
+    async fn record_failure(
+        &self,
+        key: &str,
+        max_failures: u32,
+    ) -> Result<Option<DateTime<Utc>>, AppError> {
+        let lockout = &self.config.auth.lockout;
+        let (failures,): (i32,) = sqlx::query_as(
+            r#"
+            INSERT INTO signin_failures (key, failures, last_failed_at)
+            VALUES ($1, 1, now())
+            ON CONFLICT (key) DO UPDATE
+            SET failures = CASE
+                    WHEN signin_failures.last_failed_at < now() - make_interval(secs => $2)
+                    THEN 1
+                    ELSE signin_failures.failures + 1
+                END,
+                last_failed_at = now()
+            RETURNING failures
+            "#,
+        )
+        .bind(key)
+        .bind(lockout.window as f64)
+        .fetch_one(&self.pool)
+        .await?;
+        if (failures as u32) < max_failures {
+            return Ok(None);
+        }
+
+        // the count starts over once the lock expires
+        let (locked_until,): (DateTime<Utc>,) = sqlx::query_as(
+            r#"
+            UPDATE signin_failures
+            SET failures = 0, locked_until = now() + make_interval(secs => $2)
+            WHERE key = $1
+            RETURNING locked_until
+            "#,
+        )
+        .bind(key)
+        .bind(lockout.duration as f64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Some(locked_until))
+    }
+
+    async fn notify_locked(
+        &self,
+        user_id: i64,
+        locked_until: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let mut user_ids = self.config.auth.admins.clone();
+        if !user_ids.contains(&user_id) {
+            user_ids.push(user_id);
+        }
+        let event = AccountLockedEvent {
+            user_id,
+            locked_until,
+        };
+        Notification::new(user_ids, AppEvent::AccountLocked(event))
+            .publish(&self.pool)
+            .await
+    }
+}
+
+// emails are case insensitive, `Alice@test.org` and `alice@test.org` share the failures
+pub(crate) fn account_key(email: &str) -> String {
+    format!("email:{}", email.to_lowercase())
+}
+
+// whole seconds from now until then, rounded up
+fn seconds_until(now: DateTime<Utc>, then: DateTime<Utc>) -> u64 {
+    let ms = (then - now).num_milliseconds().max(0) as u64;
+    ms.div_ceil(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn signin_should_lock_account_after_max_failures() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.max_failures = 3;
+            config.auth.lockout.base_delay = 0;
+        })
+        .await?;
+        let ip = Some("10.0.0.1".parse()?);
+        let bad = SigninUser::new("Alice@test.org", "bad password");
+        for _ in 0..3 {
+            let err = state.signin(&bad, ip).await.unwrap_err();
+            assert!(matches!(err, AppError::InvalidCredentials));
+        }
+
+        // even the right password is rejected while locked
+        let good = SigninUser::new("alice@test.org", "123456");
+        let err = state.signin(&good, ip).await.unwrap_err();
+        let AppError::AccountLocked(retry_after) = err else {
+            panic!("account should be locked, got {:?}", err);
+        };
+        assert!(retry_after > 800 && retry_after <= 900);
+
+        // unknown accounts are locked the same way
+        let unknown = SigninUser::new("nobody@test.org", "123456");
+        for _ in 0..3 {
+            state.signin(&unknown, ip).await.unwrap_err();
+        }
+        let err = state.signin(&unknown, ip).await.unwrap_err();
+        assert!(matches!(err, AppError::AccountLocked(_)));
+
+        state.unlock_user(1).await?;
+        let good = SigninUser::new("Alice@test.org", "123456");
+        let user = state.signin(&good, ip).await?;
+        assert_eq!(user.id, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_delay_after_failure() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.base_delay = 60;
+        })
+        .await?;
+        let bad = SigninUser::new("Alice@test.org", "bad password");
+        let err = state.signin(&bad, None).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidCredentials));
+
+        let good = SigninUser::new("Alice@test.org", "123456");
+        let err = state.signin(&good, None).await.unwrap_err();
+        let AppError::RateLimited(retry_after) = err else {
+            panic!("signin should be delayed, got {:?}", err);
+        };
+        assert!(retry_after > 55 && retry_after <= 60);
+
+        // other accounts don't wait
+        let good = SigninUser::new("Bob@test.org", "123456");
+        state.signin(&good, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_should_lock_ip_after_max_failures() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.max_ip_failures = 2;
+            config.auth.lockout.base_delay = 0;
+        })
+        .await?;
+        let ip = Some("10.0.0.2".parse()?);
+        for email in ["Alice@test.org", "Bob@test.org"] {
+            let bad = SigninUser::new(email, "bad password");
+            state.signin(&bad, ip).await.unwrap_err();
+        }
+        let good = SigninUser::new("David@test.org", "123456");
+        let err = state.signin(&good, ip).await.unwrap_err();
+        assert!(matches!(err, AppError::AccountLocked(_)));
+        // the accounts themselves are not locked
+        state.signin(&good, None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_signin_failures_should_keep_active_rows() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.window = 60;
+            config.auth.lockout.base_delay = 10;
+        })
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO signin_failures (key, failures, last_failed_at, locked_until)
+            VALUES ('email:old', 1, now() - interval '1 hour', NULL),
+                ('email:delayed', 12, now() - interval '1 hour', NULL),
+                ('email:locked', 0, now() - interval '1 hour', now() + interval '1 hour'),
+                ('email:recent', 1, now(), NULL)
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+
+        assert_eq!(state.prune_signin_failures().await?, 1);
+        let keys: Vec<String> = sqlx::query_scalar("SELECT key FROM signin_failures ORDER BY key")
+            .fetch_all(&state.pool)
+            .await?;
+        assert_eq!(keys, ["email:delayed", "email:locked", "email:recent"]);
+        Ok(())
+    }
+}
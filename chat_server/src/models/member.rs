@@ -0,0 +1,545 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    error::AppError,
+    events::{AppEvent, MembershipEvent, Notification},
+    AppState, User, Workspace,
+};
+
+use super::{retention::validate_retention_days, ChatUser};
+
+const MAX_WORKSPACE_NAME: usize = 255;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    Member,
+    Admin,
+}
+
+/// a member as listed to the admins of the workspace
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct WorkspaceMember {
+    pub user_id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub role: WorkspaceRole,
+    /// the owner is an admin whatever the role
+    pub owner: bool,
+    pub deactivated_at: Option<DateTime<Utc>>,
+    pub joined_at: DateTime<Utc>,
+}
+
+/// fields left out are unchanged
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateWorkspace {
+    pub name: Option<String>,
+    pub require_mfa: Option<bool>,
+    /// 0 keeps the messages forever
+    pub retention_days: Option<i32>,
+}
+
+impl AppState {
+    /// the active workspace of the user
+    pub async fn get_workspace(&self, user: &User) -> Result<Workspace, AppError> {
+        self.find_workspace_by_id(user.ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace id: {}", user.ws_id)))
+    }
+
+    /// rename the workspace or change its retention, only the owner may require 2fa
+    pub async fn update_workspace(
+        &self,
+        user: &User,
+        input: UpdateWorkspace,
+    ) -> Result<Workspace, AppError> {
+        let ws = self.require_workspace_admin(user).await?;
+        if input.require_mfa.is_some() && ws.owner_id != user.id {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can require 2fa".to_string(),
+            ));
+        }
+        let name = input.name.map(|name| name.trim().to_string());
+        if let Some(name) = name.as_deref() {
+            if name.is_empty() || name.chars().count() > MAX_WORKSPACE_NAME {
+                return Err(AppError::InvalidInput(format!(
+                    "workspace name must be 1 to {MAX_WORKSPACE_NAME} characters"
+                )));
+            }
+            if self
+                .find_workspace_by_name(name)
+                .await?
+                .is_some_and(|other| other.id != ws.id)
+            {
+                return Err(AppError::InvalidInput(format!(
+                    "workspace {name} already exists"
+                )));
+            }
+        }
+        if let Some(days) = input.retention_days {
+            validate_retention_days(days)?;
+        }
+
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET name = COALESCE($2, name),
+                require_mfa = COALESCE($3, require_mfa),
+                retention_days = CASE WHEN $4::int IS NULL THEN retention_days
+                    ELSE NULLIF($4, 0) END
+            WHERE id = $1
+            RETURNING id, name, owner_id, require_mfa, retention_days, created_at
+            "#,
+        )
+        .bind(ws.id)
+        .bind(name)
+        .bind(input.require_mfa)
+        .bind(input.retention_days)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(ws)
+    }
+
+    /// hand the workspace over to an active member, the former owner stays as an admin
+    pub async fn transfer_ownership(
+        &self,
+        owner: &User,
+        user_id: i64,
+    ) -> Result<Workspace, AppError> {
+        let ws = self.require_workspace_owner(owner).await?;
+        let member = self.find_workspace_member(ws.id, user_id).await?;
+        if member.deactivated_at.is_some() {
+            return Err(AppError::InvalidInput(format!(
+                "user {user_id} is deactivated"
+            )));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "UPDATE workspace_members SET role = 'admin' WHERE ws_id = $1 AND user_id = $2",
+        )
+        .bind(ws.id)
+        .bind(owner.id)
+        .execute(&mut *tx)
+        .await?;
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET owner_id = $2
+            WHERE id = $1
+            RETURNING id, name, owner_id, require_mfa, retention_days, created_at
+            "#,
+        )
+        .bind(ws.id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(ws)
+    }
+
+    pub async fn list_workspace_members(
+        &self,
+        user: &User,
+    ) -> Result<Vec<WorkspaceMember>, AppError> {
+        let ws = self.require_workspace_admin(user).await?;
+        self.fetch_workspace_members(ws.id).await
+    }
+
+    pub(crate) async fn fetch_workspace_members(
+        &self,
+        ws_id: i64,
+    ) -> Result<Vec<WorkspaceMember>, AppError> {
+        let members = sqlx::query_as(
+            r#"
+            SELECT m.user_id, u.fullname, u.email, m.role, w.owner_id = m.user_id AS owner,
+                m.deactivated_at, m.joined_at
+            FROM workspace_members m
+            JOIN users u ON u.id = m.user_id
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE m.ws_id = $1
+            ORDER BY m.user_id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(members)
+    }
+
+    /// promote or demote a member, owner only
+    pub async fn set_member_role(
+        &self,
+        owner: &User,
+        user_id: i64,
+        role: WorkspaceRole,
+    ) -> Result<WorkspaceMember, AppError> {
+        let ws = self.require_workspace_owner(owner).await?;
+        self.find_managed_member(&ws, owner, user_id).await?;
+        sqlx::query("UPDATE workspace_members SET role = $3 WHERE ws_id = $1 AND user_id = $2")
+            .bind(ws.id)
+            .bind(user_id)
+            .bind(role)
+            .execute(&self.pool)
+            .await?;
+        self.find_workspace_member(ws.id, user_id).await
+    }
+
+    /// the tokens of the member are rejected by `verify_token` until reactivated
+    pub async fn deactivate_member(
+        &self,
+        admin: &User,
+        user_id: i64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let ws = self.require_workspace_admin(admin).await?;
+        self.find_managed_member(&ws, admin, user_id).await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET deactivated_at = COALESCE(deactivated_at, now())
+            WHERE ws_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(ws.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        leave_active_workspace(&mut tx, ws.id, user_id).await?;
+        revoke_membership(&mut tx, ws.id, user_id).await?;
+        tx.commit().await?;
+        self.find_workspace_member(ws.id, user_id).await
+    }
+
+    pub async fn reactivate_member(
+        &self,
+        admin: &User,
+        user_id: i64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let ws = self.require_workspace_admin(admin).await?;
+        self.find_managed_member(&ws, admin, user_id).await?;
+        sqlx::query(
+            "UPDATE workspace_members SET deactivated_at = NULL WHERE ws_id = $1 AND user_id = $2",
+        )
+        .bind(ws.id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        self.find_workspace_member(ws.id, user_id).await
+    }
+
+    /// remove the member from the workspace and from its chats. A chat it can't leave without
+    /// breaking the invariants of create_chat keeps it, e.g. a single chat or a group of 3: the
+    /// others keep their history, and the removed user has no access to the workspace anyway
+    pub async fn remove_member(&self, admin: &User, user_id: i64) -> Result<(), AppError> {
+        let ws = self.require_workspace_admin(admin).await?;
+        self.find_managed_member(&ws, admin, user_id).await?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM workspace_members WHERE ws_id = $1 AND user_id = $2")
+            .bind(ws.id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $2)
+            WHERE ws_id = $1
+                AND $2 = ANY(members)
+                AND type <> 'single'
+                AND cardinality(members) > CASE WHEN type = 'group' THEN 3 ELSE 2 END
+            "#,
+        )
+        .bind(ws.id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        leave_active_workspace(&mut tx, ws.id, user_id).await?;
+        revoke_membership(&mut tx, ws.id, user_id).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// the users of ids which are active members of the workspace, the recipients of its events
+    pub(crate) async fn find_active_chat_users(
+        &self,
+        ws_id: i64,
+        ids: &[i64],
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id
+            WHERE m.ws_id = $1 AND u.id = ANY($2) AND m.deactivated_at IS NULL
+            "#,
+        )
+        .bind(ws_id)
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users)
+    }
+
+    /// whether the user may use the api in the workspace, checked by `verify_token`
+    pub(crate) async fn is_active_member(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<bool, AppError> {
+        let active = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM workspace_members
+                WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NULL
+            )
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(active)
+    }
+
+    async fn find_workspace_member(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let member = sqlx::query_as(
+            r#"
+            SELECT m.user_id, u.fullname, u.email, m.role, w.owner_id = m.user_id AS owner,
+                m.deactivated_at, m.joined_at
+            FROM workspace_members m
+            JOIN users u ON u.id = m.user_id
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE m.ws_id = $1 AND m.user_id = $2
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        member.ok_or_else(|| AppError::NotFound(format!("member id: {user_id}")))
+    }
+
+    // the owner can't be managed, and an admin is managed by the owner only
+    async fn find_managed_member(
+        &self,
+        ws: &Workspace,
+        user: &User,
+        user_id: i64,
+    ) -> Result<WorkspaceMember, AppError> {
+        let member = self.find_workspace_member(ws.id, user_id).await?;
+        if member.owner || user_id == user.id {
+            return Err(AppError::PermissionDenied(
+                "the owner or yourself can't be managed".to_string(),
+            ));
+        }
+        if member.role == WorkspaceRole::Admin && ws.owner_id != user.id {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can manage admins".to_string(),
+            ));
+        }
+        Ok(member)
+    }
+
+    async fn require_workspace_owner(&self, user: &User) -> Result<Workspace, AppError> {
+        let ws = self.get_workspace(user).await?;
+        if ws.owner_id != user.id {
+            return Err(AppError::PermissionDenied(
+                "only the workspace owner can do this".to_string(),
+            ));
+        }
+        Ok(ws)
+    }
+
+    pub(crate) async fn require_workspace_admin(&self, user: &User) -> Result<Workspace, AppError> {
+        let ws = self.get_workspace(user).await?;
+        if ws.owner_id == user.id {
+            return Ok(ws);
+        }
+        let role: Option<WorkspaceRole> = sqlx::query_scalar(
+            r#"
+            SELECT role FROM workspace_members
+            WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NULL
+            "#,
+        )
+        .bind(ws.id)
+        .bind(user.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if role != Some(WorkspaceRole::Admin) {
+            return Err(AppError::PermissionDenied(
+                "only the workspace admins can do this".to_string(),
+            ));
+        }
+        Ok(ws)
+    }
+}
+
+// the next signin of the user starts in another active workspace, if there is one
+// notify_server closes the streams of the user once committed
+async fn revoke_membership(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ws_id: i64,
+    user_id: i64,
+) -> Result<(), AppError> {
+    let event = MembershipEvent { ws_id, user_id };
+    Notification::new(vec![user_id], AppEvent::MembershipRevoked(event))
+        .publish(&mut **tx)
+        .await
+}
+
+async fn leave_active_workspace(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ws_id: i64,
+    user_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET ws_id = m.ws_id
+        FROM (
+            SELECT ws_id FROM workspace_members
+            WHERE user_id = $2 AND ws_id <> $1 AND deactivated_at IS NULL
+            ORDER BY joined_at, ws_id
+            LIMIT 1
+        ) m
+        WHERE users.id = $2 AND users.ws_id = $1
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    // alice owns acme, the fixture workspaces are owned by the super user
+    async fn acme_owned_by_alice(state: &AppState) -> Result<(User, User, User)> {
+        let ws = state
+            .find_workspace_by_id(1)
+            .await?
+            .expect("ws should exist");
+        state.update_workspace_owner(ws, 1).await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let bob = state.find_user_by_id(2).await?.expect("user should exist");
+        let charlie = state.find_user_by_id(3).await?.expect("user should exist");
+        Ok((alice, bob, charlie))
+    }
+
+    #[tokio::test]
+    async fn update_workspace_should_check_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (alice, bob, _) = acme_owned_by_alice(&state).await?;
+        let input = UpdateWorkspace {
+            name: Some("acme corp".to_string()),
+            ..Default::default()
+        };
+        let err = state
+            .update_workspace(&bob, input.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        state
+            .set_member_role(&alice, bob.id, WorkspaceRole::Admin)
+            .await?;
+        let ws = state.update_workspace(&bob, input).await?;
+        assert_eq!(ws.name, "acme corp");
+        // admins don't require 2fa
+        let input = UpdateWorkspace {
+            require_mfa: Some(true),
+            ..Default::default()
+        };
+        let err = state
+            .update_workspace(&bob, input.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let ws = state.update_workspace(&alice, input).await?;
+        assert!(ws.require_mfa);
+        assert_eq!(ws.name, "acme corp");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transfer_ownership_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (alice, bob, _) = acme_owned_by_alice(&state).await?;
+        let err = state.transfer_ownership(&bob, bob.id).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let ws = state.transfer_ownership(&alice, bob.id).await?;
+        assert_eq!(ws.owner_id, bob.id);
+        let members = state.list_workspace_members(&alice).await?;
+        assert_eq!(members[0].role, WorkspaceRole::Admin);
+        assert!(!members[0].owner);
+        assert!(members[1].owner);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deactivate_member_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (alice, bob, charlie) = acme_owned_by_alice(&state).await?;
+        state
+            .set_member_role(&alice, bob.id, WorkspaceRole::Admin)
+            .await?;
+        // an admin can't deactivate the owner or another admin
+        for id in [alice.id, bob.id] {
+            let err = state.deactivate_member(&bob, id).await.unwrap_err();
+            assert!(matches!(err, AppError::PermissionDenied(_)));
+        }
+
+        let member = state.deactivate_member(&bob, charlie.id).await?;
+        assert!(member.deactivated_at.is_some());
+        assert!(!state.is_active_member(1, charlie.id).await?);
+        // a deactivated admin is no admin
+        state.deactivate_member(&alice, bob.id).await?;
+        let err = state.reactivate_member(&bob, charlie.id).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let member = state.reactivate_member(&alice, charlie.id).await?;
+        assert!(member.deactivated_at.is_none());
+        assert!(state.is_active_member(1, charlie.id).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_member_should_leave_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (alice, bob, charlie) = acme_owned_by_alice(&state).await?;
+        let err = state.remove_member(&bob, charlie.id).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let client = state.create_workspace("client", alice.id as _).await?;
+        state.add_workspace_member(client.id, charlie.id).await?;
+        state.remove_member(&alice, charlie.id).await?;
+        assert!(!state.is_workspace_member(1, charlie.id).await?);
+        let chats: Vec<Vec<i64>> =
+            sqlx::query_scalar("SELECT members FROM chats WHERE ws_id = 1 ORDER BY id")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(chats[1], vec![1, 2, 4, 5]);
+        // a group of 2 would be a single chat
+        assert_eq!(chats[3], vec![1, 2, 3]);
+        // the next signin starts in the workspace left
+        let charlie = state
+            .find_user_by_id(charlie.id as _)
+            .await?
+            .expect("user should exist");
+        assert_eq!(charlie.ws_id, client.id);
+        Ok(())
+    }
+}
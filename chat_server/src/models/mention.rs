@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    Postgres, Transaction,
+};
+
+use crate::{error::AppError, AppState, Message};
+
+use super::{ChatUser, ListMessage};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, sqlx::Type)]
+#[sqlx(type_name = "mention_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MentionKind {
+    User,
+    Channel,
+    Here,
+}
+
+impl PgHasArrayType for MentionKind {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_mention_kind")
+    }
+}
+
+/// a message in the mention inbox of a user
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow, PartialEq)]
+pub struct MentionedMessage {
+    pub kind: MentionKind,
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+}
+
+impl AppState {
+    pub(crate) async fn create_mentions(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        message_id: i64,
+        mentions: &HashMap<i64, MentionKind>,
+    ) -> Result<(), AppError> {
+        let (user_ids, kinds): (Vec<i64>, Vec<MentionKind>) = mentions.iter().unzip();
+        sqlx::query(
+            r#"
+            INSERT INTO message_mentions (message_id, user_id, kind)
+            SELECT $1, * FROM UNNEST($2::bigint[], $3::mention_kind[])
+            "#,
+        )
+        .bind(message_id)
+        .bind(user_ids)
+        .bind(kinds)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// the mentions of the user in the chats it is still a member of
+    pub async fn list_mentions(
+        &self,
+        user_id: u64,
+        input: &ListMessage,
+    ) -> Result<Vec<MentionedMessage>, AppError> {
+        let last_id = input.last_id.unwrap_or(i64::MAX as u64);
+        let messages = sqlx::query_as(
+            r#"
+            SELECT mm.kind, m.id, m.chat_id, m.sender_id, m.content, m.files, m.created_at
+            FROM message_mentions mm
+            JOIN messages m ON m.id = mm.message_id
+            JOIN chats c ON c.id = m.chat_id
+            WHERE mm.user_id = $1 AND mm.message_id < $2 AND mm.user_id = ANY(c.members)
+            ORDER BY mm.message_id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(last_id as i64)
+        .bind(input.limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    /// the users among ids whose presence is online, the ones `@here` mentions
+    pub(crate) async fn find_online_users(&self, ids: &[i64]) -> Result<HashSet<i64>, AppError> {
+        let ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT user_id FROM user_presence WHERE user_id = ANY($1) AND status = 'online'",
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids.into_iter().collect())
+    }
+}
+
+/// resolve the `@name`, `@channel` and `@here` mentions of content against the chat members,
+/// `@here` only mentions the online ones. The sender never mentions itself
+pub(crate) fn resolve_mentions(
+    content: &str,
+    members: &[ChatUser],
+    online: &HashSet<i64>,
+    sender_id: i64,
+) -> HashMap<i64, MentionKind> {
+    let mut mentions = HashMap::new();
+    for token in parse_mentions(content) {
+        match token.as_str() {
+            "channel" | "here" => {
+                let kind = if token == "channel" {
+                    MentionKind::Channel
+                } else {
+                    MentionKind::Here
+                };
+                for member in members {
+                    if kind == MentionKind::Here && !online.contains(&member.id) {
+                        continue;
+                    }
+                    // a direct mention is more specific than a broadcast one
+                    mentions.entry(member.id).or_insert(kind);
+                }
+            }
+            name => {
+                if let Some(member) = members.iter().find(|m| m.is_mentioned_as(name)) {
+                    mentions.insert(member.id, MentionKind::User);
+                }
+            }
+        }
+    }
+    mentions.remove(&sender_id);
+    mentions
+}
+
+// collect the lowercased names following an `@` at the start of a word
+fn parse_mentions(content: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut prev = None;
+    let mut chars = content.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_word_start = prev.is_none_or(char::is_whitespace);
+        prev = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+        let start = i + 1;
+        let mut end = start;
+        while let Some(&(j, c)) = chars.peek() {
+            if !(c.is_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+                break;
+            }
+            end = j + c.len_utf8();
+            prev = Some(c);
+            chars.next();
+        }
+        let token = content[start..end].trim_end_matches(['.', '-']);
+        if !token.is_empty() {
+            tokens.push(token.to_lowercase());
+        }
+    }
+    tokens
+}
+
+impl ChatUser {
+    // a user can be mentioned by the fullname without spaces or the email local part
+    fn is_mentioned_as(&self, name: &str) -> bool {
+        let fullname: String = self.fullname.split_whitespace().collect();
+        let local = self.email.split('@').next().unwrap_or_default();
+        fullname.to_lowercase() == name || local.to_lowercase() == name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CreateMessage;
+    use anyhow::Result;
+
+    #[test]
+    fn parse_mentions_should_work() {
+        let tokens = parse_mentions("@Alice hi, ping @bob. and @here! mail me at a@b.com @");
+        assert_eq!(tokens, vec!["alice", "bob", "here"]);
+    }
+
+    #[test]
+    fn resolve_mentions_should_work() {
+        let members = vec![
+            ChatUser::new(1, "Alice", "alice@test.org"),
+            ChatUser::new(2, "Bob Smith", "bob@test.org"),
+            ChatUser::new(3, "Charlie", "charlie@test.org"),
+        ];
+        let online = HashSet::from([1, 2]);
+        let mentions = resolve_mentions("hi @bobsmith and @nobody", &members, &online, 1);
+        assert_eq!(mentions, HashMap::from([(2, MentionKind::User)]));
+
+        let mentions = resolve_mentions("@alice @bob @channel", &members, &online, 1);
+        assert_eq!(
+            mentions,
+            HashMap::from([(2, MentionKind::User), (3, MentionKind::Channel)])
+        );
+
+        // charlie is not online
+        let mentions = resolve_mentions("@here", &members, &online, 1);
+        assert_eq!(mentions, HashMap::from([(2, MentionKind::Here)]));
+    }
+
+    #[tokio::test]
+    async fn list_mentions_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "@Bob are you there?".to_string(),
+            files: vec![],
+            send_at: None,
+        };
+        let message = state.create_message(input, 1, 1).await?;
+        sqlx::query("INSERT INTO user_presence (user_id, status) VALUES (2, 'online')")
+            .execute(&state.pool)
+            .await?;
+        let input = CreateMessage {
+            content: "@here standup".to_string(),
+            files: vec![],
+            send_at: None,
+        };
+        state.create_message(input, 2, 1).await?;
+
+        let input = ListMessage {
+            last_id: None,
+            limit: 10,
+        };
+        let mentions = state.list_mentions(2, &input).await?;
+        assert_eq!(mentions.len(), 2);
+        assert_eq!(mentions[0].kind, MentionKind::Here);
+        assert_eq!(mentions[1].kind, MentionKind::User);
+        assert_eq!(mentions[1].message, message);
+
+        // the sender is not mentioned by its own message, nor the offline members by `@here`
+        let mentions = state.list_mentions(1, &input).await?;
+        assert!(mentions.is_empty());
+        let mentions = state.list_mentions(3, &input).await?;
+        assert!(mentions.is_empty());
+
+        // bob left the chat of the first message
+        sqlx::query("UPDATE chats SET members = array_remove(members, 2) WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let mentions = state.list_mentions(2, &input).await?;
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].kind, MentionKind::Here);
+        Ok(())
+    }
+}
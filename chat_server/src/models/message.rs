@@ -1,11 +1,29 @@
-use crate::{error::AppError, AppState, ChatFile, Message};
+use crate::{
+    error::AppError,
+    events::{AppEvent, MentionEvent, Notification},
+    AppState, Chat, ChatFile, Message,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use sqlx::{Postgres, Transaction};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use super::mention::resolve_mentions;
+
+// the files are sent in the notification of the message, which must fit the NOTIFY limit
+const MAX_FILES: usize = 10;
+const MAX_FILE_URL: usize = 256;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateMessage {
     pub content: String,
     pub files: Vec<String>,
+    /// deliver the message later, see `/api/scheduled`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,19 +33,41 @@ pub struct ListMessage {
 }
 
 impl AppState {
-    #[allow(dead_code)]
     pub async fn create_message(
         &self,
         input: CreateMessage,
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
+        let chat = self.validate_message(&input, chat_id).await?;
+        let mut tx = self.pool.begin().await?;
+        let message = self.insert_message(&mut tx, chat, input, user_id).await?;
+        tx.commit().await?;
+        Ok(message)
+    }
+
+    /// the checks of a new message, the chat it is sent to is returned
+    pub(crate) async fn validate_message(
+        &self,
+        input: &CreateMessage,
+        chat_id: u64,
+    ) -> Result<Chat, AppError> {
         // verify content is not empty
         if input.content.is_empty() {
             return Err(AppError::CreateMessageError("Content is empty".to_string()));
         }
+        if input.files.len() > MAX_FILES {
+            return Err(AppError::CreateMessageError(format!(
+                "Too many files, at most {MAX_FILES}"
+            )));
+        }
         // verify files exist
         for s in &input.files {
+            if s.len() > MAX_FILE_URL {
+                return Err(AppError::CreateMessageError(format!(
+                    "File url too long: {s}"
+                )));
+            }
             let chatfile = ChatFile::from_str(s)?;
             let path = chatfile.path(&self.config.server.base_dir);
             if !path.exists() {
@@ -35,20 +75,71 @@ impl AppState {
             }
         }
 
-        // create message
-        let message = sqlx::query_as(
+        self.find_chat_by_id(chat_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat id: {chat_id}")))
+    }
+
+    /// create the message, its mentions and notifications in the transaction
+    pub(crate) async fn insert_message(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        chat: Chat,
+        input: CreateMessage,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        // deactivated members stay in the chat but receive nothing
+        let members = self
+            .find_active_chat_users(chat.ws_id, &chat.members)
+            .await?;
+        // presence only matters to `@here`
+        let online = if input.content.to_lowercase().contains("@here") {
+            let ids: Vec<i64> = members.iter().map(|member| member.id).collect();
+            self.find_online_users(&ids).await?
+        } else {
+            HashSet::new()
+        };
+        let mentions = resolve_mentions(&input.content, &members, &online, user_id as _);
+
+        let message: Message = sqlx::query_as(
             r#"
             INSERT INTO messages (chat_id, sender_id, content, files)
             VALUES ($1, $2, $3, $4)
             RETURNING id, chat_id, sender_id, content, files, created_at
             "#,
         )
-        .bind(chat_id as i64)
+        .bind(chat.id)
         .bind(user_id as i64)
         .bind(input.content)
         .bind(input.files)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut **tx)
         .await?;
+
+        let receivers = members
+            .iter()
+            .map(|member| member.id)
+            .filter(|id| *id != user_id as i64)
+            .collect();
+        Notification::new(receivers, AppEvent::NewMessage(message.clone()))
+            .publish(&mut **tx)
+            .await?;
+
+        if !mentions.is_empty() {
+            self.create_mentions(tx, message.id, &mentions).await?;
+            let mut by_kind = HashMap::new();
+            for (user_id, kind) in mentions {
+                by_kind.entry(kind).or_insert_with(Vec::new).push(user_id);
+            }
+            for (kind, user_ids) in by_kind {
+                let event = MentionEvent {
+                    kind,
+                    message: message.clone(),
+                };
+                Notification::new(user_ids, AppEvent::Mentioned(event))
+                    .publish(&mut **tx)
+                    .await?;
+            }
+        }
         Ok(message)
     }
 
@@ -90,6 +181,7 @@ mod tests {
         let input = CreateMessage {
             content: "Hello".to_string(),
             files: vec![],
+            send_at: None,
         };
 
         let message = state
@@ -103,18 +195,35 @@ mod tests {
         let input = CreateMessage {
             content: "Hello".to_string(),
             files: vec!["/files/1/12345/67890/abcdef.jpg".to_string()],
+            send_at: None,
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::ChatFileError(_)));
+        let input = CreateMessage {
+            content: "Hello".to_string(),
+            files: vec!["/files/1/123/456/7890abcdef7890abcdef7890abcdef7890.jpg".to_string()],
+            send_at: None,
         };
         let err = state.create_message(input, 1, 1).await.unwrap_err();
         assert_eq!(
             err.to_string(),
-            "create message error: File not exist: /files/1/12345/67890/abcdef.jpg"
+            "create message error: File not exist: \
+            /files/1/123/456/7890abcdef7890abcdef7890abcdef7890.jpg"
         );
 
         // valid files should work
         let fileurl = upload_dummy_file(&state)?;
+        let input = CreateMessage {
+            content: "Hello".to_string(),
+            files: vec![fileurl.clone(); MAX_FILES + 1],
+            send_at: None,
+        };
+        let err = state.create_message(input, 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::CreateMessageError(_)));
         let input = CreateMessage {
             content: "Hello".to_string(),
             files: vec![fileurl],
+            send_at: None,
         };
         let message = state
             .create_message(input, 1, 1)
@@ -0,0 +1,350 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{error::AppError, AppState, User};
+
+use super::lockout::account_key;
+
+// issuer shown by the authenticator apps
+const MFA_ISSUER: &str = "chat";
+const RECOVERY_CODES: usize = 10;
+
+/// the secret to add to an authenticator app, `otpauth_url` is the content of the qr code
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_url: String,
+}
+
+/// how the user completes a signin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MfaStatus {
+    /// the password is enough
+    Disabled,
+    /// a 2fa code is expected after the password
+    Enabled,
+    /// a workspace of the user requires 2fa, the user has to enroll first
+    EnrollRequired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaCode {
+    /// a totp code, or a recovery code
+    pub code: String,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct UserMfa {
+    secret: String,
+    enabled: bool,
+    last_step: i64,
+}
+
+impl AppState {
+    pub async fn mfa_status(&self, user: &User) -> Result<MfaStatus, AppError> {
+        let enabled: Option<bool> =
+            sqlx::query_scalar("SELECT enabled FROM user_mfa WHERE user_id = $1")
+                .bind(user.id)
+                .fetch_optional(&self.pool)
+                .await?;
+        if enabled == Some(true) {
+            return Ok(MfaStatus::Enabled);
+        }
+        if self.mfa_required(user).await? {
+            return Ok(MfaStatus::EnrollRequired);
+        }
+        Ok(MfaStatus::Disabled)
+    }
+
+    /// generate a new secret, 2fa is enabled once a code of it is verified by `confirm_mfa`
+    pub async fn enroll_mfa(&self, user: &User) -> Result<MfaEnrollment, AppError> {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        let totp = new_totp(&secret, &user.email)?;
+        // an enabled secret is never replaced, it has to be disabled first
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO user_mfa (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, last_step = 0
+            WHERE user_mfa.enabled = FALSE
+            "#,
+        )
+        .bind(user.id)
+        .bind(&secret)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::InvalidInput("2fa is already enabled".to_string()));
+        }
+        Ok(MfaEnrollment {
+            secret,
+            otpauth_url: totp.get_url(),
+        })
+    }
+
+    /// enable 2fa with a code of the enrolled secret, returns the recovery codes. They are
+    /// only stored hashed, so can't be shown again
+    pub async fn confirm_mfa(&self, user: &User, code: &str) -> Result<Vec<String>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let mfa: Option<UserMfa> = sqlx::query_as(
+            "SELECT secret, enabled, last_step FROM user_mfa WHERE user_id = $1 FOR UPDATE",
+        )
+        .bind(user.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let mfa = mfa.ok_or_else(|| AppError::NotFound("2fa enrollment".to_string()))?;
+        if mfa.enabled {
+            return Err(AppError::InvalidInput("2fa is already enabled".to_string()));
+        }
+        let totp = new_totp(&mfa.secret, &user.email)?;
+        let step = verify_totp(&totp, code, mfa.last_step).ok_or(AppError::InvalidMfaCode)?;
+
+        sqlx::query("UPDATE user_mfa SET enabled = TRUE, last_step = $2 WHERE user_id = $1")
+            .bind(user.id)
+            .bind(step)
+            .execute(&mut *tx)
+            .await?;
+        let codes: Vec<_> = (0..RECOVERY_CODES).map(|_| new_recovery_code()).collect();
+        let hashes: Vec<_> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO mfa_recovery_codes (user_id, code_hash)
+            SELECT $1, UNNEST($2::CHAR(64)[])
+            "#,
+        )
+        .bind(user.id)
+        .bind(&hashes)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(codes)
+    }
+
+    /// the second signin step, failures count towards the account lockout as the password ones
+    pub async fn signin_mfa(&self, user: &User, code: &str) -> Result<(), AppError> {
+        self.check_lockout(&[account_key(&user.email)]).await?;
+        if self.verify_mfa_code(user, code).await? {
+            return Ok(());
+        }
+        self.record_account_failure(&user.email).await?;
+        Err(AppError::InvalidMfaCode)
+    }
+
+    /// turn 2fa off with a code, unless a workspace of the user requires it
+    pub async fn disable_mfa(&self, user: &User, code: &str) -> Result<(), AppError> {
+        if self.mfa_status(user).await? == MfaStatus::Disabled {
+            return Err(AppError::InvalidInput("2fa is not enabled".to_string()));
+        }
+        if self.mfa_required(user).await? {
+            return Err(AppError::PermissionDenied(
+                "a workspace of the user requires 2fa".to_string(),
+            ));
+        }
+        self.signin_mfa(user, code).await?;
+
+        // the recovery codes go with the secret
+        sqlx::query("DELETE FROM user_mfa WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1")
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // any of the workspaces of the user requires 2fa
+    async fn mfa_required(&self, user: &User) -> Result<bool, AppError> {
+        let required = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM workspace_members m
+                JOIN workspaces w ON w.id = m.ws_id
+                WHERE m.user_id = $1 AND w.require_mfa
+            )
+            "#,
+        )
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(required)
+    }
+
+    // a totp code newer than the last accepted one, or an unused recovery code which is consumed
+    async fn verify_mfa_code(&self, user: &User, code: &str) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let mfa: Option<UserMfa> = sqlx::query_as(
+            r#"
+            SELECT secret, enabled, last_step
+            FROM user_mfa
+            WHERE user_id = $1 AND enabled = TRUE
+            FOR UPDATE
+            "#,
+        )
+        .bind(user.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(mfa) = mfa else {
+            return Ok(false);
+        };
+
+        let totp = new_totp(&mfa.secret, &user.email)?;
+        if let Some(step) = verify_totp(&totp, code, mfa.last_step) {
+            sqlx::query("UPDATE user_mfa SET last_step = $2 WHERE user_id = $1")
+                .bind(user.id)
+                .bind(step)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(true);
+        }
+
+        let ret =
+            sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = $1 AND code_hash = $2")
+                .bind(user.id)
+                .bind(hash_recovery_code(code))
+                .execute(&mut *tx)
+                .await?;
+        tx.commit().await?;
+        Ok(ret.rows_affected() == 1)
+    }
+}
+
+fn new_totp(secret: &str, email: &str) -> Result<TOTP, AppError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| AppError::InvalidInput(format!("2fa secret: {:?}", e)))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        secret,
+        Some(MFA_ISSUER.to_string()),
+        email.to_string(),
+    )
+    .map_err(|e| AppError::InvalidInput(format!("2fa secret: {}", e)))
+}
+
+// the time step of the code, one step of clock drift is allowed either way
+fn verify_totp(totp: &TOTP, code: &str, last_step: i64) -> Option<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let step = now / totp.step;
+    (step.saturating_sub(1)..=step + 1)
+        .filter(|s| *s as i64 > last_step)
+        .find(|s| totp.generate(s * totp.step) == code.trim())
+        .map(|s| s as i64)
+}
+
+// 10 hex digits as `xxxxx-xxxxx`
+fn new_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    OsRng.fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+// the dash and the case are optional when typing the code
+fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UpdateWorkspace;
+    use anyhow::Result;
+
+    async fn current_code(state: &AppState, user: &User) -> Result<String> {
+        let (secret,): (String,) = sqlx::query_as("SELECT secret FROM user_mfa WHERE user_id = $1")
+            .bind(user.id)
+            .fetch_one(&state.pool)
+            .await?;
+        Ok(new_totp(&secret, &user.email)?.generate_current()?)
+    }
+
+    #[tokio::test]
+    async fn mfa_should_enroll_and_verify() -> Result<()> {
+        // no delay after the failed codes
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.base_delay = 0;
+        })
+        .await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        assert_eq!(state.mfa_status(&user).await?, MfaStatus::Disabled);
+
+        let enrollment = state.enroll_mfa(&user).await?;
+        assert!(enrollment.otpauth_url.starts_with("otpauth://totp/chat:"));
+        // not enabled until a code is verified
+        assert_eq!(state.mfa_status(&user).await?, MfaStatus::Disabled);
+        let err = state.confirm_mfa(&user, "000000").await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidMfaCode));
+
+        let code = current_code(&state, &user).await?;
+        let codes = state.confirm_mfa(&user, &code).await?;
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert_eq!(state.mfa_status(&user).await?, MfaStatus::Enabled);
+        assert!(state.enroll_mfa(&user).await.is_err());
+
+        // the code used to confirm can't be replayed
+        let err = state.signin_mfa(&user, &code).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidMfaCode));
+
+        // recovery codes are single use
+        state.signin_mfa(&user, &codes[0].to_uppercase()).await?;
+        let err = state.signin_mfa(&user, &codes[0]).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidMfaCode));
+
+        state.disable_mfa(&user, &codes[1]).await?;
+        assert_eq!(state.mfa_status(&user).await?, MfaStatus::Disabled);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn required_mfa_should_not_be_disabled() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let other = state.find_user_by_id(2).await?.expect("user should exist");
+        let ws = state
+            .find_workspace_by_id(1)
+            .await?
+            .expect("ws should exist");
+        state.update_workspace_owner(ws, 1).await?;
+        let input = UpdateWorkspace {
+            require_mfa: Some(true),
+            ..Default::default()
+        };
+        let err = state
+            .update_workspace(&other, input.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let ws = state.update_workspace(&user, input).await?;
+        assert!(ws.require_mfa);
+        assert_eq!(state.mfa_status(&user).await?, MfaStatus::EnrollRequired);
+
+        state.enroll_mfa(&user).await?;
+        let code = current_code(&state, &user).await?;
+        let codes = state.confirm_mfa(&user, &code).await?;
+        let err = state.disable_mfa(&user, &codes[0]).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        Ok(())
+    }
+}
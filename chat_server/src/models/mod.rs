@@ -1,7 +1,18 @@
+mod audit;
 mod chat;
 mod file;
+mod lockout;
+mod member;
+mod mention;
 mod message;
+mod mfa;
+mod presence;
+mod profile;
+mod retention;
+mod scheduled;
+mod typing;
 mod user;
+mod user_token;
 mod workspace;
 
 use chrono::{DateTime, Utc};
@@ -9,11 +20,23 @@ use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
 pub use {
+    audit::{AuditAction, AuditEvent, ListAudit, NewAuditEvent},
     chat::CreateChat,
+    file::HashVersion,
+    member::{UpdateWorkspace, WorkspaceMember, WorkspaceRole},
+    mention::{MentionKind, MentionedMessage},
     message::{CreateMessage, ListMessage},
+    mfa::{MfaCode, MfaEnrollment, MfaStatus},
+    presence::{heartbeat_presence, PresenceStatus, UserPresence},
+    profile::{ChangePassword, UpdateProfile, UserProfile},
+    retention::{UpdateChatRetention, KEEP_FOREVER},
+    scheduled::{ScheduledMessage, ScheduledStatus},
     user::{ChatUser, SigninUser, UserInput},
+    user_token::{ForgotPassword, ResetPassword, VerifyEmail},
 };
 
+pub(crate) use user::placeholder_password_hash;
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
 pub struct User {
     pub id: i64,
@@ -32,6 +55,22 @@ pub struct Workspace {
     pub id: i64,
     pub name: String,
     pub owner_id: i64,
+    /// members must enable 2fa to use their account
+    pub require_mfa: bool,
+    /// messages older than this many days are purged, kept forever if unset
+    pub retention_days: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// an invitation to join a workspace, pending until the invited user accepts it
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct WorkspaceInvitation {
+    pub id: i64,
+    pub ws_id: i64,
+    /// name of the workspace
+    pub workspace: String,
+    pub email: String,
+    pub invited_by: Option<i64>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -51,12 +90,17 @@ pub struct Chat {
     pub name: Option<String>,
     pub members: Vec<i64>,
     pub r#type: ChatType,
+    /// overrides the retention of the workspace, -1 keeps the messages forever
+    pub retention_days: Option<i32>,
+    /// the messages are never purged while set
+    pub legal_hold: bool,
     pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatFile {
     pub ws_id: u64,
+    pub version: HashVersion,
     pub hash: String, //extract extension from name or mime type
     pub ext: String,
 }
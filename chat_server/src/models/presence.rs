@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+use crate::{
+    error::AppError,
+    events::{AppEvent, Notification, PresenceEvent},
+    AppState, User,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "presence_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct UserPresence {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+    pub last_active_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    pub async fn list_presence(&self, ws_id: u64) -> Result<Vec<UserPresence>, AppError> {
+        let presence = sqlx::query_as(
+            r#"
+            SELECT u.id AS user_id, COALESCE(p.status, 'offline') AS status, p.last_active_at
+            FROM workspace_members m
+            JOIN users u ON u.id = m.user_id
+            LEFT JOIN user_presence p ON p.user_id = u.id
+            WHERE m.ws_id = $1
+            ORDER BY u.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(presence)
+    }
+
+    /// record user activity, an away user becomes online again. Users without an sse
+    /// connection stay offline
+    pub async fn heartbeat(&self, user: &User) -> Result<PresenceStatus, AppError> {
+        heartbeat_presence(&self.pool, user.id).await
+    }
+}
+
+/// the heartbeat of a connected user, from the api for the sse clients or from the ping of the
+/// websocket ones
+pub async fn heartbeat_presence(pool: &PgPool, user_id: i64) -> Result<PresenceStatus, AppError> {
+    let mut tx = pool.begin().await?;
+    let prev: Option<(PresenceStatus,)> = sqlx::query_as(
+        r#"
+        SELECT status
+        FROM user_presence
+        WHERE user_id = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((prev,)) = prev else {
+        return Ok(PresenceStatus::Offline);
+    };
+    let status = match prev {
+        PresenceStatus::Away => PresenceStatus::Online,
+        status => status,
+    };
+    sqlx::query(
+        r#"
+        UPDATE user_presence
+        SET status = $2, last_active_at = now(), updated_at = now()
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(status)
+    .execute(&mut *tx)
+    .await?;
+
+    if status != prev {
+        let user_ids = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT user_id
+            FROM workspace_members
+            WHERE ws_id IN (SELECT ws_id FROM workspace_members WHERE user_id = $1)
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let event = PresenceEvent { user_id, status };
+        Notification::new(user_ids, AppEvent::PresenceChanged(event))
+            .publish(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn heartbeat_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exists");
+
+        // not connected
+        assert_eq!(state.heartbeat(&user).await?, PresenceStatus::Offline);
+
+        sqlx::query("INSERT INTO user_presence (user_id, status) VALUES (1, 'away')")
+            .execute(&state.pool)
+            .await?;
+        assert_eq!(state.heartbeat(&user).await?, PresenceStatus::Online);
+
+        let presence = state.list_presence(1).await?;
+        assert_eq!(presence.len(), 5);
+        assert_eq!(presence[0].status, PresenceStatus::Online);
+        assert!(presence[0].last_active_at.is_some());
+        assert_eq!(presence[1].status, PresenceStatus::Offline);
+        assert!(presence[1].last_active_at.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{
+    error::AppError,
+    events::{AppEvent, Notification},
+    AppState, ChatFile, SigninUser, User,
+};
+
+use super::{lockout::account_key, user::hash_password};
+
+const MAX_FULLNAME: usize = 64;
+const MAX_DISPLAY_NAME: usize = 64;
+const MAX_STATUS_TEXT: usize = 128;
+
+/// a user as shown to the members of the workspace
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct UserProfile {
+    pub id: i64,
+    pub ws_id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub status_text: Option<String>,
+    pub timezone: Option<String>,
+    /// url of an uploaded file, see `/api/upload`
+    pub avatar: Option<String>,
+    pub email_verified: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// fields left out are unchanged, an empty string clears an optional field
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateProfile {
+    pub fullname: Option<String>,
+    pub display_name: Option<String>,
+    pub status_text: Option<String>,
+    pub timezone: Option<String>,
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangePassword {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+impl AppState {
+    pub async fn get_profile(&self, id: i64) -> Result<UserProfile, AppError> {
+        let profile = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, display_name, status_text, timezone, avatar,
+                email_verified_at IS NOT NULL AS email_verified, created_at
+            FROM users
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        profile.ok_or_else(|| AppError::NotFound(format!("user id: {id}")))
+    }
+
+    /// update the profile and publish it to the members of the workspaces of the user
+    pub async fn update_profile(
+        &self,
+        user: &User,
+        input: UpdateProfile,
+    ) -> Result<UserProfile, AppError> {
+        let input = self.validate_profile(user, input)?;
+        let mut tx = self.pool.begin().await?;
+        // the optional fields are set to NULL by an empty string
+        let profile: UserProfile = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET fullname = COALESCE($2, fullname),
+                display_name = NULLIF(COALESCE($3, display_name), ''),
+                status_text = NULLIF(COALESCE($4, status_text), ''),
+                timezone = NULLIF(COALESCE($5, timezone), ''),
+                avatar = NULLIF(COALESCE($6, avatar), '')
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, display_name, status_text, timezone, avatar,
+                email_verified_at IS NOT NULL AS email_verified, created_at
+            "#,
+        )
+        .bind(user.id)
+        .bind(input.fullname)
+        .bind(input.display_name)
+        .bind(input.status_text)
+        .bind(input.timezone)
+        .bind(input.avatar)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let user_ids = self.fetch_workspace_peers(user.id).await?;
+        Notification::new(user_ids, AppEvent::UserUpdated(profile.clone()))
+            .publish(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(profile)
+    }
+
+    /// change the password after checking the current one, failures count towards the lockout
+    pub async fn change_password(
+        &self,
+        user: &User,
+        input: &ChangePassword,
+    ) -> Result<(), AppError> {
+        if input.new_password.is_empty() {
+            return Err(AppError::InvalidInput(
+                "password must not be empty".to_string(),
+            ));
+        }
+        self.check_lockout(&[account_key(&user.email)]).await?;
+        let signin = SigninUser {
+            email: user.email.clone(),
+            password: input.old_password.clone(),
+        };
+        if self.verify_user(&signin).await?.is_none() {
+            self.record_account_failure(&user.email).await?;
+            return Err(AppError::InvalidCredentials);
+        }
+
+        let password_hash = hash_password(&input.new_password)?;
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(password_hash)
+            .bind(user.id)
+            .execute(&self.pool)
+            .await?;
+        // a reset link mailed before is void once the password changed
+        self.revoke_password_resets(user.id).await
+    }
+
+    fn validate_profile(
+        &self,
+        user: &User,
+        mut input: UpdateProfile,
+    ) -> Result<UpdateProfile, AppError> {
+        for (name, value, max) in [
+            ("fullname", &mut input.fullname, MAX_FULLNAME),
+            ("display_name", &mut input.display_name, MAX_DISPLAY_NAME),
+            ("status_text", &mut input.status_text, MAX_STATUS_TEXT),
+        ] {
+            if let Some(v) = value {
+                *v = v.trim().to_string();
+                if v.chars().count() > max {
+                    return Err(AppError::InvalidInput(format!(
+                        "{name} is longer than {max} characters"
+                    )));
+                }
+            }
+        }
+        if input.fullname.as_deref() == Some("") {
+            return Err(AppError::InvalidInput(
+                "fullname must not be empty".to_string(),
+            ));
+        }
+        if let Some(tz) = input.timezone.as_deref().filter(|tz| !tz.is_empty()) {
+            Tz::from_str(tz)
+                .map_err(|_| AppError::InvalidInput(format!("unknown timezone: {tz}")))?;
+        }
+        if let Some(avatar) = input.avatar.as_deref().filter(|url| !url.is_empty()) {
+            // an uploaded file of the workspace of the user
+            let file = ChatFile::from_str(avatar)?;
+            let path = file.path(&self.config.server.base_dir);
+            if file.ws_id != user.ws_id as u64 || !path.exists() {
+                return Err(AppError::InvalidInput(format!(
+                    "avatar {avatar} is not an uploaded file"
+                )));
+            }
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn update_profile_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let avatar = ChatFile::new(user.ws_id as _, "avatar.png", b"avatar");
+        let path = avatar.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, b"avatar")?;
+
+        let input = UpdateProfile {
+            display_name: Some(" alice ".to_string()),
+            timezone: Some("Asia/Shanghai".to_string()),
+            avatar: Some(avatar.url()),
+            ..Default::default()
+        };
+        let profile = state.update_profile(&user, input).await?;
+        assert_eq!(profile.fullname, user.fullname);
+        assert_eq!(profile.display_name.as_deref(), Some("alice"));
+        assert_eq!(profile.timezone.as_deref(), Some("Asia/Shanghai"));
+        assert_eq!(profile.avatar, Some(avatar.url()));
+        assert!(!profile.email_verified);
+
+        // an empty string clears the field
+        let input = UpdateProfile {
+            display_name: Some("".to_string()),
+            ..Default::default()
+        };
+        let profile = state.update_profile(&user, input).await?;
+        assert_eq!(profile.display_name, None);
+        assert_eq!(profile.timezone.as_deref(), Some("Asia/Shanghai"));
+        assert_eq!(state.get_profile(1).await?, profile);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_profile_should_reject_invalid_fields() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let other_ws = ChatFile::new(2, "avatar.png", b"avatar");
+        let inputs = [
+            UpdateProfile {
+                fullname: Some(" ".to_string()),
+                ..Default::default()
+            },
+            UpdateProfile {
+                timezone: Some("Mars/Olympus".to_string()),
+                ..Default::default()
+            },
+            UpdateProfile {
+                avatar: Some(other_ws.url()),
+                ..Default::default()
+            },
+            UpdateProfile {
+                status_text: Some("x".repeat(MAX_STATUS_TEXT + 1)),
+                ..Default::default()
+            },
+        ];
+        for input in inputs {
+            let err = state.update_profile(&user, input).await.unwrap_err();
+            assert!(matches!(err, AppError::InvalidInput(_)));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn change_password_should_verify_old_password() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.auth.lockout.base_delay = 0;
+        })
+        .await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = ChangePassword {
+            old_password: "bad password".to_string(),
+            new_password: "new password".to_string(),
+        };
+        let err = state.change_password(&user, &input).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidCredentials));
+
+        let input = ChangePassword {
+            old_password: "123456".to_string(),
+            new_password: "new password".to_string(),
+        };
+        state.change_password(&user, &input).await?;
+        let signin = SigninUser::new(&user.email, "new password");
+        assert!(state.verify_user(&signin).await?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, AppState, User};
+
+use super::Chat;
+
+// a hundred years, longer is the same as forever
+const MAX_RETENTION_DAYS: i32 = 36500;
+/// the retention of a chat keeping its messages forever, whatever the workspace retention
+pub const KEEP_FOREVER: i32 = -1;
+
+/// fields left out are unchanged
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateChatRetention {
+    /// 0 falls back to the retention of the workspace, `KEEP_FOREVER` (-1) never purges
+    pub retention_days: Option<i32>,
+    pub legal_hold: Option<bool>,
+}
+
+impl AppState {
+    /// override the retention of a chat of the workspace or put it under legal hold,
+    /// workspace admins only
+    pub async fn update_chat_retention(
+        &self,
+        user: &User,
+        chat_id: u64,
+        input: &UpdateChatRetention,
+    ) -> Result<Chat, AppError> {
+        let ws = self.require_workspace_admin(user).await?;
+        if let Some(days) = input.retention_days.filter(|days| *days != KEEP_FOREVER) {
+            validate_retention_days(days)?;
+        }
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET retention_days = CASE WHEN $3::int IS NULL THEN retention_days
+                    ELSE NULLIF($3, 0) END,
+                legal_hold = COALESCE($4, legal_hold)
+            WHERE id = $1 AND ws_id = $2
+            RETURNING id, ws_id, name, members, type, retention_days, legal_hold, created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(ws.id)
+        .bind(input.retention_days)
+        .bind(input.legal_hold)
+        .fetch_optional(&self.pool)
+        .await?;
+        chat.ok_or_else(|| AppError::NotFound(format!("chat id: {chat_id}")))
+    }
+}
+
+pub(crate) fn validate_retention_days(days: i32) -> Result<(), AppError> {
+    if !(0..=MAX_RETENTION_DAYS).contains(&days) {
+        return Err(AppError::InvalidInput(format!(
+            "retention must be 0 to {MAX_RETENTION_DAYS} days"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn update_chat_retention_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state
+            .find_workspace_by_id(1)
+            .await?
+            .expect("ws should exist");
+        state.update_workspace_owner(ws, 1).await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let bob = state.find_user_by_id(2).await?.expect("user should exist");
+
+        let input = UpdateChatRetention {
+            retention_days: Some(30),
+            legal_hold: Some(true),
+        };
+        let err = state
+            .update_chat_retention(&bob, 1, &input)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let chat = state.update_chat_retention(&alice, 1, &input).await?;
+        assert_eq!(chat.retention_days, Some(30));
+        assert!(chat.legal_hold);
+
+        // 0 clears the override, the hold is kept
+        let input = UpdateChatRetention {
+            retention_days: Some(0),
+            ..Default::default()
+        };
+        let chat = state.update_chat_retention(&alice, 1, &input).await?;
+        assert_eq!(chat.retention_days, None);
+        assert!(chat.legal_hold);
+
+        let input = UpdateChatRetention {
+            retention_days: Some(KEEP_FOREVER),
+            ..Default::default()
+        };
+        let chat = state.update_chat_retention(&alice, 1, &input).await?;
+        assert_eq!(chat.retention_days, Some(KEEP_FOREVER));
+
+        let input = UpdateChatRetention {
+            retention_days: Some(-2),
+            ..Default::default()
+        };
+        let err = state
+            .update_chat_retention(&alice, 1, &input)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::InvalidInput(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{error::AppError, AppState};
+
+use super::CreateMessage;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "scheduled_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledStatus {
+    Pending,
+    Sent,
+    Canceled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    pub send_at: DateTime<Utc>,
+    pub status: ScheduledStatus,
+    /// the delivered message
+    pub message_id: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// keep the message until its `send_at`, it is checked now and again on delivery
+    pub async fn schedule_message(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<ScheduledMessage, AppError> {
+        let send_at = input.send_at.ok_or_else(|| {
+            AppError::CreateMessageError("send_at is required to schedule".to_string())
+        })?;
+        self.validate_message(&input, chat_id).await?;
+        let scheduled = sqlx::query_as(
+            r#"
+            INSERT INTO scheduled_messages (chat_id, sender_id, content, files, send_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, chat_id, sender_id, content, files, send_at, status, message_id, error,
+                created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(input.content)
+        .bind(input.files)
+        .bind(send_at)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(scheduled)
+    }
+
+    /// the messages of the user waiting for delivery, the next one first
+    pub async fn list_scheduled_messages(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<ScheduledMessage>, AppError> {
+        let messages = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, files, send_at, status, message_id, error,
+                created_at
+            FROM scheduled_messages
+            WHERE sender_id = $1 AND status = 'pending'
+            ORDER BY send_at, id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    /// only the author cancels a message, and only until it is delivered
+    pub async fn cancel_scheduled_message(
+        &self,
+        id: u64,
+        user_id: u64,
+    ) -> Result<ScheduledMessage, AppError> {
+        // waits for a delivery in progress, which holds the row lock
+        let scheduled = sqlx::query_as(
+            r#"
+            UPDATE scheduled_messages
+            SET status = 'canceled'
+            WHERE id = $1 AND sender_id = $2 AND status = 'pending'
+            RETURNING id, chat_id, sender_id, content, files, send_at, status, message_id, error,
+                created_at
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        scheduled.ok_or_else(|| AppError::NotFound(format!("pending scheduled message id: {id}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    fn input(content: &str) -> CreateMessage {
+        CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+            send_at: Some(Utc::now() + chrono::Duration::hours(1)),
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduled_messages_should_be_canceled_by_author() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let first = state.schedule_message(input("later"), 1, 1).await?;
+        state.schedule_message(input("later again"), 2, 1).await?;
+        assert_eq!(first.status, ScheduledStatus::Pending);
+        let err = state.schedule_message(input(""), 1, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::CreateMessageError(_)));
+
+        assert_eq!(state.list_scheduled_messages(1).await?.len(), 2);
+        assert!(state.list_scheduled_messages(2).await?.is_empty());
+
+        let err = state
+            .cancel_scheduled_message(first.id as _, 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        let canceled = state.cancel_scheduled_message(first.id as _, 1).await?;
+        assert_eq!(canceled.status, ScheduledStatus::Canceled);
+        let messages = state.list_scheduled_messages(1).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "later again");
+        Ok(())
+    }
+}
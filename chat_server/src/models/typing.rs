@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use dashmap::mapref::entry::Entry;
+
+use crate::{
+    error::AppError,
+    events::{AppEvent, Notification, TypingEvent},
+    AppState,
+};
+
+// typing events of a user in a chat are sent at most once in this interval
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+// prune the rate limit state once it grows beyond this many entries
+const TYPING_MAX_ENTRIES: usize = 10_000;
+
+impl AppState {
+    /// notify the other chat members that user is typing, returns false if rate limited
+    pub async fn send_typing(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
+        if !self.typing_allowed(chat_id, user_id) {
+            return Ok(false);
+        }
+        let chat = self
+            .find_chat_by_id(chat_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("chat id: {chat_id}")))?;
+        let receivers = self
+            .find_active_chat_users(chat.ws_id, &chat.members)
+            .await?
+            .into_iter()
+            .map(|member| member.id)
+            .filter(|id| *id != user_id as i64)
+            .collect();
+        let event = TypingEvent {
+            chat_id: chat_id as _,
+            user_id: user_id as _,
+        };
+        Notification::new(receivers, AppEvent::Typing(event))
+            .publish(&self.pool)
+            .await?;
+        Ok(true)
+    }
+
+    fn typing_allowed(&self, chat_id: u64, user_id: u64) -> bool {
+        let now = Instant::now();
+        if self.typing.len() > TYPING_MAX_ENTRIES {
+            self.typing
+                .retain(|_, last| now.duration_since(*last) < TYPING_INTERVAL);
+        }
+        match self.typing.entry((chat_id, user_id)) {
+            Entry::Occupied(mut last) => {
+                if now.duration_since(*last.get()) < TYPING_INTERVAL {
+                    return false;
+                }
+                last.insert(now);
+            }
+            Entry::Vacant(last) => {
+                last.insert(now);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn send_typing_should_be_rate_limited() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(state.send_typing(1, 1).await?);
+        assert!(!state.send_typing(1, 1).await?);
+        // other chats and users are not affected
+        assert!(state.send_typing(2, 1).await?);
+        assert!(state.send_typing(1, 2).await?);
+        Ok(())
+    }
+}
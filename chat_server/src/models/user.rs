@@ -1,11 +1,17 @@
 use crate::{error::AppError, AppState, User};
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+    },
     Argon2,
 };
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
-use std::mem;
+use std::{mem, sync::OnceLock};
+
+// verified in place of the hash of unknown accounts, so they take as long as the known ones
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
 pub struct UserInput {
@@ -51,18 +57,25 @@ impl AppState {
         Ok(user)
     }
 
-    /// create 方法
+    /// create 方法, the user owns the new workspace. Existing workspaces are only joined by
+    /// accepting an invitation
     /// TODO: use transaction to ensure workspace binding and user creation are atomic
     pub async fn create_user(&self, user: &UserInput) -> Result<User, AppError> {
         // check if user exists
         if self.find_user_by_email(&user.email).await?.is_some() {
             return Err(AppError::EmailAlreadyExists(user.email.to_string()));
         }
-        // check if workspace exists
-        let ws = match self.find_workspace_by_name(&user.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&user.workspace, 0).await?,
-        };
+        if self
+            .find_workspace_by_name(&user.workspace)
+            .await?
+            .is_some()
+        {
+            return Err(AppError::InvalidInput(format!(
+                "workspace {} already exists, ask its admins for an invitation",
+                user.workspace
+            )));
+        }
+        let ws = self.create_workspace(&user.workspace, 0).await?;
 
         // 使用 argon2 生成密码哈希
         let password = hash_password(&user.password)?;
@@ -79,9 +92,8 @@ impl AppState {
         .bind(password)
         .fetch_one(&self.pool)
         .await?;
-        if ws.owner_id == 0 {
-            self.update_workspace_owner(ws, user.id as _).await?;
-        }
+        self.add_workspace_member(ws.id, user.id).await?;
+        self.update_workspace_owner(ws, user.id as _).await?;
         Ok(user)
     }
 
@@ -107,7 +119,11 @@ impl AppState {
                     Ok(None)
                 }
             }
-            None => Ok(None),
+            None => {
+                // do the argon2 work anyway, or the response time tells the account doesn't exist
+                verify_password(&input.password, dummy_hash()?)?;
+                Ok(None)
+            }
         }
     }
 
@@ -144,7 +160,7 @@ impl User {
     }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+pub(crate) fn hash_password(password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
 
     // Argon2 with default params (Argon2id v19)
@@ -155,6 +171,21 @@ fn hash_password(password: &str) -> Result<String, AppError> {
     Ok(password_hash.to_string())
 }
 
+/// the hash of a random password nobody knows, for the accounts created on behalf of a user
+pub(crate) fn placeholder_password_hash() -> Result<String, AppError> {
+    let mut password = [0u8; 32];
+    OsRng.fill_bytes(&mut password);
+    hash_password(&hex::encode(password))
+}
+
+fn dummy_hash() -> Result<&'static str, AppError> {
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash);
+    }
+    let hash = hash_password("dummy password")?;
+    Ok(DUMMY_HASH.get_or_init(|| hash))
+}
+
 fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
     let argon2 = Argon2::default();
     let parsed_hash = PasswordHash::new(password_hash)?;
@@ -171,7 +202,8 @@ impl User {
     pub fn new(id: i64, fullname: &str, email: &str) -> Self {
         Self {
             id,
-            ws_id: 0,
+            // the workspace of the fixture users
+            ws_id: 1,
             fullname: fullname.to_string(),
             email: email.to_string(),
             password_hash: None,
@@ -180,6 +212,17 @@ impl User {
     }
 }
 
+#[cfg(test)]
+impl ChatUser {
+    pub fn new(id: i64, fullname: &str, email: &str) -> Self {
+        Self {
+            id,
+            fullname: fullname.to_string(),
+            email: email.to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 impl UserInput {
     pub fn new(fullname: &str, email: &str, workspace: &str, password: &str) -> Self {
@@ -225,7 +268,7 @@ mod tests {
         // init test data
         let name = "firstero";
         let email = "firsero@acme.org";
-        let workspace = "acme";
+        let workspace = "firstero";
         let password = "password";
 
         let user_input = UserInput::new(name, email, workspace, password);
@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::{error::AppError, AppState, Mail, TokenScope, User};
+
+use super::user::hash_password;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "user_token_purpose", rename_all = "snake_case")]
+enum TokenPurpose {
+    EmailVerify,
+    PasswordReset,
+    Invite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForgotPassword {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+impl TokenPurpose {
+    fn scope(&self) -> TokenScope {
+        match self {
+            TokenPurpose::EmailVerify => TokenScope::EmailVerify,
+            TokenPurpose::PasswordReset => TokenScope::PasswordReset,
+            TokenPurpose::Invite => TokenScope::Invite,
+        }
+    }
+}
+
+impl AppState {
+    /// mail the email verification link to the user
+    pub async fn send_email_verification(&self, user: &User) -> Result<(), AppError> {
+        let token = self
+            .issue_user_token(user, TokenPurpose::EmailVerify)
+            .await?;
+        let body = format!(
+            "Hi {},\n\nPlease confirm your email address by opening the link below within 24 hours:\n\n{}\n",
+            user.fullname,
+            self.mail_link("verify-email", &token),
+        );
+        let mail = Mail::new(&user.email, "Confirm your email address", body);
+        self.mailer.send(&mail).await
+    }
+
+    pub async fn verify_email(&self, token: &str) -> Result<User, AppError> {
+        let user_id = self
+            .consume_user_token(token, TokenPurpose::EmailVerify)
+            .await?;
+        let user = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET email_verified_at = COALESCE(email_verified_at, now())
+            WHERE id = $1
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    /// mail a password reset link in the background. Nothing tells whether the email has an
+    /// account: the lookup also runs in the background, so the caller does the same work, and
+    /// unknown emails and delivery failures are only logged
+    pub fn forgot_password(&self, email: &str) -> JoinHandle<()> {
+        let state = self.clone();
+        let email = email.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = state.send_password_reset(&email).await {
+                warn!("Failed to mail the password reset link to {}: {}", email, e);
+            }
+        })
+    }
+
+    async fn send_password_reset(&self, email: &str) -> Result<(), AppError> {
+        let Some(user) = self.find_user_by_email(email).await? else {
+            info!("Password reset requested for unknown email {}", email);
+            return Ok(());
+        };
+        let token = self
+            .issue_user_token(&user, TokenPurpose::PasswordReset)
+            .await?;
+        let body = format!(
+            "Hi {},\n\nOpen the link below within an hour to choose a new password:\n\n{}\n\nIf you didn't ask for it, you can ignore this email.\n",
+            user.fullname,
+            self.mail_link("reset-password", &token),
+        );
+        let mail = Mail::new(&user.email, "Reset your password", body);
+        self.mailer.send(&mail).await
+    }
+
+    /// mail an invitation to an account created for the user, its link sets the first password
+    pub async fn send_invitation(&self, user: &User, workspace: &str) -> Result<(), AppError> {
+        let token = self.issue_user_token(user, TokenPurpose::Invite).await?;
+        let body = format!(
+            "Hi {},\n\nYou are invited to the {} workspace. Open the link below within 7 days to choose your password:\n\n{}\n",
+            user.fullname,
+            workspace,
+            self.mail_link("reset-password", &token),
+        );
+        let mail = Mail::new(
+            &user.email,
+            format!("You are invited to {}", workspace),
+            body,
+        );
+        self.mailer.send(&mail).await
+    }
+
+    /// set the password with a reset or an invitation token, which also unlocks the account
+    pub async fn reset_password(&self, input: &ResetPassword) -> Result<User, AppError> {
+        if input.password.is_empty() {
+            return Err(AppError::InvalidInput(
+                "password must not be empty".to_string(),
+            ));
+        }
+        let user_id = match self
+            .consume_user_token(&input.token, TokenPurpose::PasswordReset)
+            .await
+        {
+            Err(AppError::InvalidToken) => {
+                self.consume_user_token(&input.token, TokenPurpose::Invite)
+                    .await?
+            }
+            ret => ret?,
+        };
+        let password_hash = hash_password(&input.password)?;
+        // the link was mailed to the address, which is verified too
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET password_hash = $1, email_verified_at = COALESCE(email_verified_at, now())
+            WHERE id = $2
+            "#,
+        )
+        .bind(password_hash)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        // the other reset links are void once the password changed
+        self.revoke_password_resets(user_id).await?;
+        self.unlock_user(user_id as _).await
+    }
+
+    async fn issue_user_token(
+        &self,
+        user: &User,
+        purpose: TokenPurpose,
+    ) -> Result<String, AppError> {
+        let id = uuid::Uuid::now_v7().to_string();
+        let scope = purpose.scope();
+        sqlx::query(
+            r#"
+            INSERT INTO user_tokens (id, user_id, purpose, expires_at)
+            VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+            "#,
+        )
+        .bind(&id)
+        .bind(user.id)
+        .bind(purpose)
+        .bind(scope.duration() as f64)
+        .execute(&self.pool)
+        .await?;
+        self.sk.encode_single_use(user.clone(), scope, &id)
+    }
+
+    // mark the token as used, returns the id of its user
+    async fn consume_user_token(
+        &self,
+        token: &str,
+        purpose: TokenPurpose,
+    ) -> Result<i64, AppError> {
+        let (user, id) = self.pk.verify_single_use(token, purpose.scope())?;
+        let user_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE user_tokens
+            SET used_at = now()
+            WHERE id = $1 AND user_id = $2 AND purpose = $3
+                AND used_at IS NULL AND expires_at > now()
+            RETURNING user_id
+            "#,
+        )
+        .bind(&id)
+        .bind(user.id)
+        .bind(purpose)
+        .fetch_optional(&self.pool)
+        .await?;
+        user_id.ok_or(AppError::InvalidToken)
+    }
+
+    /// void the outstanding password reset and invitation links of the user
+    pub(crate) async fn revoke_password_resets(&self, user_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE user_tokens
+            SET used_at = now()
+            WHERE user_id = $1 AND purpose IN ($2, $3) AND used_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(TokenPurpose::PasswordReset)
+        .bind(TokenPurpose::Invite)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    fn mail_link(&self, page: &str, token: &str) -> String {
+        let base_url = self.config.mail.base_url.trim_end_matches('/');
+        format!("{}/{}?token={}", base_url, page, token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SigninUser;
+    use anyhow::Result;
+
+    // the token of the link in the mail
+    fn link_token(mail: &Mail) -> &str {
+        let start = mail.body.find("?token=").expect("mail should have a link") + 7;
+        mail.body[start..].split_whitespace().next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reset_password_should_work_once() -> Result<()> {
+        let (_tdb, state, mailer) = AppState::new_for_test_with_mailer(|_| {}).await?;
+        state.forgot_password("nobody@test.org").await?;
+        assert!(mailer.mails().is_empty());
+
+        state.forgot_password("Alice@test.org").await?;
+        state.forgot_password("Alice@test.org").await?;
+        let mails = mailer.mails();
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0].to, "Alice@test.org");
+
+        let input = ResetPassword {
+            token: link_token(&mails[0]).to_string(),
+            password: "new password".to_string(),
+        };
+        state.reset_password(&input).await?;
+        let user = state
+            .signin(&SigninUser::new("Alice@test.org", "new password"), None)
+            .await?;
+        assert_eq!(user.id, 1);
+
+        // the used token and the other outstanding one are both void
+        let err = state.reset_password(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidToken));
+        let input = ResetPassword {
+            token: link_token(&mails[1]).to_string(),
+            password: "another password".to_string(),
+        };
+        let err = state.reset_password(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidToken));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_email_should_reject_other_tokens() -> Result<()> {
+        let (_tdb, state, mailer) = AppState::new_for_test_with_mailer(|_| {}).await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        state.send_email_verification(&user).await?;
+        state.forgot_password(&user.email).await?;
+        let mails = mailer.mails();
+
+        // a password reset token doesn't verify the email
+        let err = state.verify_email(link_token(&mails[1])).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidToken));
+
+        let verified = state.verify_email(link_token(&mails[0])).await?;
+        assert_eq!(verified.id, user.id);
+        let (verified_at,): (Option<chrono::DateTime<chrono::Utc>>,) =
+            sqlx::query_as("SELECT email_verified_at FROM users WHERE id = 1")
+                .fetch_one(&state.pool)
+                .await?;
+        assert!(verified_at.is_some());
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{error::AppError, AppState};
 
-use super::{ChatUser, Workspace};
+use super::{ChatUser, User, Workspace, WorkspaceInvitation};
 
 impl AppState {
     pub async fn create_workspace(&self, name: &str, owner_id: u64) -> Result<Workspace, AppError> {
@@ -8,7 +8,7 @@ impl AppState {
             r#"
             INSERT INTO workspaces (name, owner_id)
             VALUES ($1, $2)
-            RETURNING id, name, owner_id, created_at
+            RETURNING id, name, owner_id, require_mfa, retention_days, created_at
             "#,
         )
         .bind(name)
@@ -22,7 +22,7 @@ impl AppState {
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
-            SELECT id, name, owner_id, created_at
+            SELECT id, name, owner_id, require_mfa, retention_days, created_at
             FROM workspaces
             WHERE name = $1
             "#,
@@ -34,11 +34,10 @@ impl AppState {
     }
 
     /// find_by_id 方法
-    #[allow(dead_code)]
     pub async fn find_workspace_by_id(&self, id: i64) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
-            SELECT id, name, owner_id, created_at
+            SELECT id, name, owner_id, require_mfa, retention_days, created_at
             FROM workspaces
             WHERE id = $1
             "#,
@@ -59,7 +58,7 @@ impl AppState {
             UPDATE workspaces
             SET owner_id = $1
             WHERE id = $2
-            RETURNING id, name, owner_id, created_at
+            RETURNING id, name, owner_id, require_mfa, retention_days, created_at
             "#,
         )
         .bind(owner_id as i64)
@@ -69,12 +68,15 @@ impl AppState {
         Ok(ws)
     }
 
+    /// the members of the workspace
     pub async fn fetch_all_chat_users(&self, id: i64) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-            SELECT id, fullname, email
-            FROM users
-            WHERE ws_id = $1
+            SELECT u.id, u.fullname, u.email
+            FROM workspace_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.ws_id = $1
+            ORDER BY u.id
             "#,
         )
         .bind(id)
@@ -82,13 +84,199 @@ impl AppState {
         .await?;
         Ok(users)
     }
+
+    /// the users sharing a workspace with the user, the user included
+    pub async fn fetch_workspace_peers(&self, user_id: i64) -> Result<Vec<i64>, AppError> {
+        let ids = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT user_id
+            FROM workspace_members
+            WHERE ws_id IN (SELECT ws_id FROM workspace_members WHERE user_id = $1)
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids)
+    }
+
+    /// the workspaces the user is a member of
+    pub async fn list_user_workspaces(&self, user_id: i64) -> Result<Vec<Workspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, w.require_mfa, w.retention_days, w.created_at
+            FROM workspace_members m
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE m.user_id = $1
+            ORDER BY m.joined_at, w.id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(workspaces)
+    }
+
+    pub async fn is_workspace_member(&self, ws_id: i64, user_id: i64) -> Result<bool, AppError> {
+        let is_member = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM workspace_members WHERE ws_id = $1 AND user_id = $2)",
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(is_member)
+    }
+
+    pub async fn add_workspace_member(&self, ws_id: i64, user_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO workspace_members (ws_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// invite an email to the workspace of the admin, the account joins once it accepts. The
+    /// result is the same whether an account has this email or not
+    pub async fn invite_workspace_member(
+        &self,
+        admin: &User,
+        email: &str,
+    ) -> Result<WorkspaceInvitation, AppError> {
+        let ws = self.require_workspace_admin(admin).await?;
+        let email = email.trim().to_lowercase();
+        if email.is_empty() {
+            return Err(AppError::InvalidInput(
+                "email must not be empty".to_string(),
+            ));
+        }
+        let invitation = sqlx::query_as(
+            r#"
+            WITH i AS (
+                INSERT INTO workspace_invitations (ws_id, email, invited_by)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (ws_id, email)
+                DO UPDATE SET invited_by = EXCLUDED.invited_by, created_at = now()
+                RETURNING id, ws_id, email, invited_by, created_at
+            )
+            SELECT i.id, i.ws_id, w.name AS workspace, i.email, i.invited_by, i.created_at
+            FROM i
+            JOIN workspaces w ON w.id = i.ws_id
+            "#,
+        )
+        .bind(ws.id)
+        .bind(email)
+        .bind(admin.id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(invitation)
+    }
+
+    /// the invitations sent to the email of the user
+    pub async fn list_invitations(
+        &self,
+        user: &User,
+    ) -> Result<Vec<WorkspaceInvitation>, AppError> {
+        let invitations = sqlx::query_as(
+            r#"
+            SELECT i.id, i.ws_id, w.name AS workspace, i.email, i.invited_by, i.created_at
+            FROM workspace_invitations i
+            JOIN workspaces w ON w.id = i.ws_id
+            WHERE i.email = lower($1)
+            ORDER BY i.id
+            "#,
+        )
+        .bind(&user.email)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(invitations)
+    }
+
+    /// join the workspace of the invitation, a deactivated membership stays deactivated. The
+    /// email of the account must be verified, or anyone signing up with it would take it
+    pub async fn accept_invitation(&self, user: &User, id: i64) -> Result<Workspace, AppError> {
+        let verified: bool =
+            sqlx::query_scalar("SELECT email_verified_at IS NOT NULL FROM users WHERE id = $1")
+                .bind(user.id)
+                .fetch_one(&self.pool)
+                .await?;
+        if !verified {
+            return Err(AppError::PermissionDenied(
+                "verify your email before accepting an invitation".to_string(),
+            ));
+        }
+        let mut tx = self.pool.begin().await?;
+        let ws_id: Option<i64> = sqlx::query_scalar(
+            "DELETE FROM workspace_invitations WHERE id = $1 AND email = lower($2) RETURNING ws_id",
+        )
+        .bind(id)
+        .bind(&user.email)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let ws_id = ws_id.ok_or_else(|| AppError::NotFound(format!("invitation id: {id}")))?;
+        sqlx::query(
+            r#"
+            INSERT INTO workspace_members (ws_id, user_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user.id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.find_workspace_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace id: {ws_id}")))
+    }
+
+    pub async fn decline_invitation(&self, user: &User, id: i64) -> Result<(), AppError> {
+        let result =
+            sqlx::query("DELETE FROM workspace_invitations WHERE id = $1 AND email = lower($2)")
+                .bind(id)
+                .bind(&user.email)
+                .execute(&self.pool)
+                .await?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("invitation id: {id}")));
+        }
+        Ok(())
+    }
+
+    /// make ws_id the active workspace of the user, the one the next signin starts in
+    pub async fn switch_workspace(&self, user: &User, ws_id: i64) -> Result<User, AppError> {
+        if !self.is_workspace_member(ws_id, user.id).await? {
+            return Err(AppError::NotFound(format!("workspace id: {ws_id}")));
+        }
+        let user = sqlx::query_as(
+            r#"
+            UPDATE users
+            SET ws_id = $1
+            WHERE id = $2
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user.id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(user)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
 
-    use crate::{models::UserInput, AppState};
+    use crate::{error::AppError, models::UserInput, AppState};
 
     #[tokio::test]
     async fn workspace_find_should_work() -> Result<()> {
@@ -108,19 +296,18 @@ mod tests {
     #[tokio::test]
     async fn workspace_create_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        // 先有 workspace 再有 user
+        // 先有 workspace 再有 user: joining it takes an invitation
         let ws = state.create_workspace("ws_create", 1).await?;
+        assert_eq!(ws.name, "ws_create");
+        assert_eq!(ws.owner_id, 1);
         let input = UserInput::new(
             "test_for_ws_create",
             "test_for_ws_create@org",
             &ws.name,
             "password",
         );
-        let user = state.create_user(&input).await?;
-
-        assert_eq!(ws.name, "ws_create");
-        assert_eq!(user.ws_id, ws.id);
-        assert_eq!(ws.owner_id, 1);
+        let err = state.create_user(&input).await.unwrap_err();
+        assert!(matches!(err, AppError::InvalidInput(_)));
         // 直接创建 user，并创建 workspace
         let input = UserInput::new(
             "test_for_ws_create2",
@@ -138,4 +325,65 @@ mod tests {
         assert_eq!(ws.owner_id, user.id);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn workspace_members_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let bob = state.find_user_by_id(2).await?.expect("user should exist");
+        let ws = state.create_workspace("client", alice.id as _).await?;
+        state.add_workspace_member(ws.id, alice.id).await?;
+
+        let workspaces = state.list_user_workspaces(alice.id).await?;
+        let names: Vec<_> = workspaces.iter().map(|ws| ws.name.as_str()).collect();
+        assert_eq!(names, ["acme", "client"]);
+        // bob is not a member yet
+        let err = state.switch_workspace(&bob, ws.id).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // only the admins of the active workspace invite
+        let err = state
+            .invite_workspace_member(&bob, &alice.email)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        let alice = state.switch_workspace(&alice, ws.id).await?;
+        assert_eq!(alice.ws_id, ws.id);
+        let invitation = state.invite_workspace_member(&alice, &bob.email).await?;
+        // an unknown email is invited the same way
+        let unknown = state
+            .invite_workspace_member(&alice, "nobody@test.org")
+            .await?;
+        assert_eq!(unknown.workspace, invitation.workspace);
+
+        // bob joins only once he accepts
+        let err = state.switch_workspace(&bob, ws.id).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        let invitations = state.list_invitations(&bob).await?;
+        assert_eq!(invitations.len(), 1);
+        assert_eq!(invitations[0], invitation);
+        let err = state
+            .accept_invitation(&bob, invitation.id)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+        sqlx::query("UPDATE users SET email_verified_at = now()")
+            .execute(&state.pool)
+            .await?;
+        let err = state
+            .accept_invitation(&alice, invitation.id)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        state.accept_invitation(&bob, invitation.id).await?;
+        assert!(state.list_invitations(&bob).await?.is_empty());
+
+        let bob = state.switch_workspace(&bob, ws.id).await?;
+        assert_eq!(bob.ws_id, ws.id);
+        let users = state.fetch_all_chat_users(ws.id).await?;
+        assert_eq!(users.len(), 2);
+        // the other members of acme share a workspace with bob
+        assert_eq!(state.fetch_workspace_peers(bob.id).await?.len(), 5);
+        Ok(())
+    }
 }
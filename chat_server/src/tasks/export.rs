@@ -0,0 +1,728 @@
+//! the archive of a workspace export is a zip of
+//!
+//! - `manifest.json`: the `ExportManifest`, with the size and sha256 of every other entry
+//! - `users.jsonl`: the `UserProfile` of the members, without their password hashes
+//! - `members.jsonl`: the `WorkspaceMember` rows with their roles
+//! - `chats.jsonl`: the `Chat` rows of the workspace
+//! - `messages.jsonl`: the `Message` rows of these chats, oldest first
+//! - `files/<ws_id>/...`: the blobs referenced by the messages and avatars, at the path of
+//!   their `ChatFile` url
+//!
+//! a `.jsonl` file has one json object per line
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    error::AppError, AppState, ChatFile, Message, TokenScope, User, UserProfile, Workspace,
+};
+
+/// bumped on any change of the archive layout or of the records
+pub const EXPORT_SCHEMA_VERSION: u32 = 1;
+
+// messages are read by pages of this size
+const MESSAGE_BATCH: i64 = 1000;
+// writes queued for the archive writer
+const ARCHIVE_QUEUE: usize = 64;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "export_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ExportStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    /// the archive was removed after `export.ttl`
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct WorkspaceExport {
+    pub id: i64,
+    pub ws_id: i64,
+    pub requested_by: Option<i64>,
+    pub status: ExportStatus,
+    /// bytes of the archive once done
+    pub size: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// signed download link of a done export, valid for an hour
+    #[sqlx(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExportManifest {
+    pub schema_version: u32,
+    pub workspace: Workspace,
+    pub exported_at: DateTime<Utc>,
+    pub entries: Vec<ManifestEntry>,
+    /// urls referenced by the records whose blob is gone
+    pub missing_files: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// number of lines of a `.jsonl` entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub records: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ExportSweepReport {
+    /// pending or running for longer than `export.timeout`
+    pub interrupted: u64,
+    /// done exports whose archive was removed
+    pub expired: u64,
+}
+
+// the writes of an archive, sent to the blocking task writing it
+enum ArchiveOp {
+    Entry(String),
+    /// a line of the current `.jsonl` entry
+    Record(Vec<u8>),
+    EndEntry {
+        jsonl: bool,
+    },
+    /// a blob copied from the disk, listed as missing when it can't be opened
+    Blob {
+        url: String,
+        entry: String,
+        path: PathBuf,
+    },
+}
+
+// a zip written entry by entry, keeping the size and the checksum of each of them
+struct ArchiveWriter {
+    zip: ZipWriter<File>,
+    entries: Vec<ManifestEntry>,
+    current: Option<(String, Sha256, u64, u64)>,
+}
+
+impl WorkspaceExport {
+    /// where the archive is kept
+    pub fn path(&self, base_dir: &Path) -> PathBuf {
+        base_dir
+            .join("exports")
+            .join(self.ws_id.to_string())
+            .join(format!("{}.zip", self.id))
+    }
+}
+
+impl AppState {
+    /// start an export of the active workspace in the background, workspace admins only.
+    /// An export already in progress is returned instead of starting another one
+    pub async fn start_workspace_export(&self, user: &User) -> Result<WorkspaceExport, AppError> {
+        let ws = self.require_workspace_admin(user).await?;
+        self.fail_interrupted_exports().await?;
+        let running: Option<WorkspaceExport> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, requested_by, status, size, error, created_at, finished_at
+            FROM workspace_exports
+            WHERE ws_id = $1 AND status IN ('pending', 'running')
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(ws.id)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(export) = running {
+            return Ok(export);
+        }
+
+        let export = self.create_workspace_export(ws.id, Some(user.id)).await?;
+        let state = self.clone();
+        let id = export.id;
+        tokio::spawn(async move {
+            if let Err(e) = state.run_workspace_export(id).await {
+                warn!("Failed to run workspace export {}: {}", id, e);
+            }
+        });
+        Ok(export)
+    }
+
+    /// the export of the active workspace, with its download link once done
+    pub async fn get_workspace_export(
+        &self,
+        user: &User,
+        id: i64,
+    ) -> Result<WorkspaceExport, AppError> {
+        let ws = self.require_workspace_admin(user).await?;
+        let mut export = self
+            .find_workspace_export(id)
+            .await?
+            .filter(|export| export.ws_id == ws.id)
+            .ok_or_else(|| AppError::NotFound(format!("export id: {id}")))?;
+        if export.status == ExportStatus::Done {
+            let token = self.sk.encode_single_use(
+                user.clone(),
+                TokenScope::ExportDownload,
+                &id.to_string(),
+            )?;
+            export.url = Some(format!("/api/exports/{id}/download?token={token}"));
+        }
+        Ok(export)
+    }
+
+    /// the archive of a done export, the token comes from its signed download link
+    pub async fn verify_export_download(
+        &self,
+        id: i64,
+        token: &str,
+    ) -> Result<WorkspaceExport, AppError> {
+        let (user, export_id) = self
+            .pk
+            .verify_single_use(token, TokenScope::ExportDownload)?;
+        if export_id != id.to_string() {
+            return Err(AppError::InvalidToken);
+        }
+        self.find_workspace_export(id)
+            .await?
+            .filter(|export| export.ws_id == user.ws_id && export.status == ExportStatus::Done)
+            .ok_or_else(|| AppError::NotFound(format!("export id: {id}")))
+    }
+
+    pub(crate) async fn create_workspace_export(
+        &self,
+        ws_id: i64,
+        requested_by: Option<i64>,
+    ) -> Result<WorkspaceExport, AppError> {
+        let export = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_exports (ws_id, requested_by)
+            VALUES ($1, $2)
+            RETURNING id, ws_id, requested_by, status, size, error, created_at, finished_at
+            "#,
+        )
+        .bind(ws_id)
+        .bind(requested_by)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(export)
+    }
+
+    /// write the archive of a pending export, the failure is kept on the export
+    pub(crate) async fn run_workspace_export(&self, id: i64) -> Result<WorkspaceExport, AppError> {
+        let export: WorkspaceExport = sqlx::query_as(
+            r#"
+            UPDATE workspace_exports
+            SET status = 'running'
+            WHERE id = $1 AND status = 'pending'
+            RETURNING id, ws_id, requested_by, status, size, error, created_at, finished_at
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("pending export id: {id}")))?;
+
+        let path = export.path(&self.config.server.base_dir);
+        let (status, size, error) = match self.write_export_archive(export.ws_id, &path).await {
+            Ok(size) => {
+                info!("Exported workspace {} to {:?}", export.ws_id, path);
+                (ExportStatus::Done, Some(size as i64), None)
+            }
+            Err(e) => {
+                warn!("Failed to export workspace {}: {}", export.ws_id, e);
+                (ExportStatus::Failed, None, Some(e.to_string()))
+            }
+        };
+        let export = sqlx::query_as(
+            r#"
+            UPDATE workspace_exports
+            SET status = $2, size = $3, error = $4, finished_at = now()
+            WHERE id = $1
+            RETURNING id, ws_id, requested_by, status, size, error, created_at, finished_at
+            "#,
+        )
+        .bind(id)
+        .bind(status)
+        .bind(size)
+        .bind(error)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(export)
+    }
+
+    async fn find_workspace_export(&self, id: i64) -> Result<Option<WorkspaceExport>, AppError> {
+        let export = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, requested_by, status, size, error, created_at, finished_at
+            FROM workspace_exports
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(export)
+    }
+
+    // written to a `.part` file first, a download never sees a partial archive. The records
+    // are read here and written by a blocking task, which also streams the blobs from the disk
+    async fn write_export_archive(&self, ws_id: i64, path: &Path) -> Result<u64, AppError> {
+        let workspace = self
+            .find_workspace_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace id: {ws_id}")))?;
+        let dir = path.parent().expect("export path should have a parent");
+        tokio::fs::create_dir_all(dir).await?;
+        let part = path.with_extension("zip.part");
+        let file = File::create(&part)?;
+        let (tx, rx) = mpsc::channel(ARCHIVE_QUEUE);
+        let writer = tokio::task::spawn_blocking(move || write_archive(file, rx, workspace));
+        let sent = self.send_export_records(ws_id, tx).await;
+        let written = writer
+            .await
+            .map_err(|e| AppError::IOError(io::Error::other(e)))?;
+        // a failed writer also fails the sends, its error is the one to report
+        if let Err(e) = written.and(sent) {
+            if let Err(e) = tokio::fs::remove_file(&part).await {
+                warn!("Failed to remove export {:?}: {}", part, e);
+            }
+            return Err(e);
+        }
+        tokio::fs::rename(&part, path).await?;
+        Ok(tokio::fs::metadata(path).await?.len())
+    }
+
+    async fn send_export_records(
+        &self,
+        ws_id: i64,
+        tx: mpsc::Sender<ArchiveOp>,
+    ) -> Result<(), AppError> {
+        let send = |op| {
+            let tx = tx.clone();
+            async move {
+                tx.send(op)
+                    .await
+                    .map_err(|_| AppError::IOError(io::Error::other("archive writer stopped")))
+            }
+        };
+        let mut urls = BTreeSet::new();
+
+        let users = self.fetch_export_users(ws_id).await?;
+        send(ArchiveOp::Entry("users.jsonl".to_string())).await?;
+        for user in &users {
+            urls.extend(user.avatar.clone());
+            send(ArchiveOp::Record(serde_json::to_vec(user)?)).await?;
+        }
+        send(ArchiveOp::EndEntry { jsonl: true }).await?;
+
+        let members = self.fetch_workspace_members(ws_id).await?;
+        send(ArchiveOp::Entry("members.jsonl".to_string())).await?;
+        for member in &members {
+            send(ArchiveOp::Record(serde_json::to_vec(member)?)).await?;
+        }
+        send(ArchiveOp::EndEntry { jsonl: true }).await?;
+
+        let mut chats = self.fetch_all_chat(ws_id as _).await?;
+        chats.sort_by_key(|chat| chat.id);
+        send(ArchiveOp::Entry("chats.jsonl".to_string())).await?;
+        for chat in &chats {
+            send(ArchiveOp::Record(serde_json::to_vec(chat)?)).await?;
+        }
+        send(ArchiveOp::EndEntry { jsonl: true }).await?;
+
+        send(ArchiveOp::Entry("messages.jsonl".to_string())).await?;
+        let mut last_id = 0;
+        loop {
+            let messages = self.fetch_export_messages(ws_id, last_id).await?;
+            let Some(last) = messages.last() else {
+                break;
+            };
+            last_id = last.id;
+            for message in &messages {
+                urls.extend(message.files.iter().cloned());
+                send(ArchiveOp::Record(serde_json::to_vec(message)?)).await?;
+            }
+        }
+        send(ArchiveOp::EndEntry { jsonl: true }).await?;
+
+        let base_dir = &self.config.server.base_dir;
+        for url in urls {
+            // only the blobs of the workspace, a reference to another one is not followed
+            let file = match ChatFile::from_str(&url) {
+                Ok(file) if file.ws_id == ws_id as u64 => file,
+                _ => continue,
+            };
+            let op = ArchiveOp::Blob {
+                entry: format!("files/{}", file.hash_to_path()),
+                path: file.path(base_dir),
+                url,
+            };
+            send(op).await?;
+        }
+        Ok(())
+    }
+
+    /// fail the exports interrupted by a restart and remove the expired archives
+    pub async fn sweep_workspace_exports(&self) -> Result<ExportSweepReport, AppError> {
+        let interrupted = self.fail_interrupted_exports().await?;
+        let expired: Vec<WorkspaceExport> = sqlx::query_as(
+            r#"
+            UPDATE workspace_exports
+            SET status = 'expired'
+            WHERE status = 'done' AND finished_at < now() - make_interval(secs => $1)
+            RETURNING id, ws_id, requested_by, status, size, error, created_at, finished_at
+            "#,
+        )
+        .bind(self.config.export.ttl as f64)
+        .fetch_all(&self.pool)
+        .await?;
+        for export in &expired {
+            remove_archive(&export.path(&self.config.server.base_dir)).await;
+        }
+        Ok(ExportSweepReport {
+            interrupted,
+            expired: expired.len() as u64,
+        })
+    }
+
+    // no replica is still writing an export older than the timeout, the archives left are
+    // partial
+    async fn fail_interrupted_exports(&self) -> Result<u64, AppError> {
+        let interrupted: Vec<WorkspaceExport> = sqlx::query_as(
+            r#"
+            UPDATE workspace_exports
+            SET status = 'failed', error = 'interrupted', finished_at = now()
+            WHERE status IN ('pending', 'running')
+                AND created_at < now() - make_interval(secs => $1)
+            RETURNING id, ws_id, requested_by, status, size, error, created_at, finished_at
+            "#,
+        )
+        .bind(self.config.export.timeout as f64)
+        .fetch_all(&self.pool)
+        .await?;
+        for export in &interrupted {
+            let path = export.path(&self.config.server.base_dir);
+            remove_archive(&path.with_extension("zip.part")).await;
+        }
+        Ok(interrupted.len() as u64)
+    }
+
+    async fn fetch_export_users(&self, ws_id: i64) -> Result<Vec<UserProfile>, AppError> {
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.display_name, u.status_text, u.timezone,
+                u.avatar, u.email_verified_at IS NOT NULL AS email_verified, u.created_at
+            FROM workspace_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.ws_id = $1
+            ORDER BY u.id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users)
+    }
+
+    async fn fetch_export_messages(
+        &self,
+        ws_id: i64,
+        last_id: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        let messages = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.content, m.files, m.created_at
+            FROM messages m
+            JOIN chats c ON c.id = m.chat_id
+            WHERE c.ws_id = $1 AND m.id > $2
+            ORDER BY m.id
+            LIMIT $3
+            "#,
+        )
+        .bind(ws_id)
+        .bind(last_id)
+        .bind(MESSAGE_BATCH)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+}
+
+/// sweep the workspace exports periodically, until the runtime shuts down
+pub(crate) fn spawn_export_sweeper(state: AppState) {
+    let period = Duration::from_secs(state.config.export.interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.sweep_workspace_exports().await {
+                Ok(report) => info!(
+                    "Export sweep: failed {} interrupted exports, removed {} archives",
+                    report.interrupted, report.expired
+                ),
+                Err(e) => warn!("Export sweep failed: {}", e),
+            }
+        }
+    });
+}
+
+// the archive may already be gone
+async fn remove_archive(path: &Path) {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove export {:?}: {}", path, e),
+    }
+}
+
+// runs on the blocking pool until the sender is dropped, then writes the manifest
+fn write_archive(
+    file: File,
+    mut rx: mpsc::Receiver<ArchiveOp>,
+    workspace: Workspace,
+) -> Result<(), AppError> {
+    let mut archive = ArchiveWriter::new(file);
+    let mut missing_files = vec![];
+    while let Some(op) = rx.blocking_recv() {
+        match op {
+            ArchiveOp::Entry(path) => archive.start_entry(&path)?,
+            ArchiveOp::Record(line) => archive.write_record(&line)?,
+            ArchiveOp::EndEntry { jsonl } => archive.finish_entry(jsonl),
+            ArchiveOp::Blob { url, entry, path } => match File::open(&path) {
+                Ok(mut blob) => {
+                    archive.start_entry(&entry)?;
+                    io::copy(&mut blob, &mut archive)?;
+                    archive.finish_entry(false);
+                }
+                Err(e) => {
+                    warn!("Failed to read exported file {}: {}", url, e);
+                    missing_files.push(url);
+                }
+            },
+        }
+    }
+    let manifest = ExportManifest {
+        schema_version: EXPORT_SCHEMA_VERSION,
+        workspace,
+        exported_at: Utc::now(),
+        entries: vec![],
+        missing_files,
+    };
+    archive.finish(manifest)
+}
+
+impl ArchiveWriter {
+    fn new(file: File) -> Self {
+        Self {
+            zip: ZipWriter::new(file),
+            entries: vec![],
+            current: None,
+        }
+    }
+
+    fn start_entry(&mut self, path: &str) -> Result<(), AppError> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file(path, options).map_err(zip_error)?;
+        self.current = Some((path.to_string(), Sha256::new(), 0, 0));
+        Ok(())
+    }
+
+    fn write_record(&mut self, line: &[u8]) -> Result<(), AppError> {
+        self.write_all(line)?;
+        self.write_all(b"\n")?;
+        if let Some((_, _, _, records)) = self.current.as_mut() {
+            *records += 1;
+        }
+        Ok(())
+    }
+
+    fn finish_entry(&mut self, jsonl: bool) {
+        let (path, hasher, size, records) =
+            self.current.take().expect("an entry should be started");
+        self.entries.push(ManifestEntry {
+            path,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+            records: jsonl.then_some(records),
+        });
+    }
+
+    // the manifest is the last entry, it lists all the others
+    fn finish(mut self, mut manifest: ExportManifest) -> Result<(), AppError> {
+        manifest.entries = std::mem::take(&mut self.entries);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip
+            .start_file("manifest.json", options)
+            .map_err(zip_error)?;
+        serde_json::to_writer_pretty(&mut self.zip, &manifest)?;
+        self.zip.finish().map_err(zip_error)?.sync_all()?;
+        Ok(())
+    }
+}
+
+// the entry is hashed and measured as it is written
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (_, hasher, size, _) = self.current.as_mut().expect("an entry should be started");
+        let n = self.zip.write(buf)?;
+        hasher.update(&buf[..n]);
+        *size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.zip.flush()
+    }
+}
+
+fn zip_error(e: zip::result::ZipError) -> AppError {
+    AppError::IOError(std::io::Error::other(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CreateMessage;
+    use anyhow::Result;
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    #[tokio::test]
+    async fn workspace_export_should_write_archive() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "hello.txt", b"hello");
+        let path = file.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, b"hello")?;
+        let input = CreateMessage {
+            content: "see the file".to_string(),
+            files: vec![file.url()],
+            send_at: None,
+        };
+        state.create_message(input, 1, 1).await?;
+
+        let export = state.create_workspace_export(1, None).await?;
+        let export = state.run_workspace_export(export.id).await?;
+        assert_eq!(export.status, ExportStatus::Done, "{:?}", export.error);
+        let archive = export.path(&state.config.server.base_dir);
+        assert_eq!(export.size, Some(std::fs::metadata(&archive)?.len() as i64));
+
+        let mut zip = ZipArchive::new(File::open(&archive)?)?;
+        let manifest: ExportManifest = serde_json::from_reader(zip.by_name("manifest.json")?)?;
+        assert_eq!(manifest.schema_version, EXPORT_SCHEMA_VERSION);
+        assert_eq!(manifest.workspace.id, 1);
+        assert!(manifest.missing_files.is_empty());
+        for entry in &manifest.entries {
+            let mut data = vec![];
+            zip.by_name(&entry.path)?.read_to_end(&mut data)?;
+            assert_eq!(hex::encode(Sha256::digest(&data)), entry.sha256);
+            assert_eq!(data.len() as u64, entry.size);
+        }
+        let records = |path: &str| {
+            let entry = manifest.entries.iter().find(|e| e.path == path);
+            entry.and_then(|e| e.records)
+        };
+        assert_eq!(records("users.jsonl"), Some(5));
+        assert_eq!(records("chats.jsonl"), Some(4));
+        assert_eq!(records("messages.jsonl"), Some(11));
+        assert!(zip
+            .by_name(&format!("files/{}", file.hash_to_path()))
+            .is_ok());
+
+        let mut users = String::new();
+        zip.by_name("users.jsonl")?.read_to_string(&mut users)?;
+        assert!(!users.contains("password"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_export_download_should_be_signed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state
+            .find_workspace_by_id(1)
+            .await?
+            .expect("ws should exist");
+        state.update_workspace_owner(ws, 1).await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let bob = state.find_user_by_id(2).await?.expect("user should exist");
+        let err = state.start_workspace_export(&bob).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDenied(_)));
+
+        let export = state.create_workspace_export(1, Some(alice.id)).await?;
+        let pending = state.get_workspace_export(&alice, export.id).await?;
+        assert!(pending.url.is_none());
+        state.run_workspace_export(export.id).await?;
+        let done = state.get_workspace_export(&alice, export.id).await?;
+        let url = done.url.expect("done export should have a url");
+        let token = url.split("token=").nth(1).unwrap();
+        let verified = state.verify_export_download(export.id, token).await?;
+        assert_eq!(verified.id, export.id);
+
+        // the token is for this export only
+        let other = state.create_workspace_export(1, Some(alice.id)).await?;
+        let err = state
+            .verify_export_download(other.id, token)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::InvalidToken));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_workspace_exports_should_fail_and_expire() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let done = state.create_workspace_export(1, None).await?;
+        let done = state.run_workspace_export(done.id).await?;
+        let archive = done.path(&state.config.server.base_dir);
+        assert!(archive.exists());
+        // left running by a restart
+        let stale = state.create_workspace_export(1, None).await?;
+        let age = "UPDATE workspace_exports SET created_at = $2, finished_at = $2 WHERE id = $1";
+        for (id, days) in [(done.id, 8), (stale.id, 1)] {
+            sqlx::query(age)
+                .bind(id)
+                .bind(Utc::now() - chrono::Duration::days(days))
+                .execute(&state.pool)
+                .await?;
+        }
+        sqlx::query(
+            "UPDATE workspace_exports SET status = 'running', finished_at = NULL WHERE id = $1",
+        )
+        .bind(stale.id)
+        .execute(&state.pool)
+        .await?;
+        let fresh = state.create_workspace_export(1, None).await?;
+
+        let report = state.sweep_workspace_exports().await?;
+        let expected = ExportSweepReport {
+            interrupted: 1,
+            expired: 1,
+        };
+        assert_eq!(report, expected);
+        assert!(!archive.exists());
+        for (id, status) in [
+            (done.id, ExportStatus::Expired),
+            (stale.id, ExportStatus::Failed),
+            (fresh.id, ExportStatus::Pending),
+        ] {
+            let export = state.find_workspace_export(id).await?;
+            assert_eq!(export.map(|e| e.status), Some(status));
+        }
+        Ok(())
+    }
+}
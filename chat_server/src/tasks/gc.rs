@@ -0,0 +1,227 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{remove_empty_parents, scan_blobs};
+use crate::{error::AppError, AppState};
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    pub grace_period: Duration,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub scanned: u64,
+    pub removed: u64,
+    pub reclaimed_bytes: u64,
+    // urls of the removed (or to be removed in dry-run mode) files
+    pub files: Vec<String>,
+}
+
+impl AppState {
+    /// walk the content-addressed tree under base_dir and delete the blobs no message refers to
+    pub async fn collect_orphan_files(&self, opts: &GcOptions) -> Result<GcReport, AppError> {
+        let base_dir = self.config.server.base_dir.clone();
+        // walk the tree before loading references, so that a file referenced by a message
+        // created in between is never considered as orphaned
+        let blobs = tokio::task::spawn_blocking(move || scan_blobs(&base_dir))
+            .await
+            .map_err(|e| AppError::IOError(std::io::Error::other(e)))??;
+        let referenced = self.fetch_referenced_files().await?;
+
+        let now = SystemTime::now();
+        let mut report = GcReport {
+            dry_run: opts.dry_run,
+            scanned: blobs.len() as u64,
+            ..Default::default()
+        };
+        for blob in blobs {
+            if referenced.contains(&blob.url) {
+                continue;
+            }
+            let age = now.duration_since(blob.modified).unwrap_or_default();
+            if age < opts.grace_period {
+                continue;
+            }
+            // stored again since the scan, by an upload the references didn't include yet
+            if is_touched(&blob.path, blob.modified).await {
+                continue;
+            }
+            if !opts.dry_run {
+                if let Err(e) = tokio::fs::remove_file(&blob.path).await {
+                    warn!("Failed to remove orphaned file {:?}: {}", blob.path, e);
+                    continue;
+                }
+                remove_empty_parents(&blob.path, &self.config.server.base_dir);
+            }
+            report.removed += 1;
+            report.reclaimed_bytes += blob.size;
+            report.files.push(blob.url);
+        }
+        Ok(report)
+    }
+
+    async fn fetch_referenced_files(&self) -> Result<HashSet<String>, AppError> {
+        let files: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT unnest(files)
+            FROM messages
+            UNION
+            SELECT unnest(files)
+            FROM scheduled_messages
+            WHERE status = 'pending'
+            UNION
+            SELECT avatar
+            FROM users
+            WHERE avatar IS NOT NULL
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(files.into_iter().map(|(f,)| f).collect())
+    }
+}
+
+async fn is_touched(path: &Path, modified: SystemTime) -> bool {
+    match tokio::fs::metadata(path)
+        .await
+        .and_then(|meta| meta.modified())
+    {
+        Ok(now) => now != modified,
+        Err(_) => true,
+    }
+}
+
+/// run the garbage collection periodically, until the runtime shuts down
+pub(crate) fn spawn_gc_task(state: AppState) {
+    let opts = GcOptions {
+        grace_period: Duration::from_secs(state.config.gc.grace_period),
+        dry_run: false,
+    };
+    let period = Duration::from_secs(state.config.gc.interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.collect_orphan_files(&opts).await {
+                Ok(report) => info!(
+                    "File gc: scanned {} files, removed {}, reclaimed {} bytes",
+                    report.scanned, report.removed, report.reclaimed_bytes
+                ),
+                Err(e) => warn!("File gc failed: {}", e),
+            }
+            match state.prune_rate_limits().await {
+                Ok(n) => info!("Pruned {} rate limit buckets", n),
+                Err(e) => warn!("Failed to prune rate limits: {}", e),
+            }
+            match state.prune_signin_failures().await {
+                Ok(n) => info!("Pruned {} signin failures", n),
+                Err(e) => warn!("Failed to prune signin failures: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChatFile, CreateMessage};
+    use anyhow::Result;
+    use std::fs;
+
+    #[tokio::test]
+    async fn collect_orphan_files_should_work() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(format!("chat-gc-{}", uuid::Uuid::now_v7()));
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.server.base_dir = base_dir.clone();
+        })
+        .await?;
+
+        let used = write_file(&state, "used.txt", b"used")?;
+        let orphan = write_file(&state, "orphan.txt", b"orphan")?;
+        let input = CreateMessage {
+            content: "Hello".to_string(),
+            files: vec![used.url()],
+            send_at: None,
+        };
+        state.create_message(input, 1, 1).await?;
+        // avatars are referenced by the users
+        let avatar = write_file(&state, "avatar.png", b"avatar")?;
+        sqlx::query("UPDATE users SET avatar = $1 WHERE id = 1")
+            .bind(avatar.url())
+            .execute(&state.pool)
+            .await?;
+
+        // files inside the grace period are kept
+        let opts = GcOptions {
+            grace_period: Duration::from_secs(3600),
+            dry_run: false,
+        };
+        let report = state.collect_orphan_files(&opts).await?;
+        assert_eq!(report.scanned, 3);
+        assert_eq!(report.removed, 0);
+
+        // dry run only reports
+        let opts = GcOptions {
+            grace_period: Duration::ZERO,
+            dry_run: true,
+        };
+        let report = state.collect_orphan_files(&opts).await?;
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.reclaimed_bytes, 6);
+        assert_eq!(report.files, vec![orphan.url()]);
+        assert!(orphan.path(&base_dir).exists());
+
+        let opts = GcOptions {
+            grace_period: Duration::ZERO,
+            dry_run: false,
+        };
+        let report = state.collect_orphan_files(&opts).await?;
+        assert_eq!(report.removed, 1);
+        assert!(!orphan.path(&base_dir).exists());
+        assert!(used.path(&base_dir).exists());
+        assert!(avatar.path(&base_dir).exists());
+
+        fs::remove_dir_all(&base_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stored_again_files_should_restart_grace_period() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = write_file(&state, "again.txt", b"again")?;
+        let base_dir = &state.config.server.base_dir;
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        fs::File::options()
+            .append(true)
+            .open(file.path(base_dir))?
+            .set_modified(old)?;
+
+        // uploaded again, the message referencing it is not created yet
+        assert!(!file.store(base_dir, b"again").await?);
+        let opts = GcOptions {
+            grace_period: Duration::from_secs(3600),
+            dry_run: false,
+        };
+        let report = state.collect_orphan_files(&opts).await?;
+        assert!(!report.files.contains(&file.url()));
+        assert!(file.path(base_dir).exists());
+        Ok(())
+    }
+
+    fn write_file(state: &AppState, name: &str, data: &[u8]) -> Result<ChatFile> {
+        let file = ChatFile::new(1, name, data);
+        let path = file.path(&state.config.server.base_dir);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, data)?;
+        Ok(file)
+    }
+}
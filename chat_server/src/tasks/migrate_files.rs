@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{info, warn};
+
+use super::{remove_empty_parents, scan_blobs};
+use crate::{error::AppError, models::HashVersion, AppState, ChatFile};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MigrateFilesReport {
+    pub dry_run: bool,
+    pub migrated: u64,
+    /// the messages and the scheduled messages pointing to a migrated file
+    pub rewritten_messages: u64,
+    pub rewritten_avatars: u64,
+    // legacy files whose content doesn't match their address, left untouched
+    pub corrupted: Vec<String>,
+}
+
+impl AppState {
+    /// rehash the legacy sha1 addressed blobs with sha256, and rewrite the references of the
+    /// messages, the scheduled messages and the avatars
+    pub async fn migrate_legacy_files(
+        &self,
+        dry_run: bool,
+    ) -> Result<MigrateFilesReport, AppError> {
+        let base_dir = self.config.server.base_dir.clone();
+        let blobs = tokio::task::spawn_blocking(move || scan_blobs(&base_dir))
+            .await
+            .map_err(|e| AppError::IOError(std::io::Error::other(e)))??;
+
+        let mut report = MigrateFilesReport {
+            dry_run,
+            ..Default::default()
+        };
+        for blob in blobs {
+            if blob.file.version != HashVersion::V1 {
+                continue;
+            }
+            let data = fs::read(&blob.path).await?;
+            if !blob.file.verify(&data) {
+                warn!("File {} doesn't match its hash, skipped", blob.url);
+                report.corrupted.push(blob.url);
+                continue;
+            }
+            let file = ChatFile::with_ext(blob.file.ws_id, &blob.file.ext, &data);
+            let url = file.url();
+            if dry_run {
+                report.migrated += 1;
+                let (messages, avatars) = self.count_file_references(&blob.url).await?;
+                report.rewritten_messages += messages;
+                report.rewritten_avatars += avatars;
+                continue;
+            }
+
+            // write the new blob first, so references never point to a missing file
+            file.store(&self.config.server.base_dir, &data).await?;
+            let (messages, avatars) = self.replace_file_references(&blob.url, &url).await?;
+            fs::remove_file(&blob.path).await?;
+            remove_empty_parents(&blob.path, &self.config.server.base_dir);
+            info!("Migrated file {} to {}", blob.url, url);
+
+            report.migrated += 1;
+            report.rewritten_messages += messages;
+            report.rewritten_avatars += avatars;
+        }
+        Ok(report)
+    }
+
+    /// the messages and the avatars referencing the url
+    async fn count_file_references(&self, url: &str) -> Result<(u64, u64), AppError> {
+        let (messages, avatars): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT (SELECT count(*) FROM messages WHERE $1 = ANY(files))
+                + (SELECT count(*) FROM scheduled_messages WHERE $1 = ANY(files)),
+                (SELECT count(*) FROM users WHERE avatar = $1)
+            "#,
+        )
+        .bind(url)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok((messages as _, avatars as _))
+    }
+
+    async fn replace_file_references(&self, from: &str, to: &str) -> Result<(u64, u64), AppError> {
+        let mut tx = self.pool.begin().await?;
+        let messages = sqlx::query(
+            r#"
+            UPDATE messages
+            SET files = array_replace(files, $1, $2)
+            WHERE $1 = ANY(files)
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+        let scheduled = sqlx::query(
+            r#"
+            UPDATE scheduled_messages
+            SET files = array_replace(files, $1, $2)
+            WHERE $1 = ANY(files)
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+        let avatars = sqlx::query("UPDATE users SET avatar = $2 WHERE avatar = $1")
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok((
+            messages.rows_affected() + scheduled.rows_affected(),
+            avatars.rows_affected(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateMessage, ListMessage};
+    use anyhow::Result;
+    use chrono::{Duration, Utc};
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn migrate_legacy_files_should_work() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(format!("chat-migrate-{}", uuid::Uuid::now_v7()));
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.server.base_dir = base_dir.clone();
+        })
+        .await?;
+
+        // sha1 of "hello world"
+        let legacy = ChatFile::from_str("/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt")?;
+        let legacy_path = legacy.path(&base_dir);
+        std::fs::create_dir_all(legacy_path.parent().unwrap())?;
+        std::fs::write(&legacy_path, b"hello world")?;
+        let input = CreateMessage {
+            content: "Hello".to_string(),
+            files: vec![legacy.url()],
+            send_at: None,
+        };
+        state.create_message(input.clone(), 1, 1).await?;
+        let input = CreateMessage {
+            send_at: Some(Utc::now() + Duration::hours(1)),
+            ..input
+        };
+        state.schedule_message(input, 1, 1).await?;
+        sqlx::query("UPDATE users SET avatar = $1 WHERE id = 2")
+            .bind(legacy.url())
+            .execute(&state.pool)
+            .await?;
+
+        let report = state.migrate_legacy_files(true).await?;
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.rewritten_messages, 2);
+        assert_eq!(report.rewritten_avatars, 1);
+        assert!(legacy_path.exists());
+
+        let report = state.migrate_legacy_files(false).await?;
+        assert_eq!(report.migrated, 1);
+        assert_eq!(report.rewritten_messages, 2);
+        assert_eq!(report.rewritten_avatars, 1);
+        assert!(!legacy_path.exists());
+
+        let file = ChatFile::new(1, "test.txt", b"hello world");
+        assert!(file.path(&base_dir).exists());
+        let input = ListMessage {
+            last_id: None,
+            limit: 1,
+        };
+        let messages = state.list_messages(1, &input).await?;
+        assert_eq!(messages[0].files, vec![file.url()]);
+        let scheduled = state.list_scheduled_messages(1).await?;
+        assert_eq!(scheduled[0].files, vec![file.url()]);
+        let (avatar,): (Option<String>,) = sqlx::query_as("SELECT avatar FROM users WHERE id = 2")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(avatar, Some(file.url()));
+
+        // migration is idempotent
+        let report = state.migrate_legacy_files(false).await?;
+        assert_eq!(report.migrated, 0);
+
+        std::fs::remove_dir_all(&base_dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+mod export;
+mod gc;
+mod migrate_files;
+mod retention;
+mod scheduled;
+mod slack_import;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::{error::AppError, ChatFile};
+
+pub use export::{
+    ExportManifest, ExportStatus, ExportSweepReport, ManifestEntry, WorkspaceExport,
+    EXPORT_SCHEMA_VERSION,
+};
+pub use gc::{GcOptions, GcReport};
+pub use migrate_files::MigrateFilesReport;
+pub use retention::RetentionReport;
+pub use slack_import::{ImportStatus, SlackImportJob, SlackImportOptions, SlackImportReport};
+
+pub(crate) use export::spawn_export_sweeper;
+pub(crate) use gc::spawn_gc_task;
+pub(crate) use retention::spawn_retention_task;
+pub(crate) use scheduled::spawn_scheduled_task;
+
+#[derive(Debug)]
+struct Blob {
+    file: ChatFile,
+    url: String,
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+// collect every file under base_dir which matches the ChatFile layout
+fn scan_blobs(base_dir: &Path) -> Result<Vec<Blob>, AppError> {
+    let mut blobs = vec![];
+    if !base_dir.exists() {
+        return Ok(blobs);
+    }
+    let mut dirs = vec![base_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let path = entry.path();
+            if meta.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let Some(rel) = path.strip_prefix(base_dir).ok().and_then(|p| p.to_str()) else {
+                continue;
+            };
+            let Ok(file) = ChatFile::from_str(&format!("/files/{rel}")) else {
+                continue;
+            };
+            blobs.push(Blob {
+                url: file.url(),
+                file,
+                path,
+                size: meta.len(),
+                modified: meta.modified()?,
+            });
+        }
+    }
+    Ok(blobs)
+}
+
+// best effort cleanup of the hash directories left empty, stops at the first non-empty one
+fn remove_empty_parents(path: &Path, base_dir: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == base_dir || !d.starts_with(base_dir) || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
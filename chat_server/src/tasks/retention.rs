@@ -0,0 +1,235 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::FromRow;
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    models::{AuditAction, NewAuditEvent},
+    utils::RequestMeta,
+    AppState,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RetentionReport {
+    /// chats with purged messages
+    pub chats: u64,
+    pub purged_messages: u64,
+    /// file references dropped with the messages, the gc collects the orphaned files
+    pub released_files: u64,
+}
+
+#[derive(Debug, FromRow)]
+struct ExpiringChat {
+    id: i64,
+    ws_id: i64,
+    retention_days: i32,
+}
+
+impl AppState {
+    /// delete the messages older than the retention of their chat, or of its workspace.
+    /// Chats under legal hold or kept forever are skipped, every purge is recorded in the audit
+    /// log
+    pub async fn purge_expired_messages(&self) -> Result<RetentionReport, AppError> {
+        let chats: Vec<ExpiringChat> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, COALESCE(c.retention_days, w.retention_days) AS retention_days
+            FROM chats c
+            JOIN workspaces w ON w.id = c.ws_id
+            WHERE NOT c.legal_hold AND COALESCE(c.retention_days, w.retention_days) > 0
+            ORDER BY c.id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut report = RetentionReport::default();
+        for chat in chats {
+            let before = Utc::now() - chrono::Duration::days(chat.retention_days as i64);
+            let (messages, files) = self.purge_chat_messages(chat.id, before).await?;
+            if messages == 0 {
+                continue;
+            }
+            report.chats += 1;
+            report.purged_messages += messages;
+            report.released_files += files;
+            let event = NewAuditEvent::anonymous(AuditAction::RetentionPurge, Some(chat.ws_id))
+                .target("chat", chat.id)
+                .details(json!({
+                    "messages": messages,
+                    "files": files,
+                    "retention_days": chat.retention_days,
+                    "before": before,
+                }));
+            self.audit(&RequestMeta::default(), event).await;
+        }
+        Ok(report)
+    }
+
+    // batches walk the (chat_id, created_at) index, each checks the legal hold again so that
+    // a hold set during a run stops it
+    async fn purge_chat_messages(
+        &self,
+        chat_id: i64,
+        before: DateTime<Utc>,
+    ) -> Result<(u64, u64), AppError> {
+        let batch_size = self.config.retention.batch_size as i64;
+        let (mut messages, mut files) = (0, 0);
+        loop {
+            let purged: Vec<i32> = sqlx::query_scalar(
+                r#"
+                DELETE FROM messages
+                WHERE id IN (
+                    SELECT id
+                    FROM messages
+                    WHERE chat_id = $1 AND created_at < $2
+                    ORDER BY created_at
+                    LIMIT $3
+                ) AND NOT EXISTS (SELECT 1 FROM chats WHERE id = $1 AND legal_hold)
+                RETURNING cardinality(files)
+                "#,
+            )
+            .bind(chat_id)
+            .bind(before)
+            .bind(batch_size)
+            .fetch_all(&self.pool)
+            .await?;
+            messages += purged.len() as u64;
+            files += purged.iter().map(|n| *n as u64).sum::<u64>();
+            if (purged.len() as i64) < batch_size {
+                return Ok((messages, files));
+            }
+        }
+    }
+}
+
+/// purge the expired messages periodically, until the runtime shuts down
+pub(crate) fn spawn_retention_task(state: AppState) {
+    let period = Duration::from_secs(state.config.retention.interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.purge_expired_messages().await {
+                Ok(report) => info!(
+                    "Retention: purged {} messages from {} chats, released {} files",
+                    report.purged_messages, report.chats, report.released_files
+                ),
+                Err(e) => warn!("Retention purge failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ListAudit, UpdateChatRetention, UpdateWorkspace, KEEP_FOREVER};
+    use anyhow::Result;
+
+    async fn insert_old_message(
+        state: &AppState,
+        chat_id: i64,
+        files: &[&str],
+        days: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, files, created_at)
+            VALUES ($1, 1, 'old', $2, $3)
+            "#,
+        )
+        .bind(chat_id)
+        .bind(files)
+        .bind(Utc::now() - chrono::Duration::days(days))
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn count_messages(state: &AppState, chat_id: i64) -> Result<i64> {
+        let count = sqlx::query_scalar("SELECT count(*) FROM messages WHERE chat_id = $1")
+            .bind(chat_id)
+            .fetch_one(&state.pool)
+            .await?;
+        Ok(count)
+    }
+
+    #[tokio::test]
+    async fn purge_expired_messages_should_skip_legal_hold() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with(|config| {
+            config.retention.batch_size = 2;
+        })
+        .await?;
+        let ws = state
+            .find_workspace_by_id(1)
+            .await?
+            .expect("ws should exist");
+        state.update_workspace_owner(ws, 1).await?;
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = UpdateWorkspace {
+            retention_days: Some(30),
+            ..Default::default()
+        };
+        state.update_workspace(&alice, input).await?;
+
+        for chat_id in [1, 2] {
+            insert_old_message(&state, chat_id, &["/files/1/a.png", "/files/1/b.png"], 40).await?;
+            insert_old_message(&state, chat_id, &[], 35).await?;
+            insert_old_message(&state, chat_id, &[], 31).await?;
+        }
+        // kept by the overrides of the chats
+        insert_old_message(&state, 3, &[], 40).await?;
+        let input = UpdateChatRetention {
+            retention_days: Some(60),
+            ..Default::default()
+        };
+        state.update_chat_retention(&alice, 3, &input).await?;
+        insert_old_message(&state, 4, &[], 400).await?;
+        let input = UpdateChatRetention {
+            retention_days: Some(KEEP_FOREVER),
+            ..Default::default()
+        };
+        state.update_chat_retention(&alice, 4, &input).await?;
+        let input = UpdateChatRetention {
+            legal_hold: Some(true),
+            ..Default::default()
+        };
+        state.update_chat_retention(&alice, 2, &input).await?;
+        let counts = [
+            count_messages(&state, 1).await?,
+            count_messages(&state, 2).await?,
+            count_messages(&state, 3).await?,
+            count_messages(&state, 4).await?,
+        ];
+
+        let report = state.purge_expired_messages().await?;
+        let expected = RetentionReport {
+            chats: 1,
+            purged_messages: 3,
+            released_files: 2,
+        };
+        assert_eq!(report, expected);
+        assert_eq!(count_messages(&state, 1).await?, counts[0] - 3);
+        assert_eq!(count_messages(&state, 2).await?, counts[1]);
+        assert_eq!(count_messages(&state, 3).await?, counts[2]);
+        assert_eq!(count_messages(&state, 4).await?, counts[3]);
+
+        let input = ListAudit {
+            action: Some(AuditAction::RetentionPurge),
+            ..Default::default()
+        };
+        let events = state.list_audit_events(&alice, &input).await?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].target_id.as_deref(), Some("1"));
+        assert_eq!(events[0].details["messages"], 3);
+
+        // nothing left to purge
+        let report = state.purge_expired_messages().await?;
+        assert_eq!(report, RetentionReport::default());
+        Ok(())
+    }
+}
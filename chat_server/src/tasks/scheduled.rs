@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use sqlx::Connection;
+use tracing::{info, warn};
+
+use crate::{
+    error::AppError,
+    models::{ScheduledMessage, ScheduledStatus},
+    AppState, CreateMessage,
+};
+
+impl AppState {
+    /// send the scheduled messages which are due, returns how many were handled. Every replica
+    /// may run it, a message is claimed with its row lock and delivered exactly once
+    pub async fn deliver_due_messages(&self) -> Result<u64, AppError> {
+        let mut handled = 0;
+        while self.deliver_next_message().await?.is_some() {
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    // the message is created in the transaction holding the lock, so that a crash between the
+    // two leaves the row pending instead of sending it twice
+    async fn deliver_next_message(&self) -> Result<Option<ScheduledMessage>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, files, send_at, status, message_id, error,
+                created_at
+            FROM scheduled_messages
+            WHERE status = 'pending' AND send_at <= now()
+            ORDER BY send_at, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+            "#,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(scheduled) = scheduled else {
+            return Ok(None);
+        };
+
+        let input = CreateMessage {
+            content: scheduled.content.clone(),
+            files: scheduled.files.clone(),
+            send_at: None,
+        };
+        let sender_id = scheduled.sender_id;
+        // the sender may have left the chat or been deactivated since
+        let chat = match self.validate_message(&input, scheduled.chat_id as _).await {
+            Ok(chat) if !chat.members.contains(&sender_id) => Err(AppError::PermissionDenied(
+                format!("user {} is not a member of chat {}", sender_id, chat.id),
+            )),
+            Ok(chat) if !self.is_active_member(chat.ws_id, sender_id).await? => {
+                Err(AppError::PermissionDenied(format!(
+                    "user {} is deactivated in workspace {}",
+                    sender_id, chat.ws_id
+                )))
+            }
+            ret => ret,
+        };
+        // the insert runs in a savepoint, a failure marks the message failed instead of leaving
+        // it pending to block the next deliveries
+        let message = match chat {
+            Ok(chat) => {
+                let mut savepoint = tx.begin().await?;
+                match self
+                    .insert_message(&mut savepoint, chat, input, sender_id as _)
+                    .await
+                {
+                    Ok(message) => {
+                        savepoint.commit().await?;
+                        Ok(message)
+                    }
+                    Err(e) => {
+                        savepoint.rollback().await?;
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let (status, message_id, error) = match message {
+            Ok(message) => (ScheduledStatus::Sent, Some(message.id), None),
+            Err(e) => {
+                warn!("Scheduled message {} not delivered: {}", scheduled.id, e);
+                (ScheduledStatus::Failed, None, Some(e.to_string()))
+            }
+        };
+        let scheduled = sqlx::query_as(
+            r#"
+            UPDATE scheduled_messages
+            SET status = $2, message_id = $3, error = $4
+            WHERE id = $1
+            RETURNING id, chat_id, sender_id, content, files, send_at, status, message_id, error,
+                created_at
+            "#,
+        )
+        .bind(scheduled.id)
+        .bind(status)
+        .bind(message_id)
+        .bind(error)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(scheduled))
+    }
+}
+
+/// deliver the due scheduled messages periodically, until the runtime shuts down
+pub(crate) fn spawn_scheduled_task(state: AppState) {
+    let period = Duration::from_secs(state.config.scheduled.interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match state.deliver_due_messages().await {
+                Ok(0) => {}
+                Ok(handled) => info!("Delivered {} scheduled messages", handled),
+                Err(e) => warn!("Scheduled message delivery failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListMessage;
+    use anyhow::Result;
+    use chrono::Utc;
+
+    async fn make_due(state: &AppState) -> Result<()> {
+        sqlx::query("UPDATE scheduled_messages SET send_at = now() - interval '1 second'")
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliver_due_messages_should_send_once() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateMessage {
+            content: "good morning".to_string(),
+            files: vec![],
+            send_at: Some(Utc::now() + chrono::Duration::hours(1)),
+        };
+        let scheduled = state.schedule_message(input.clone(), 1, 1).await?;
+        // bob leaves chat 4 before the delivery of his message
+        let failed = state.schedule_message(input, 4, 2).await?;
+        assert_eq!(state.deliver_due_messages().await?, 0);
+
+        make_due(&state).await?;
+        sqlx::query("UPDATE chats SET members = array_remove(members, 2) WHERE id = 4")
+            .execute(&state.pool)
+            .await?;
+        // concurrent workers share the due messages
+        let (a, b) = tokio::join!(state.deliver_due_messages(), state.deliver_due_messages());
+        assert_eq!(a? + b?, 2);
+        assert_eq!(state.deliver_due_messages().await?, 0);
+
+        let rows: Vec<ScheduledMessage> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, files, send_at, status, message_id, error,
+                created_at
+            FROM scheduled_messages
+            ORDER BY id
+            "#,
+        )
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(rows[0].id, scheduled.id);
+        assert_eq!(rows[0].status, ScheduledStatus::Sent);
+        assert_eq!(rows[1].id, failed.id);
+        assert_eq!(rows[1].status, ScheduledStatus::Failed);
+        assert!(rows[1].message_id.is_none());
+
+        let input = ListMessage {
+            last_id: None,
+            limit: 1,
+        };
+        let messages = state.list_messages(1, &input).await?;
+        assert_eq!(Some(messages[0].id), rows[0].message_id);
+        assert_eq!(messages[0].content, "good morning");
+        let err = state
+            .cancel_scheduled_message(scheduled.id as _, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliver_due_messages_should_fail_undeliverable_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = |content: &str| CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+            send_at: Some(Utc::now() + chrono::Duration::hours(1)),
+        };
+        let poison = state.schedule_message(input("poison"), 1, 1).await?;
+        let deactivated = state.schedule_message(input("bye"), 2, 3).await?;
+        let fine = state.schedule_message(input("hello"), 1, 1).await?;
+        make_due(&state).await?;
+        // the insert of the message fails in the database
+        sqlx::query("ALTER TABLE messages ADD CONSTRAINT poison CHECK (content <> 'poison')")
+            .execute(&state.pool)
+            .await?;
+        sqlx::query("UPDATE workspace_members SET deactivated_at = now() WHERE user_id = 3")
+            .execute(&state.pool)
+            .await?;
+
+        assert_eq!(state.deliver_due_messages().await?, 3);
+        let statuses: Vec<(i64, ScheduledStatus)> =
+            sqlx::query_as("SELECT id, status FROM scheduled_messages ORDER BY id")
+                .fetch_all(&state.pool)
+                .await?;
+        let expected = vec![
+            (poison.id, ScheduledStatus::Failed),
+            (deactivated.id, ScheduledStatus::Failed),
+            (fine.id, ScheduledStatus::Sent),
+        ];
+        assert_eq!(statuses, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,924 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Read, Seek},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use tracing::{info, warn};
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{
+    error::AppError,
+    models::{placeholder_password_hash, ChatType},
+    AppState, ChatFile, User, Workspace,
+};
+
+// the columns of the imported names
+const MAX_FULLNAME: usize = 64;
+const MAX_CHAT_NAME: usize = 64;
+
+// the events of a channel exported as messages, they are not chat messages
+const SKIPPED_SUBTYPES: [&str; 9] = [
+    "channel_join",
+    "channel_leave",
+    "channel_topic",
+    "channel_purpose",
+    "channel_name",
+    "channel_archive",
+    "channel_unarchive",
+    "group_join",
+    "group_leave",
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SlackImportOptions {
+    /// fetch the files missing from the archive from their slack url
+    #[serde(default)]
+    pub download_files: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SlackImportReport {
+    pub users_created: u64,
+    /// accounts which already existed with the same email
+    pub users_matched: u64,
+    /// created accounts mailed an invitation and matched accounts invited to the workspace
+    pub invited: u64,
+    pub chats_created: u64,
+    /// conversations a chat can't be created for, e.g. with less than 2 imported members
+    pub chats_skipped: u64,
+    pub messages_imported: u64,
+    /// imported by a previous run, or not a chat message
+    pub messages_skipped: u64,
+    pub files_stored: u64,
+    /// neither in the archive nor downloaded
+    pub files_missing: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[sqlx(type_name = "import_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// a slack export uploaded through the api, imported in the background
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct SlackImportJob {
+    pub id: i64,
+    pub ws_id: i64,
+    pub requested_by: Option<i64>,
+    pub status: ImportStatus,
+    pub report: Option<Json<SlackImportReport>>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "slack_import_kind", rename_all = "snake_case")]
+enum SlackKind {
+    User,
+    Chat,
+    Message,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlackUser {
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    real_name: Option<String>,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    is_bot: bool,
+    #[serde(default)]
+    profile: SlackProfile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SlackProfile {
+    email: Option<String>,
+    real_name: Option<String>,
+}
+
+/// a channel, a private channel, a dm or a group dm
+#[derive(Debug, Deserialize)]
+struct SlackConversation {
+    id: String,
+    name: Option<String>,
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlackMessage {
+    #[serde(default)]
+    r#type: String,
+    subtype: Option<String>,
+    user: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SlackFile {
+    id: String,
+    name: Option<String>,
+    url_private_download: Option<String>,
+}
+
+// the state of an import run
+struct SlackImport<'a, R> {
+    ws: Workspace,
+    // zip reads block on the file and inflate, they run on the blocking pool
+    zip: Arc<Mutex<ZipArchive<R>>>,
+    opts: &'a SlackImportOptions,
+    users: HashMap<String, i64>,
+    // local fullname of the slack user ids, for the mentions
+    names: HashMap<String, String>,
+    chats: HashMap<String, i64>,
+    messages: HashSet<String>,
+    report: SlackImportReport,
+}
+
+impl SlackImportJob {
+    /// where the uploaded archive is kept until the import finishes
+    pub fn path(&self, base_dir: &Path) -> PathBuf {
+        base_dir
+            .join("imports")
+            .join(self.ws_id.to_string())
+            .join(format!("{}.zip", self.id))
+    }
+}
+
+impl AppState {
+    /// a pending import of a slack export into the workspace, its archive is uploaded to the
+    /// path of the job before it is run
+    pub async fn create_slack_import(
+        &self,
+        ws_id: i64,
+        requested_by: Option<i64>,
+    ) -> Result<SlackImportJob, AppError> {
+        self.find_workspace_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace id: {ws_id}")))?;
+        let job = sqlx::query_as(
+            r#"
+            INSERT INTO slack_import_jobs (ws_id, requested_by)
+            VALUES ($1, $2)
+            RETURNING id, ws_id, requested_by, status, report, error, created_at, finished_at
+            "#,
+        )
+        .bind(ws_id)
+        .bind(requested_by)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(job)
+    }
+
+    pub async fn find_slack_import(&self, id: i64) -> Result<SlackImportJob, AppError> {
+        let job = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, requested_by, status, report, error, created_at, finished_at
+            FROM slack_import_jobs
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        job.ok_or_else(|| AppError::NotFound(format!("slack import id: {id}")))
+    }
+
+    /// import the uploaded archive of a pending job, the archive is removed once done
+    pub(crate) async fn run_slack_import(
+        &self,
+        id: i64,
+        opts: &SlackImportOptions,
+    ) -> Result<SlackImportJob, AppError> {
+        let job: SlackImportJob = sqlx::query_as(
+            r#"
+            UPDATE slack_import_jobs
+            SET status = 'running'
+            WHERE id = $1 AND status = 'pending'
+            RETURNING id, ws_id, requested_by, status, report, error, created_at, finished_at
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("pending slack import id: {id}")))?;
+
+        let path = job.path(&self.config.server.base_dir);
+        let ret = match File::open(&path) {
+            Ok(archive) => self.import_slack(job.ws_id, archive, opts).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to remove slack export {:?}: {}", path, e);
+        }
+        match &ret {
+            Ok(report) => info!(
+                "Slack export imported into workspace {}: {:?}",
+                job.ws_id, report
+            ),
+            Err(e) => warn!(
+                "Failed to import slack export into workspace {}: {}",
+                job.ws_id, e
+            ),
+        }
+        self.finish_slack_import(id, ret.as_ref()).await
+    }
+
+    /// keep the report or the error of the import on its job
+    pub(crate) async fn finish_slack_import(
+        &self,
+        id: i64,
+        ret: Result<&SlackImportReport, &AppError>,
+    ) -> Result<SlackImportJob, AppError> {
+        let (status, report, error) = match ret {
+            Ok(report) => (ImportStatus::Done, Some(Json(report.clone())), None),
+            Err(e) => (ImportStatus::Failed, None, Some(e.to_string())),
+        };
+        let job = sqlx::query_as(
+            r#"
+            UPDATE slack_import_jobs
+            SET status = $2, report = $3, error = $4, finished_at = now()
+            WHERE id = $1
+            RETURNING id, ws_id, requested_by, status, report, error, created_at, finished_at
+            "#,
+        )
+        .bind(id)
+        .bind(status)
+        .bind(report)
+        .bind(error)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(job)
+    }
+
+    /// import a slack workspace export zip into the workspace. Users are matched by email and
+    /// invited to join, or created with a placeholder password and mailed an invitation, the
+    /// imported rows are remembered so a re-run only imports what is new
+    pub async fn import_slack<R: Read + Seek + Send + 'static>(
+        &self,
+        ws_id: i64,
+        archive: R,
+        opts: &SlackImportOptions,
+    ) -> Result<SlackImportReport, AppError> {
+        let ws = self
+            .find_workspace_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("workspace id: {ws_id}")))?;
+        let zip = tokio::task::spawn_blocking(|| ZipArchive::new(archive))
+            .await
+            .map_err(join_error)?
+            .map_err(invalid_archive)?;
+        let mut import = SlackImport {
+            users: self.fetch_slack_imports(ws.id, SlackKind::User).await?,
+            chats: self.fetch_slack_imports(ws.id, SlackKind::Chat).await?,
+            messages: self
+                .fetch_slack_imports(ws.id, SlackKind::Message)
+                .await?
+                .into_keys()
+                .collect(),
+            names: HashMap::new(),
+            ws,
+            zip: Arc::new(Mutex::new(zip)),
+            opts,
+            report: SlackImportReport::default(),
+        };
+
+        let users: Vec<SlackUser> = import.read_json("users.json").await?.unwrap_or_default();
+        for user in &users {
+            if !import.users.contains_key(&user.id) {
+                let id = self.import_slack_user(&mut import, user).await?;
+                import.users.insert(user.id.clone(), id);
+            }
+        }
+        let ids: Vec<i64> = import.users.values().copied().collect();
+        let fullnames: HashMap<i64, String> = self
+            .find_chat_users_by_ids(&ids)
+            .await?
+            .into_iter()
+            .map(|user| (user.id, user.fullname))
+            .collect();
+        import.names = import
+            .users
+            .iter()
+            .filter_map(|(slack_id, id)| Some((slack_id.clone(), fullnames.get(id)?.clone())))
+            .collect();
+
+        for (name, r#type) in [
+            ("channels.json", ChatType::PublicChannel),
+            ("groups.json", ChatType::PrivateChannel),
+            ("dms.json", ChatType::Single),
+            ("mpims.json", ChatType::Group),
+        ] {
+            let conversations: Vec<SlackConversation> =
+                import.read_json(name).await?.unwrap_or_default();
+            for conversation in &conversations {
+                let chat_id = match import.chats.get(&conversation.id) {
+                    Some(id) => *id,
+                    None => match self
+                        .import_slack_chat(&mut import, conversation, &r#type)
+                        .await?
+                    {
+                        Some(id) => id,
+                        None => continue,
+                    },
+                };
+                // the messages of a dm are under its id, the others under their name
+                let dir = match (&r#type, &conversation.name) {
+                    (ChatType::Single, _) | (_, None) => &conversation.id,
+                    (_, Some(name)) => name,
+                };
+                self.import_slack_messages(&mut import, conversation, dir, chat_id)
+                    .await?;
+            }
+        }
+        Ok(import.report)
+    }
+
+    async fn fetch_slack_imports(
+        &self,
+        ws_id: i64,
+        kind: SlackKind,
+    ) -> Result<HashMap<String, i64>, AppError> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT slack_id, local_id FROM slack_imports WHERE ws_id = $1 AND kind = $2",
+        )
+        .bind(ws_id)
+        .bind(kind)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn import_slack_user<R>(
+        &self,
+        import: &mut SlackImport<'_, R>,
+        slack: &SlackUser,
+    ) -> Result<i64, AppError> {
+        let ws = &import.ws;
+        let email = slack
+            .profile
+            .email
+            .clone()
+            .filter(|email| !email.is_empty());
+        let existing = match &email {
+            Some(email) => self.find_user_by_email(email).await?,
+            None => None,
+        };
+        let mut tx = self.pool.begin().await?;
+        let (user, created) = match existing {
+            Some(user) => (user, false),
+            None => {
+                let fullname = [&slack.profile.real_name, &slack.real_name]
+                    .into_iter()
+                    .flatten()
+                    .chain([&slack.name, &slack.id])
+                    .find(|name| !name.trim().is_empty())
+                    .map(|name| truncate(name.trim(), MAX_FULLNAME))
+                    .unwrap_or_default();
+                // bots have no email, they only keep their messages. Slack ids like USLACKBOT
+                // are the same in every export, the workspace keeps the placeholders apart
+                let placeholder =
+                    format!("{}.{}@slack-import.invalid", slack.id.to_lowercase(), ws.id);
+                let user: User = sqlx::query_as(
+                    r#"
+                    INSERT INTO users (ws_id, fullname, email, password_hash)
+                    VALUES ($1, $2, $3, $4)
+                    RETURNING id, ws_id, fullname, email, created_at
+                    "#,
+                )
+                .bind(ws.id)
+                .bind(fullname)
+                .bind(email.as_deref().unwrap_or(&placeholder))
+                .bind(placeholder_password_hash()?)
+                .fetch_one(&mut *tx)
+                .await?;
+                (user, true)
+            }
+        };
+        if created {
+            // a deleted slack account is a deactivated member
+            sqlx::query(
+                r#"
+                INSERT INTO workspace_members (ws_id, user_id, deactivated_at)
+                VALUES ($1, $2, CASE WHEN $3 THEN now() END)
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(ws.id)
+            .bind(user.id)
+            .bind(slack.deleted)
+            .execute(&mut *tx)
+            .await?;
+        }
+        // an existing account only joins once it accepts, the export doesn't prove it owns the
+        // email any more than an admin typing it in would
+        let mut invited = false;
+        if !created && !slack.deleted {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO workspace_invitations (ws_id, email)
+                SELECT $1, lower($2)
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM workspace_members WHERE ws_id = $1 AND user_id = $3
+                )
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(ws.id)
+            .bind(&user.email)
+            .bind(user.id)
+            .execute(&mut *tx)
+            .await?;
+            invited = result.rows_affected() > 0;
+        }
+        record_slack_import(&mut tx, ws.id, SlackKind::User, &slack.id, user.id).await?;
+        tx.commit().await?;
+
+        if !created {
+            import.report.users_matched += 1;
+            if invited {
+                import.report.invited += 1;
+            }
+            return Ok(user.id);
+        }
+        import.report.users_created += 1;
+        if email.is_some() && !slack.deleted && !slack.is_bot {
+            match self.send_invitation(&user, &ws.name).await {
+                Ok(()) => import.report.invited += 1,
+                Err(e) => warn!("Failed to mail the invitation to {}: {}", user.email, e),
+            }
+        }
+        Ok(user.id)
+    }
+
+    async fn import_slack_chat<R>(
+        &self,
+        import: &mut SlackImport<'_, R>,
+        conversation: &SlackConversation,
+        r#type: &ChatType,
+    ) -> Result<Option<i64>, AppError> {
+        let mut members: Vec<i64> = conversation
+            .members
+            .iter()
+            .filter_map(|id| import.users.get(id).copied())
+            .collect();
+        members.sort_unstable();
+        members.dedup();
+        let name = match r#type {
+            ChatType::PublicChannel | ChatType::PrivateChannel => conversation
+                .name
+                .as_deref()
+                .map(|name| truncate(name, MAX_CHAT_NAME)),
+            _ => None,
+        };
+        // the invariants of create_chat
+        let invalid = match (r#type, &name) {
+            _ if members.len() < 2 => Some("less than 2 members"),
+            (ChatType::PublicChannel | ChatType::PrivateChannel, None) => {
+                Some("a channel without a name")
+            }
+            (ChatType::Single, _) if members.len() > 2 => Some("a dm of more than 2 members"),
+            (ChatType::Group, _) if members.len() > 8 => Some("a group of more than 8 members"),
+            _ => None,
+        };
+        if let Some(reason) = invalid {
+            warn!("Slack conversation {} skipped: {}", conversation.id, reason);
+            import.report.chats_skipped += 1;
+            return Ok(None);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO chats (ws_id, name, type, members)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+        )
+        .bind(import.ws.id)
+        .bind(name)
+        .bind(r#type)
+        .bind(members)
+        .fetch_one(&mut *tx)
+        .await?;
+        record_slack_import(&mut tx, import.ws.id, SlackKind::Chat, &conversation.id, id).await?;
+        tx.commit().await?;
+        import.chats.insert(conversation.id.clone(), id);
+        import.report.chats_created += 1;
+        Ok(Some(id))
+    }
+
+    // one transaction per day file of the conversation
+    async fn import_slack_messages<R: Read + Seek + Send + 'static>(
+        &self,
+        import: &mut SlackImport<'_, R>,
+        conversation: &SlackConversation,
+        dir: &str,
+        chat_id: i64,
+    ) -> Result<(), AppError> {
+        let prefix = format!("{dir}/");
+        let mut days: Vec<String> = import
+            .zip
+            .lock()
+            .expect("zip lock should not be poisoned")
+            .file_names()
+            .filter(|name| name.starts_with(&prefix) && name.ends_with(".json"))
+            .map(|name| name.to_string())
+            .collect();
+        days.sort();
+
+        for day in days {
+            let mut messages: Vec<SlackMessage> = import.read_json(&day).await?.unwrap_or_default();
+            messages.sort_by(|a, b| a.ts.cmp(&b.ts));
+            let mut tx = self.pool.begin().await?;
+            for message in &messages {
+                let key = format!("{}:{}", conversation.id, message.ts);
+                let sender = message.user.as_ref().and_then(|id| import.users.get(id));
+                let created_at = parse_ts(&message.ts);
+                let skipped = message
+                    .subtype
+                    .as_deref()
+                    .is_some_and(|subtype| SKIPPED_SUBTYPES.contains(&subtype));
+                let (Some(&sender), Some(created_at), false, false) = (
+                    sender,
+                    created_at,
+                    skipped || message.r#type != "message",
+                    import.messages.contains(&key),
+                ) else {
+                    import.report.messages_skipped += 1;
+                    continue;
+                };
+
+                let mut files = vec![];
+                for file in &message.files {
+                    match self.store_slack_file(import, file).await? {
+                        Some(url) => files.push(url),
+                        None => import.report.files_missing += 1,
+                    }
+                }
+                let content = convert_text(&message.text, &import.names);
+                if content.trim().is_empty() && files.is_empty() {
+                    import.report.messages_skipped += 1;
+                    continue;
+                }
+
+                let (id,): (i64,) = sqlx::query_as(
+                    r#"
+                    INSERT INTO messages (chat_id, sender_id, content, files, created_at)
+                    VALUES ($1, $2, $3, $4, $5)
+                    RETURNING id
+                    "#,
+                )
+                .bind(chat_id)
+                .bind(sender)
+                .bind(content)
+                .bind(&files)
+                .bind(created_at)
+                .fetch_one(&mut *tx)
+                .await?;
+                record_slack_import(&mut tx, import.ws.id, SlackKind::Message, &key, id).await?;
+                import.messages.insert(key);
+                import.report.messages_imported += 1;
+                import.report.files_stored += files.len() as u64;
+            }
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+
+    // the file from the `__uploads` directory of the archive, or downloaded if allowed
+    async fn store_slack_file<R: Read + Seek + Send + 'static>(
+        &self,
+        import: &mut SlackImport<'_, R>,
+        file: &SlackFile,
+    ) -> Result<Option<String>, AppError> {
+        let name = file.name.clone().unwrap_or_else(|| file.id.clone());
+        let mut data = import
+            .read_entry(&format!("__uploads/{}/{}", file.id, name))
+            .await?;
+        if data.is_none() && import.opts.download_files {
+            if let Some(url) = &file.url_private_download {
+                data = match download(url).await {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        warn!("Failed to download slack file {}: {}", file.id, e);
+                        None
+                    }
+                };
+            }
+        }
+        let Some(data) = data else {
+            return Ok(None);
+        };
+
+        let chat_file = ChatFile::new(import.ws.id as _, &name, &data);
+        chat_file.store(&self.config.server.base_dir, &data).await?;
+        Ok(Some(chat_file.url()))
+    }
+}
+
+impl<R: Read + Seek + Send + 'static> SlackImport<'_, R> {
+    async fn read_entry(&self, name: &str) -> Result<Option<Vec<u8>>, AppError> {
+        let zip = self.zip.clone();
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut zip = zip.lock().expect("zip lock should not be poisoned");
+            read_entry(&mut zip, &name)
+        })
+        .await
+        .map_err(join_error)?
+    }
+
+    async fn read_json<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, AppError> {
+        let Some(data) = self.read_entry(name).await? else {
+            return Ok(None);
+        };
+        let value = serde_json::from_slice(&data)
+            .map_err(|e| AppError::InvalidInput(format!("invalid slack export {name}: {e}")))?;
+        Ok(Some(value))
+    }
+}
+
+async fn record_slack_import(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    ws_id: i64,
+    kind: SlackKind,
+    slack_id: &str,
+    local_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO slack_imports (ws_id, kind, slack_id, local_id)
+        VALUES ($1, $2, $3, $4)
+        "#,
+    )
+    .bind(ws_id)
+    .bind(kind)
+    .bind(slack_id)
+    .bind(local_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+async fn download(url: &str) -> Result<Vec<u8>, reqwest::Error> {
+    let res = reqwest::get(url).await?.error_for_status()?;
+    Ok(res.bytes().await?.to_vec())
+}
+
+fn read_entry<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Option<Vec<u8>>, AppError> {
+    let mut file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(invalid_archive(e)),
+    };
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    Ok(Some(data))
+}
+
+fn invalid_archive(e: ZipError) -> AppError {
+    AppError::InvalidInput(format!("invalid slack export: {e}"))
+}
+
+fn join_error(e: tokio::task::JoinError) -> AppError {
+    AppError::IOError(std::io::Error::other(e))
+}
+
+// slack timestamps are `<seconds>.<microseconds>`
+fn parse_ts(ts: &str) -> Option<DateTime<Utc>> {
+    let (secs, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    let micros = format!("{:0<6}", micros);
+    DateTime::from_timestamp(
+        secs.parse().ok()?,
+        micros.get(..6)?.parse::<u32>().ok()? * 1000,
+    )
+}
+
+// `<@U123>` mentions become `@fullname`, and the escaped `&`, `<` and `>` are restored
+fn convert_text(text: &str, names: &HashMap<String, String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("<@") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('>') else {
+            out.push_str(&rest[start..]);
+            rest = "";
+            break;
+        };
+        let mention = &rest[start..start + len + 1];
+        let id = mention[2..mention.len() - 1]
+            .split('|')
+            .next()
+            .unwrap_or_default();
+        match names.get(id) {
+            Some(name) => {
+                out.push('@');
+                out.push_str(name);
+            }
+            None => out.push_str(mention),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn truncate(s: &str, max: usize) -> String {
+    s.chars().take(max).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use serde_json::json;
+    use std::io::{Cursor, Write};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    fn slack_export() -> Result<Vec<u8>> {
+        let entries = [
+            (
+                "users.json",
+                json!([
+                    { "id": "U1", "name": "alice", "profile": { "email": "Alice@test.org" } },
+                    { "id": "U2", "name": "zoe", "real_name": "Zoe Doe",
+                      "profile": { "email": "zoe@test.org" } },
+                    { "id": "B1", "name": "bot", "is_bot": true, "profile": {} },
+                ]),
+            ),
+            (
+                "channels.json",
+                json!([{ "id": "C1", "name": "general", "members": ["U1", "U2", "B1"] }]),
+            ),
+            ("dms.json", json!([{ "id": "D1", "members": ["U1", "U2"] }])),
+            // only alice was imported from this group
+            (
+                "mpims.json",
+                json!([{ "id": "G1", "name": "mpdm-alice--max-1", "members": ["U1", "U9"] }]),
+            ),
+            (
+                "general/2024-01-01.json",
+                json!([
+                    { "type": "message", "subtype": "channel_join", "user": "U2",
+                      "text": "<@U2> has joined the channel", "ts": "1704067200.000100" },
+                    { "type": "message", "user": "U2", "text": "hi <@U1> &amp; all",
+                      "ts": "1704067260.000200" },
+                    { "type": "message", "user": "B1", "text": "", "ts": "1704067320.000300",
+                      "files": [{ "id": "F1", "name": "report.txt" },
+                                { "id": "F2", "name": "lost.txt" }] },
+                ]),
+            ),
+            (
+                "D1/2024-01-02.json",
+                json!([{ "type": "message", "user": "U1", "text": "psst",
+                         "ts": "1704153600.000000" }]),
+            ),
+        ];
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, value) in entries {
+            zip.start_file(name, SimpleFileOptions::default())?;
+            zip.write_all(value.to_string().as_bytes())?;
+        }
+        zip.start_file("__uploads/F1/report.txt", SimpleFileOptions::default())?;
+        zip.write_all(b"quarterly report")?;
+        Ok(zip.finish()?.into_inner())
+    }
+
+    #[tokio::test]
+    async fn import_slack_should_be_idempotent() -> Result<()> {
+        let (_tdb, state, mailer) = AppState::new_for_test_with_mailer(|_| {}).await?;
+        let ws = state.create_workspace("slack", 1).await?;
+        let archive = slack_export()?;
+        let opts = SlackImportOptions::default();
+        let report = state
+            .import_slack(ws.id, Cursor::new(archive.clone()), &opts)
+            .await?;
+        let expected = SlackImportReport {
+            users_created: 2,
+            users_matched: 1,
+            invited: 2,
+            chats_created: 2,
+            chats_skipped: 1,
+            messages_imported: 3,
+            messages_skipped: 1,
+            files_stored: 1,
+            files_missing: 1,
+        };
+        assert_eq!(report, expected);
+        let mails = mailer.mails();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].to, "zoe@test.org");
+
+        let zoe = state
+            .find_user_by_email("zoe@test.org")
+            .await?
+            .expect("user should exist");
+        assert_eq!(zoe.fullname, "Zoe Doe");
+        // alice has to accept the invitation first
+        assert!(!state.is_active_member(ws.id, 1).await?);
+        let alice = state.find_user_by_id(1).await?.expect("user should exist");
+        let invitations = state.list_invitations(&alice).await?;
+        assert_eq!(invitations.len(), 1);
+        assert_eq!(invitations[0].ws_id, ws.id);
+        let messages: Vec<(String, Vec<String>, DateTime<Utc>)> = sqlx::query_as(
+            r#"
+            SELECT m.content, m.files, m.created_at
+            FROM messages m JOIN chats c ON c.id = m.chat_id
+            WHERE c.ws_id = $1
+            ORDER BY m.created_at
+            "#,
+        )
+        .bind(ws.id)
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(messages[0].0, "hi @Alice & all");
+        assert_eq!(messages[0].2, parse_ts("1704067260.000200").unwrap());
+        let file = ChatFile::new(ws.id as _, "report.txt", b"quarterly report");
+        assert_eq!(messages[1].1, [file.url()]);
+        assert!(file.path(&state.config.server.base_dir).exists());
+
+        // nothing is imported twice
+        let report = state
+            .import_slack(ws.id, Cursor::new(archive.clone()), &opts)
+            .await?;
+        assert_eq!(report.users_created + report.chats_created, 0);
+        assert_eq!(report.messages_imported, 0);
+        assert_eq!(report.messages_skipped, 4);
+        assert_eq!(mailer.mails().len(), 1);
+
+        // the bot of another workspace is another account
+        let other = state.create_workspace("slack2", 1).await?;
+        let report = state
+            .import_slack(other.id, Cursor::new(archive), &opts)
+            .await?;
+        assert_eq!(report.users_created, 1);
+        assert_eq!(report.users_matched, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slack_import_job_should_keep_report() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.create_workspace("slack", 1).await?;
+        let job = state.create_slack_import(ws.id, Some(1)).await?;
+        assert_eq!(job.status, ImportStatus::Pending);
+        let path = job.path(&state.config.server.base_dir);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, slack_export()?)?;
+
+        let opts = SlackImportOptions::default();
+        let job = state.run_slack_import(job.id, &opts).await?;
+        assert_eq!(job.status, ImportStatus::Done, "{:?}", job.error);
+        assert_eq!(job.report.map(|r| r.0.messages_imported), Some(3));
+        assert!(!path.exists());
+        assert_eq!(
+            state.find_slack_import(job.id).await?.status,
+            ImportStatus::Done
+        );
+        let err = state.run_slack_import(job.id, &opts).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+
+        // a broken upload fails the job
+        let job = state.create_slack_import(ws.id, Some(1)).await?;
+        std::fs::write(job.path(&state.config.server.base_dir), b"not a zip")?;
+        let job = state.run_slack_import(job.id, &opts).await?;
+        assert_eq!(job.status, ImportStatus::Failed);
+        assert!(job.error.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn convert_text_should_resolve_mentions() {
+        let names = HashMap::from([("U1".to_string(), "Alice".to_string())]);
+        assert_eq!(
+            convert_text("<@U1|alice> &lt;3 <@U9> <@U1", &names),
+            "@Alice <3 <@U9> <@U1"
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, Extensions, HeaderMap},
+};
+
+use crate::AppState;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// ip of the client, none if the server is not served with connect info (e.g. in tests)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// the peer address, or the first `x-forwarded-for` address if the proxy is trusted
+pub fn client_ip(
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    trust_proxy: bool,
+) -> Option<IpAddr> {
+    let forwarded = trust_proxy
+        .then(|| headers.get(FORWARDED_FOR_HEADER))
+        .flatten()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+    let peer = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    forwarded.or(peer)
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let trust_proxy = state.config.rate_limit.trust_proxy;
+        Ok(Self(client_ip(
+            &parts.headers,
+            &parts.extensions,
+            trust_proxy,
+        )))
+    }
+}
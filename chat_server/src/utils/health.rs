@@ -0,0 +1,111 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::Duration};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+// a check taking longer than this fails
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Ok,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CheckResult {
+    pub status: HealthStatus,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// body of the `/healthz` and `/readyz` responses, served with 503 if any check failed
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+impl HealthReport {
+    pub fn new() -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            checks: BTreeMap::new(),
+        }
+    }
+
+    /// run the check with a timeout and record its status and latency
+    pub async fn check<E: Display>(
+        mut self,
+        name: &str,
+        check: impl Future<Output = Result<(), E>>,
+    ) -> Self {
+        let start = Instant::now();
+        let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("timed out after {:?}", CHECK_TIMEOUT)),
+        };
+        let status = match error {
+            Some(_) => HealthStatus::Fail,
+            None => HealthStatus::Ok,
+        };
+        if status == HealthStatus::Fail {
+            self.status = HealthStatus::Fail;
+        }
+        let result = CheckResult {
+            status,
+            latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+            error,
+        };
+        self.checks.insert(name.to_string(), result);
+        self
+    }
+}
+
+impl Default for HealthReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntoResponse for HealthReport {
+    fn into_response(self) -> Response {
+        let status = match self.status {
+            HealthStatus::Ok => StatusCode::OK,
+            HealthStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn health_report_should_fail_if_any_check_fails() {
+        let report = HealthReport::new()
+            .check("ok", async { Ok::<_, String>(()) })
+            .await;
+        assert_eq!(report.status, HealthStatus::Ok);
+
+        let report = report
+            .check("fail", async { Err("boom".to_string()) })
+            .await;
+        assert_eq!(report.status, HealthStatus::Fail);
+        assert_eq!(report.checks["ok"].status, HealthStatus::Ok);
+        assert_eq!(report.checks["fail"].error.as_deref(), Some("boom"));
+        assert_eq!(
+            report.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -10,15 +10,84 @@ pub struct EncodingKey(Ed25519KeyPair);
 
 pub struct DecodingKey(Ed25519PublicKey);
 
+/// what a token grants, each scope is a distinct audience so a token is only accepted where
+/// its scope is expected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+    /// the whole api
+    Full,
+    /// the password is verified, the 2fa code is still expected by `/api/signin/mfa`
+    MfaPending,
+    /// the workspace requires 2fa, only the enrollment endpoints are allowed
+    MfaEnroll,
+    /// the link of the email verification email, single use
+    EmailVerify,
+    /// the link of the password reset email, single use
+    PasswordReset,
+    /// the download link of a workspace export, its id is the export id
+    ExportDownload,
+    /// the link of an invitation email, sets the first password like a reset, single use
+    Invite,
+}
+
+impl TokenScope {
+    fn audience(&self) -> &'static str {
+        match self {
+            TokenScope::Full => JWT_AUD,
+            TokenScope::MfaPending => "chat-server/mfa-pending",
+            TokenScope::MfaEnroll => "chat-server/mfa-enroll",
+            TokenScope::EmailVerify => "chat-server/email-verify",
+            TokenScope::PasswordReset => "chat-server/password-reset",
+            TokenScope::ExportDownload => "chat-server/export-download",
+            TokenScope::Invite => "chat-server/invite",
+        }
+    }
+
+    /// seconds a token of the scope is valid
+    pub(crate) fn duration(&self) -> u64 {
+        match self {
+            TokenScope::Full => JWT_DURATION,
+            TokenScope::MfaPending => 60 * 5,
+            TokenScope::MfaEnroll => 60 * 15,
+            TokenScope::EmailVerify => 60 * 60 * 24,
+            TokenScope::PasswordReset => 60 * 60,
+            TokenScope::ExportDownload => 60 * 60,
+            TokenScope::Invite => 60 * 60 * 24 * 7,
+        }
+    }
+}
+
 impl EncodingKey {
     pub fn load(pem: &str) -> Result<Self, AppError> {
         Ok(Self(Ed25519KeyPair::from_pem(pem)?))
     }
 
     pub fn encode(&self, user: impl Into<User>) -> Result<String, AppError> {
+        self.encode_scoped(user, TokenScope::Full)
+    }
+
+    pub fn encode_scoped(
+        &self,
+        user: impl Into<User>,
+        scope: TokenScope,
+    ) -> Result<String, AppError> {
         let user = user.into();
-        let mut claims = Claims::with_custom_claims(user, Duration::from_secs(JWT_DURATION));
-        claims = claims.with_issuer(JWT_ISS).with_audience(JWT_AUD);
+        let mut claims = Claims::with_custom_claims(user, Duration::from_secs(scope.duration()));
+        claims = claims.with_issuer(JWT_ISS).with_audience(scope.audience());
+        Ok(self.sign(claims)?)
+    }
+
+    /// a token with a unique id, the caller keeps track of the used ids
+    pub fn encode_single_use(
+        &self,
+        user: impl Into<User>,
+        scope: TokenScope,
+        id: &str,
+    ) -> Result<String, AppError> {
+        let claims = Claims::with_custom_claims(user.into(), Duration::from_secs(scope.duration()))
+            .with_issuer(JWT_ISS)
+            .with_audience(scope.audience())
+            .with_jwt_id(id);
         Ok(self.sign(claims)?)
     }
 }
@@ -29,10 +98,14 @@ impl DecodingKey {
     }
 
     pub fn verify(&self, token: &str) -> Result<User, AppError> {
-        // let mut options = VerificationOptions::default();
+        self.verify_scoped(token, &[TokenScope::Full])
+    }
 
+    /// verify a token of any of the scopes
+    pub fn verify_scoped(&self, token: &str, scopes: &[TokenScope]) -> Result<User, AppError> {
+        let audiences: Vec<_> = scopes.iter().map(|scope| scope.audience()).collect();
         let options = VerificationOptions {
-            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUD])),
+            allowed_audiences: Some(HashSet::from_strings(&audiences)),
             allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
             ..Default::default()
         };
@@ -40,6 +113,24 @@ impl DecodingKey {
         let claims = self.verify_token(token, Some(options))?;
         Ok(claims.custom)
     }
+
+    /// verify a token of `encode_single_use`, returns its user and id
+    pub fn verify_single_use(
+        &self,
+        token: &str,
+        scope: TokenScope,
+    ) -> Result<(User, String), AppError> {
+        let options = VerificationOptions {
+            allowed_audiences: Some(HashSet::from_strings(&[scope.audience()])),
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
+            ..Default::default()
+        };
+        let claims = self
+            .verify_token::<User>(token, Some(options))
+            .map_err(|_| AppError::InvalidToken)?;
+        let id = claims.jwt_id.ok_or(AppError::InvalidToken)?;
+        Ok((claims.custom, id))
+    }
 }
 
 impl Deref for EncodingKey {
@@ -76,6 +167,20 @@ mod tests {
         let user2 = dk.verify(&token)?;
 
         assert_eq!(user, user2);
+
+        // a token is only accepted where its scope is expected
+        let token = ek.encode_scoped(user.clone(), TokenScope::MfaPending)?;
+        assert!(dk.verify(&token).is_err());
+        let user3 = dk.verify_scoped(&token, &[TokenScope::MfaPending])?;
+        assert_eq!(user, user3);
+
+        let token = ek.encode_single_use(user.clone(), TokenScope::PasswordReset, "id")?;
+        assert!(dk
+            .verify_single_use(&token, TokenScope::EmailVerify)
+            .is_err());
+        let (user4, id) = dk.verify_single_use(&token, TokenScope::PasswordReset)?;
+        assert_eq!(user, user4);
+        assert_eq!(id, "id");
         Ok(())
     }
 }
@@ -0,0 +1,194 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{MailConfig, MailTransport, SmtpTls},
+    error::AppError,
+};
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// a plain text email
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// delivers the emails of the server, picked by `mail.transport`
+pub trait Mailer: Send + Sync + 'static {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>>;
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+/// writes each email to a `.eml` file of the directory
+pub struct FileMailer {
+    from: Mailbox,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+/// keeps the emails, cloned mailers share them
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMailer {
+    mails: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl Mail {
+    pub fn new(to: impl Into<String>, subject: impl Into<String>, body: impl Into<String>) -> Self {
+        Self {
+            to: to.into(),
+            subject: subject.into(),
+            body: body.into(),
+        }
+    }
+
+    fn to_message(&self, from: &Mailbox) -> Result<Message, AppError> {
+        let to = self
+            .to
+            .parse::<Mailbox>()
+            .map_err(|e| AppError::InvalidInput(format!("email {}: {}", self.to, e)))?;
+        Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(&self.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.body.clone())
+            .map_err(|e| AppError::Mail(e.to_string()))
+    }
+}
+
+impl SmtpMailer {
+    pub fn try_new(config: &MailConfig) -> Result<Self, AppError> {
+        let smtp = &config.smtp;
+        let builder = match smtp.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)
+                .map_err(|e| AppError::Mail(e.to_string()))?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)
+                .map_err(|e| AppError::Mail(e.to_string()))?,
+        };
+        let mut builder = builder.port(smtp.port);
+        if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            from: parse_from(config)?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let message = mail.to_message(&self.from)?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|e| AppError::Mail(e.to_string()))?;
+            Ok(())
+        })
+    }
+}
+
+impl FileMailer {
+    pub fn try_new(config: &MailConfig) -> Result<Self, AppError> {
+        std::fs::create_dir_all(&config.dir)?;
+        Ok(Self {
+            from: parse_from(config)?,
+            transport: AsyncFileTransport::new(&config.dir),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>> {
+        Box::pin(async move {
+            let message = mail.to_message(&self.from)?;
+            let id = self
+                .transport
+                .send(message)
+                .await
+                .map_err(|e| AppError::Mail(e.to_string()))?;
+            tracing::info!("Mail to {} written as {}.eml", mail.to, id);
+            Ok(())
+        })
+    }
+}
+
+impl MemoryMailer {
+    /// the emails sent so far
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails.lock().expect("mailer lock poisoned").clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), AppError>> {
+        self.mails
+            .lock()
+            .expect("mailer lock poisoned")
+            .push(mail.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// the mailer of the configured transport
+pub fn new_mailer(config: &MailConfig) -> Result<Arc<dyn Mailer>, AppError> {
+    Ok(match config.transport {
+        MailTransport::Smtp => Arc::new(SmtpMailer::try_new(config)?),
+        MailTransport::File => Arc::new(FileMailer::try_new(config)?),
+        MailTransport::Memory => Arc::new(MemoryMailer::default()),
+    })
+}
+
+fn parse_from(config: &MailConfig) -> Result<Mailbox, AppError> {
+    config
+        .from
+        .parse()
+        .map_err(|e| AppError::Mail(format!("mail.from: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn file_mailer_should_write_eml() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat-mails-{}", uuid::Uuid::now_v7()));
+        let config = MailConfig {
+            dir: dir.clone(),
+            ..Default::default()
+        };
+        let mailer = FileMailer::try_new(&config)?;
+        let mail = Mail::new("alice@test.org", "hello", "hello alice");
+        mailer.send(&mail).await?;
+
+        let files: Vec<_> = std::fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+        assert_eq!(files.len(), 1);
+        let content = std::fs::read_to_string(files[0].path())?;
+        assert!(content.contains("To: alice@test.org"));
+        assert!(content.contains("Subject: hello"));
+
+        let mail = Mail::new("not an email", "hello", "hello");
+        assert!(mailer.send(&mail).await.is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::OnceLock;
+
+use axum::{extract::State, routing::get, Router};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::PgPool;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{info, warn};
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// install the process wide prometheus recorder, metrics recorded before are dropped
+pub fn metrics_handle() -> &'static PrometheusHandle {
+    HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("http_request_duration_seconds".to_string()),
+                LATENCY_BUCKETS,
+            )
+            .expect("latency buckets should not be empty")
+            .install_recorder()
+            .expect("failed to install prometheus recorder")
+    })
+}
+
+/// render the metrics in the prometheus text format, with the current pool gauges
+pub fn render_metrics(pool: &PgPool) -> String {
+    metrics::gauge!("db_pool_connections").set(pool.size() as f64);
+    metrics::gauge!("db_pool_idle_connections").set(pool.num_idle() as f64);
+    metrics_handle().render()
+}
+
+/// serve `/metrics` on its own port, kept off the public listener of the server
+pub async fn spawn_metrics_server(port: u16, pool: PgPool) -> std::io::Result<JoinHandle<()>> {
+    let addr = format!("0.0.0.0:{}", port);
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Serving metrics on {}", addr);
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, metrics_router(pool)).await {
+            warn!("Metrics server failed: {}", e);
+        }
+    }))
+}
+
+fn metrics_router(pool: PgPool) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(pool)
+}
+
+/// prometheus metrics of the process
+async fn metrics_handler(State(pool): State<PgPool>) -> String {
+    render_metrics(&pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn metrics_router_should_render_pool_gauges() -> Result<()> {
+        metrics_handle();
+        let pool = PgPool::connect_lazy("postgres://localhost:5432/chat")?;
+        let req = Request::builder().uri("/metrics").body(Body::empty())?;
+        let res = metrics_router(pool).oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+        assert!(String::from_utf8(body.to_vec())?.contains("db_pool_connections 0"));
+        Ok(())
+    }
+}
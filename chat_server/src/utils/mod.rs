@@ -1,3 +1,20 @@
+mod client_ip;
+mod health;
 mod jwt;
+mod mailer;
+mod metrics;
+mod request_meta;
+mod shutdown;
+mod telemetry;
 
-pub(crate) use jwt::{DecodingKey, EncodingKey};
+pub use client_ip::{client_ip, ClientIp};
+pub use health::{CheckResult, HealthReport, HealthStatus};
+pub use jwt::{DecodingKey, EncodingKey, TokenScope};
+pub use mailer::{new_mailer, FileMailer, Mail, Mailer, MemoryMailer, SmtpMailer};
+pub use metrics::{metrics_handle, render_metrics, spawn_metrics_server};
+pub use request_meta::RequestMeta;
+pub use shutdown::{serve_with_graceful_shutdown, shutdown_signal};
+pub use telemetry::{
+    current_traceparent, init_tracing, log_handle, set_traceparent, LogFilter, LogHandle,
+    TelemetryGuard,
+};
@@ -0,0 +1,45 @@
+use std::{convert::Infallible, net::IpAddr};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::USER_AGENT, request::Parts, HeaderMap},
+};
+
+use super::client_ip;
+use crate::{middlewares::REQUEST_ID_HEADER, AppState};
+
+// longer user agents are cut, they are only kept for the audit log
+const MAX_USER_AGENT: usize = 512;
+
+/// where a request comes from, recorded with the audit events
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestMeta {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequestMeta {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let trust_proxy = state.config.rate_limit.trust_proxy;
+        let header = |headers: &HeaderMap, name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        Ok(Self {
+            ip: client_ip(&parts.headers, &parts.extensions, trust_proxy),
+            user_agent: header(&parts.headers, USER_AGENT.as_str())
+                .map(|v| v.chars().take(MAX_USER_AGENT).collect()),
+            request_id: header(&parts.headers, REQUEST_ID_HEADER),
+        })
+    }
+}
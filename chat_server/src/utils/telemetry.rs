@@ -0,0 +1,206 @@
+use std::{collections::HashMap, env, sync::OnceLock};
+
+use anyhow::Result;
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{self as sdktrace, TracerProvider},
+    Resource,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{level_filters::LevelFilter, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer as _, Registry,
+};
+
+use crate::config::{LogConfig, LogFormat, TelemetryConfig};
+
+const TRACEPARENT: &str = "traceparent";
+
+static LOG_HANDLE: OnceLock<LogHandle> = OnceLock::new();
+
+/// body of the `/admin/log` endpoints
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LogFilter {
+    pub filter: String,
+}
+
+/// changes the log filter of a running subscriber
+#[derive(Debug, Clone)]
+pub struct LogHandle(reload::Handle<EnvFilter, Registry>);
+
+/// keeps the otlp exporter alive, the pending spans are flushed on drop
+pub struct TelemetryGuard(Option<TracerProvider>);
+
+/// install the global subscriber: logs to stdout filtered by `RUST_LOG` or the config, and
+/// spans to the otlp collector if configured
+pub fn init_tracing(
+    log: &LogConfig,
+    config: &TelemetryConfig,
+    service_name: &'static str,
+) -> Result<TelemetryGuard> {
+    let filter = match env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(filter) => EnvFilter::try_new(filter)?,
+        Err(_) => EnvFilter::try_new(&log.filter)?,
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = LOG_HANDLE.set(LogHandle(handle));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let provider = config
+        .otlp_endpoint
+        .as_deref()
+        .map(|endpoint| build_tracer_provider(endpoint, service_name))
+        .transpose()?;
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(service_name))
+            .with_filter(LevelFilter::INFO)
+    });
+    let (json, text) = match log.format {
+        LogFormat::Json => (Some(tracing_subscriber::fmt::layer().json()), None),
+        LogFormat::Text => (None, Some(tracing_subscriber::fmt::layer())),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(text)
+        .with(otel)
+        .init();
+    Ok(TelemetryGuard(provider))
+}
+
+/// handle of the global subscriber installed by `init_tracing`
+pub fn log_handle() -> Option<&'static LogHandle> {
+    LOG_HANDLE.get()
+}
+
+impl LogHandle {
+    /// current filter directives
+    pub fn filter(&self) -> Option<String> {
+        self.0.with_current(|filter| filter.to_string()).ok()
+    }
+
+    /// replace the filter, the invalid directives are rejected and the filter is kept
+    pub fn set_filter(&self, filter: &str) -> Result<()> {
+        let filter: EnvFilter = EnvFilter::try_new(filter)?;
+        self.0.reload(filter)?;
+        Ok(())
+    }
+}
+
+// export the spans in batches to the otlp/http endpoint
+fn build_tracer_provider(endpoint: &str, service_name: &'static str) -> Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint)
+        .build_span_exporter()?;
+    let resource = Resource::new([KeyValue::new("service.name", service_name)]);
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(sdktrace::config().with_resource(resource))
+        .build())
+}
+
+/// W3C traceparent of the current span, none if spans are not exported
+pub fn current_traceparent() -> Option<String> {
+    let cx = Span::current().context();
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut carrier));
+    carrier.remove(TRACEPARENT)
+}
+
+/// continue the trace of a W3C traceparent in span
+pub fn set_traceparent(span: &Span, traceparent: &str) {
+    let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+    let cx = global::get_text_map_propagator(|propagator| propagator.extract(&carrier));
+    span.set_parent(cx);
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        let Some(provider) = self.0.take() else {
+            return;
+        };
+        for ret in provider.force_flush() {
+            if let Err(e) = ret {
+                warn!("Failed to flush spans: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Router};
+    use opentelemetry::trace::TraceContextExt;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    #[test]
+    fn traceparent_should_propagate() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("publish");
+            let traceparent = span.in_scope(current_traceparent).expect("traceparent");
+            assert!(traceparent.starts_with("00-"));
+
+            let child = tracing::info_span!("dispatch");
+            set_traceparent(&child, &traceparent);
+            let parent_id = span.context().span().span_context().trace_id();
+            let child_id = child.context().span().span_context().trace_id();
+            assert_eq!(parent_id, child_id);
+        });
+    }
+
+    #[test]
+    fn log_filter_should_reload() -> Result<()> {
+        let (filter, handle) = reload::Layer::new(EnvFilter::try_new("info")?);
+        let handle = LogHandle(handle);
+        let subscriber = tracing_subscriber::registry().with(filter);
+        tracing::subscriber::with_default(subscriber, || -> Result<()> {
+            assert!(!tracing::enabled!(tracing::Level::DEBUG));
+            handle.set_filter("debug")?;
+            assert!(tracing::enabled!(tracing::Level::DEBUG));
+            assert_eq!(handle.filter().as_deref(), Some("debug"));
+
+            // invalid directives keep the current filter
+            assert!(handle.set_filter("chat_server=loud").is_err());
+            assert_eq!(handle.filter().as_deref(), Some("debug"));
+            Ok(())
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn otlp_exporter_should_send_spans_to_collector() -> Result<()> {
+        // a collector stand-in recording the content type of the export requests
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |headers: HeaderMap| async move {
+                let _ = tx.send(headers["content-type"].to_str().unwrap().to_string());
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let provider = build_tracer_provider(&endpoint, "chat-server")?;
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("request").in_scope(|| tracing::info!("hello"));
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush()).await?;
+
+        let content_type = rx.recv().await.expect("collector should receive spans");
+        assert_eq!(content_type, "application/x-protobuf");
+        Ok(())
+    }
+}
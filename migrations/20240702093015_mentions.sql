@@ -0,0 +1,14 @@
+-- mention kind: user (@name), channel (@channel) and here (@here)
+CREATE TYPE mention_kind AS ENUM ('user', 'channel', 'here');
+
+-- users mentioned by a message
+CREATE TABLE IF NOT EXISTS message_mentions (
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    kind mention_kind NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (message_id, user_id)
+);
+
+-- mention inbox of a user, latest first
+CREATE INDEX IF NOT EXISTS message_mentions_user_id_message_id_idx ON message_mentions(user_id, message_id DESC);
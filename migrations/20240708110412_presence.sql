@@ -0,0 +1,10 @@
+-- presence of a user: online (connected and active), away (connected but idle), offline
+CREATE TYPE presence_status AS ENUM ('online', 'away', 'offline');
+
+-- maintained by notify server from the active sse connections and by heartbeats
+CREATE TABLE IF NOT EXISTS user_presence (
+    user_id BIGINT PRIMARY KEY REFERENCES users(id),
+    status presence_status NOT NULL DEFAULT 'offline',
+    last_active_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,10 @@
+-- events dispatched by notify server, kept for a while to replay them to reconnecting clients
+CREATE TABLE IF NOT EXISTS notify_outbox (
+    id BIGINT PRIMARY KEY,
+    user_ids BIGINT[] NOT NULL,
+    event JSONB NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS notify_outbox_user_ids_idx ON notify_outbox USING GIN (user_ids);
+CREATE INDEX IF NOT EXISTS notify_outbox_created_at_idx ON notify_outbox(created_at);
@@ -0,0 +1,7 @@
+-- failed signins per account (email:<email>) and per ip (ip:<ip>)
+CREATE TABLE IF NOT EXISTS signin_failures (
+    key VARCHAR(320) PRIMARY KEY,
+    failures INT NOT NULL DEFAULT 0,
+    last_failed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until TIMESTAMPTZ
+);
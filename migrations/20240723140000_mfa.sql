@@ -0,0 +1,20 @@
+-- totp secret of a user, enabled once the first code is verified
+CREATE TABLE IF NOT EXISTS user_mfa (
+    user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- base32 encoded
+    secret VARCHAR(64) NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    -- time step of the last accepted code, a code can't be replayed
+    last_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- single use recovery codes, sha256 hashed
+CREATE TABLE IF NOT EXISTS mfa_recovery_codes (
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash CHAR(64) NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
+
+-- members of the workspace must enable 2fa
+ALTER TABLE workspaces ADD COLUMN require_mfa BOOLEAN NOT NULL DEFAULT FALSE;
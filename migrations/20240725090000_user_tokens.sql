@@ -0,0 +1,16 @@
+-- set once the email verification link is followed
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+CREATE TYPE user_token_purpose AS ENUM ('email_verify', 'password_reset');
+
+-- single use tokens sent by email, the id is the jti of the signed token
+CREATE TABLE IF NOT EXISTS user_tokens (
+    id VARCHAR(64) PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    purpose user_token_purpose NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS user_tokens_user_id_idx ON user_tokens(user_id, purpose);
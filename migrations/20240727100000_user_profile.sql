@@ -0,0 +1,8 @@
+-- profile of a user, all optional
+ALTER TABLE users
+    ADD COLUMN display_name VARCHAR(64),
+    ADD COLUMN status_text VARCHAR(128),
+    -- IANA time zone, e.g. Asia/Shanghai
+    ADD COLUMN timezone VARCHAR(64),
+    -- url of an uploaded file of the workspace
+    ADD COLUMN avatar VARCHAR(256);
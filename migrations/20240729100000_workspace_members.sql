@@ -0,0 +1,14 @@
+-- workspaces of a user, `users.ws_id` is the active one a signin starts in
+CREATE TABLE IF NOT EXISTS workspace_members (
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (ws_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_idx ON workspace_members(user_id);
+
+-- every user is a member of its current workspace
+INSERT INTO workspace_members (ws_id, user_id)
+SELECT ws_id, id FROM users
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,7 @@
+-- the owner of a workspace is `workspaces.owner_id`, admins manage the members with it
+CREATE TYPE workspace_role AS ENUM ('member', 'admin');
+
+ALTER TABLE workspace_members
+    ADD COLUMN role workspace_role NOT NULL DEFAULT 'member',
+    -- the tokens of a deactivated member are rejected in the workspace
+    ADD COLUMN deactivated_at TIMESTAMPTZ;
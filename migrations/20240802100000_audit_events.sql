@@ -0,0 +1,38 @@
+-- security relevant and administrative actions
+CREATE TYPE audit_action AS ENUM (
+    'signup',
+    'signin',
+    'signin_failed',
+    'chat_create',
+    'member_add',
+    'member_remove',
+    'member_deactivate',
+    'member_reactivate',
+    'role_change',
+    'ownership_transfer',
+    'workspace_update',
+    'file_upload',
+    'file_download'
+);
+
+-- no foreign keys, the events outlive the rows they refer to
+CREATE TABLE IF NOT EXISTS audit_events (
+    id BIGSERIAL PRIMARY KEY,
+    -- NULL for the failed signins of unknown accounts
+    ws_id BIGINT,
+    actor_id BIGINT,
+    action audit_action NOT NULL,
+    target_type VARCHAR(32),
+    target_id VARCHAR(255),
+    details JSONB NOT NULL DEFAULT '{}',
+    ip VARCHAR(64),
+    user_agent TEXT,
+    request_id VARCHAR(64),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS audit_events_ws_id_idx ON audit_events(ws_id, id DESC);
+
+-- append only
+CREATE RULE audit_events_no_update AS ON UPDATE TO audit_events DO INSTEAD NOTHING;
+CREATE RULE audit_events_no_delete AS ON DELETE TO audit_events DO INSTEAD NOTHING;
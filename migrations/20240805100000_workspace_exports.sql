@@ -0,0 +1,18 @@
+-- archives of the data of a workspace, written under `base_dir/exports`
+CREATE TYPE export_status AS ENUM ('pending', 'running', 'done', 'failed');
+
+CREATE TABLE IF NOT EXISTS workspace_exports (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    -- NULL when started by the `export` subcommand
+    requested_by BIGINT REFERENCES users(id) ON DELETE SET NULL,
+    status export_status NOT NULL DEFAULT 'pending',
+    size BIGINT,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS workspace_exports_ws_id_idx ON workspace_exports(ws_id);
+
+ALTER TYPE audit_action ADD VALUE 'workspace_export';
@@ -0,0 +1,14 @@
+-- slack ids of the rows imported from a slack export, a re-run of the same export skips them
+CREATE TYPE slack_import_kind AS ENUM ('user', 'chat', 'message');
+
+CREATE TABLE IF NOT EXISTS slack_imports (
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    kind slack_import_kind NOT NULL,
+    slack_id VARCHAR(255) NOT NULL,
+    local_id BIGINT NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (ws_id, kind, slack_id)
+);
+
+-- the imported users choose their password with the link of an invitation email
+ALTER TYPE user_token_purpose ADD VALUE 'invite';
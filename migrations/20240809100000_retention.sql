@@ -0,0 +1,12 @@
+-- messages older than the retention are purged, NULL keeps them forever
+ALTER TABLE workspaces
+    ADD COLUMN retention_days INT;
+
+ALTER TABLE chats
+    -- overrides the retention of the workspace
+    ADD COLUMN retention_days INT,
+    -- nothing is purged from a chat under legal hold
+    ADD COLUMN legal_hold BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TYPE audit_action ADD VALUE 'retention_update';
+ALTER TYPE audit_action ADD VALUE 'retention_purge';
@@ -0,0 +1,21 @@
+-- messages sent later, a delivered one points to its message
+CREATE TYPE scheduled_status AS ENUM ('pending', 'sent', 'canceled', 'failed');
+
+CREATE TABLE IF NOT EXISTS scheduled_messages (
+    id BIGSERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    sender_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    files TEXT[] NOT NULL DEFAULT '{}',
+    send_at TIMESTAMPTZ NOT NULL,
+    status scheduled_status NOT NULL DEFAULT 'pending',
+    message_id BIGINT REFERENCES messages(id) ON DELETE SET NULL,
+    -- why the delivery failed
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the worker only looks for the pending messages which are due
+CREATE INDEX IF NOT EXISTS scheduled_messages_send_at_idx ON scheduled_messages(send_at)
+    WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS scheduled_messages_sender_id_idx ON scheduled_messages(sender_id, send_at);
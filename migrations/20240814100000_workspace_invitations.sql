@@ -0,0 +1,15 @@
+-- an invited user joins the workspace once it accepts, invitations are keyed by email so that
+-- inviting an unknown address looks the same as inviting an account
+CREATE TABLE IF NOT EXISTS workspace_invitations (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    -- lowercase
+    email VARCHAR(255) NOT NULL,
+    invited_by BIGINT REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (ws_id, email)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_invitations_email_idx ON workspace_invitations(email);
+
+ALTER TYPE audit_action ADD VALUE 'member_invite';
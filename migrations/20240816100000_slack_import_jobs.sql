@@ -0,0 +1,17 @@
+-- slack exports uploaded through the api are imported in the background, the archive is kept
+-- under `base_dir/imports` until the import finishes
+CREATE TYPE import_status AS ENUM ('pending', 'running', 'done', 'failed');
+
+CREATE TABLE IF NOT EXISTS slack_import_jobs (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    requested_by BIGINT REFERENCES users(id) ON DELETE SET NULL,
+    status import_status NOT NULL DEFAULT 'pending',
+    -- the SlackImportReport once done
+    report JSONB,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS slack_import_jobs_ws_id_idx ON slack_import_jobs(ws_id);
@@ -0,0 +1,2 @@
+-- the archive of a done export is removed once it is older than `export.ttl`
+ALTER TYPE export_status ADD VALUE 'expired';
@@ -0,0 +1,9 @@
+-- account security and server administration actions
+ALTER TYPE audit_action ADD VALUE 'mfa_enable';
+ALTER TYPE audit_action ADD VALUE 'mfa_disable';
+ALTER TYPE audit_action ADD VALUE 'password_reset';
+ALTER TYPE audit_action ADD VALUE 'password_change';
+ALTER TYPE audit_action ADD VALUE 'email_verify';
+ALTER TYPE audit_action ADD VALUE 'user_unlock';
+ALTER TYPE audit_action ADD VALUE 'slack_import';
+ALTER TYPE audit_action ADD VALUE 'log_filter_update';
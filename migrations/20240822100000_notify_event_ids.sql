@@ -0,0 +1,9 @@
+-- the chat server numbers an event once when it publishes it, so that every notify server
+-- replica streams it under the same id. The ids keep increasing from the ones the replicas
+-- issued themselves, from the current time in micros
+CREATE SEQUENCE IF NOT EXISTS notify_event_id_seq;
+
+SELECT setval('notify_event_id_seq', GREATEST(
+    (SELECT max(id) FROM notify_outbox),
+    (extract(epoch FROM now()) * 1000000)::BIGINT
+));
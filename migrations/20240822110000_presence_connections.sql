@@ -0,0 +1,11 @@
+-- the users connected to each notify server instance, refreshed by the instance. A user goes
+-- offline once no instance has a connection of it
+CREATE TABLE IF NOT EXISTS presence_connections (
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    instance_id UUID NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, instance_id)
+);
+
+CREATE INDEX IF NOT EXISTS presence_connections_updated_at_idx
+    ON presence_connections(updated_at);
@@ -0,0 +1,43 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chat_server::{log_handle, AdminList, LogFilter};
+
+use crate::AppState;
+
+impl AdminList for AppState {
+    fn admins(&self) -> &[i64] {
+        &self.config.auth.admins
+    }
+}
+
+/// current log filter directives
+pub(crate) async fn get_log_filter_handler() -> Response {
+    current_log_filter()
+}
+
+/// replace the log filter directives at runtime
+pub(crate) async fn set_log_filter_handler(Json(input): Json<LogFilter>) -> Response {
+    let Some(handle) = log_handle() else {
+        return not_installed();
+    };
+    if let Err(e) = handle.set_filter(&input.filter) {
+        let msg = format!("Invalid log filter {:?}: {}", input.filter, e);
+        return (StatusCode::BAD_REQUEST, msg).into_response();
+    }
+    tracing::warn!("Log filter set to {}", input.filter);
+    current_log_filter()
+}
+
+fn current_log_filter() -> Response {
+    match log_handle().and_then(|handle| handle.filter()) {
+        Some(filter) => Json(LogFilter { filter }).into_response(),
+        None => not_installed(),
+    }
+}
+
+fn not_installed() -> Response {
+    (StatusCode::NOT_FOUND, "Log subscriber is not installed").into_response()
+}
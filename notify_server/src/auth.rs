@@ -0,0 +1,83 @@
+use axum::{
+    extract::{FromRequestParts, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::Deserialize;
+
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+struct TokenParams {
+    access_token: String,
+}
+
+/// verify the bearer token, or the `access_token` query param since EventSource can't set headers
+pub(crate) async fn verify_token(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let token =
+        match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+            Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
+            Err(e) => match Query::<TokenParams>::from_request_parts(&mut parts, &state).await {
+                Ok(Query(params)) => params.access_token,
+                Err(_) => {
+                    let msg = format!("Failed to parse token: {:?}", e);
+                    tracing::warn!(msg);
+                    return (StatusCode::UNAUTHORIZED, msg).into_response();
+                }
+            },
+        };
+
+    let user = match state.pk.verify(&token) {
+        Ok(user) => user,
+        Err(e) => {
+            let msg = format!("Failed to verify token: {}", e);
+            tracing::warn!(msg);
+            return (StatusCode::FORBIDDEN, msg).into_response();
+        }
+    };
+    // the token outlives a deactivation or a removal from the workspace
+    match state.is_active_member(user.ws_id, user.id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let msg = format!("User {} is not an active member", user.id);
+            tracing::warn!(msg);
+            return (StatusCode::FORBIDDEN, msg).into_response();
+        }
+        Err(e) => {
+            tracing::warn!("Failed to check membership: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+    tracing::Span::current().record("user_id", user.id);
+    parts.extensions.insert(user);
+    let req = Request::from_parts(parts, body);
+    next.run(req).await
+}
+
+impl AppState {
+    async fn is_active_member(&self, ws_id: i64, user_id: i64) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM workspace_members
+                WHERE ws_id = $1 AND user_id = $2 AND deactivated_at IS NULL
+            )
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+    }
+}
@@ -0,0 +1,83 @@
+use std::{env, fs::File};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use chat_server::{LogConfig, TelemetryConfig};
+
+use crate::ReplayConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    #[serde(default)]
+    pub replay: ReplayConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub db_url: String,
+    /// seconds to wait for the open connections to close on shutdown
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// port of the `/metrics` endpoint, not served when unset
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthConfig {
+    // public key of the chat server to verify tokens
+    pub pk: String,
+    /// ids of the users allowed to call the `/admin` endpoints
+    #[serde(default)]
+    pub admins: Vec<i64>,
+}
+
+/// sse and websocket connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamConfig {
+    /// interval between two sse keep-alive messages, in seconds
+    pub keep_alive: u64,
+    /// events buffered per connection before a slow client lags behind and has to resync
+    pub channel_capacity: usize,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: 15,
+            channel_capacity: 256,
+        }
+    }
+}
+
+fn default_drain_timeout() -> u64 {
+    10
+}
+
+impl AppConfig {
+    pub fn try_load() -> Result<Self> {
+        // read from /etc/config/notify.yml, or ./notify.yml or from env NOTIFY_CONFIG
+        let ret = match (
+            env::var("NOTIFY_CONFIG"),
+            File::open("notify.yml"),
+            File::open("/etc/config/notify.yml"),
+        ) {
+            (Ok(file), _, _) => serde_yaml::from_reader(File::open(file)?),
+            (_, Ok(file), _) => serde_yaml::from_reader(file),
+            (_, _, Ok(file)) => serde_yaml::from_reader(file),
+            _ => bail!("Config file not found"),
+        };
+        Ok(ret?)
+    }
+}
@@ -0,0 +1,16 @@
+use axum::{extract::State, response::IntoResponse};
+use chat_server::HealthReport;
+
+use crate::AppState;
+
+/// liveness: the process is up and serving requests
+pub(crate) async fn healthz_handler() -> impl IntoResponse {
+    HealthReport::new()
+}
+
+/// readiness: the LISTEN connection receives the chat server notifications
+pub(crate) async fn readyz_handler(State(state): State<AppState>) -> impl IntoResponse {
+    HealthReport::new()
+        .check("listener", state.check_listener())
+        .await
+}
@@ -1,12 +1,307 @@
+mod admin;
+mod auth;
+mod config;
+mod health;
+mod notif;
+mod presence;
+mod replay;
 mod sse;
+mod ws;
 
-use axum::{routing::get, Router};
+use std::{
+    future::Future,
+    ops::Deref,
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
 
+use anyhow::Result;
+use axum::{
+    middleware::{from_fn, from_fn_with_state},
+    routing::get,
+    Router,
+};
+use chat_server::{
+    metrics_handle, request_span, serve_with_graceful_shutdown, set_request_id,
+    spawn_metrics_server, track_metrics, verify_admin, DecodingKey,
+};
+use dashmap::DashMap;
+use sqlx::PgPool;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+};
+use tower_http::trace::TraceLayer;
+use uuid::Uuid;
+
+use admin::{get_log_filter_handler, set_log_filter_handler};
+use auth::verify_token;
+use health::{healthz_handler, readyz_handler};
+use replay::UserStream;
 use sse::{index_handler, sse_handler};
+use ws::ws_handler;
+
+pub use config::{AppConfig, AuthConfig, ServerConfig, StreamConfig};
+pub use notif::setup_pg_listener;
+pub use replay::ReplayConfig;
+pub use ws::{ClientFrame, ServerFrame, WsEnvelope, WS_PROTOCOL_VERSION};
+
+#[derive(Clone)]
+pub struct AppState(Arc<AppStateInner>);
+
+pub struct AppStateInner {
+    pub(crate) config: AppConfig,
+    pub(crate) pk: DecodingKey,
+    pub(crate) pool: PgPool,
+    // live channel and replay buffer of the connected (or recently disconnected) users
+    pub(crate) users: DashMap<u64, UserStream>,
+    // highest event id dispatched, the ids are assigned by the chat server
+    pub(crate) event_id: AtomicU64,
+    // outbox events up to this id may have been pruned
+    pub(crate) outbox_floor: AtomicU64,
+    // the key of the connections of this instance in presence_connections
+    pub(crate) instance_id: String,
+    // set on shutdown, the open streams tell their clients to reconnect and end
+    pub(crate) shutdown: watch::Sender<bool>,
+    // payloads received by the listener on the probe channel
+    pub(crate) probe: broadcast::Sender<String>,
+}
+
+pub async fn get_router(config: AppConfig) -> Result<Router> {
+    let (_, router) = init(config).await?;
+    Ok(router)
+}
+
+/// serve the app until shutdown resolves, then close the open streams and the db pool
+pub async fn serve(
+    listener: TcpListener,
+    config: AppConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout);
+    let (state, router) = init(config).await?;
+    let metrics = match state.config.server.metrics_port {
+        Some(port) => Some(spawn_metrics_server(port, state.pool.clone()).await?),
+        None => None,
+    };
+    let s = state.clone();
+    let shutdown = async move {
+        shutdown.await;
+        s.shutdown.send_replace(true);
+    };
+    serve_with_graceful_shutdown(listener, router, shutdown, drain_timeout).await?;
+    if let Some(metrics) = metrics {
+        metrics.abort();
+    }
+    state.pool.close().await;
+    Ok(())
+}
+
+async fn init(config: AppConfig) -> Result<(AppState, Router)> {
+    metrics_handle();
+    let pool = PgPool::connect(&config.server.db_url).await?;
+    let state = AppState::try_new(config, pool)?;
+    state.init_event_id().await?;
+    setup_pg_listener(&state.config.server.db_url, state.clone()).await?;
+    presence::spawn_presence_sweeper(state.clone());
+    replay::spawn_replay_sweeper(state.clone());
+
+    let admin = Router::new()
+        .route(
+            "/log",
+            get(get_log_filter_handler).put(set_log_filter_handler),
+        )
+        .layer(from_fn_with_state(state.clone(), verify_admin::<AppState>));
 
-pub fn get_router() -> Router {
     // build our application with a route
-    Router::new()
-        .route("/", get(index_handler))
+    let router = Router::new()
         .route("/events", get(sse_handler))
+        .route("/ws", get(ws_handler))
+        .nest("/admin", admin)
+        .layer(from_fn_with_state(state.clone(), verify_token))
+        .route("/", get(index_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .layer(from_fn(track_metrics))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(from_fn(set_request_id))
+        .with_state(state.clone());
+    Ok((state, router))
+}
+
+impl Deref for AppState {
+    type Target = AppStateInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AppState {
+    pub fn try_new(config: AppConfig, pool: PgPool) -> Result<Self> {
+        let pk = DecodingKey::load(&config.auth.pk)?;
+        Ok(Self(Arc::new(AppStateInner {
+            config,
+            pk,
+            pool,
+            users: DashMap::new(),
+            event_id: AtomicU64::new(0),
+            outbox_floor: AtomicU64::new(0),
+            instance_id: Uuid::now_v7().to_string(),
+            shutdown: watch::channel(false).0,
+            probe: broadcast::channel(16).0,
+        })))
+    }
+
+    /// resolve once the server is shutting down
+    pub(crate) fn stopping(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.shutdown.subscribe();
+        async move {
+            let _ = rx.wait_for(|stopping| *stopping).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_utils {
+    use super::*;
+    use sqlx::Executor;
+    use sqlx_db_tester::TestPg;
+    use std::path::Path;
+
+    impl AppState {
+        // state without database connection
+        pub fn new_for_test(f: impl FnOnce(&mut AppConfig)) -> Result<Self> {
+            let mut config = AppConfig::try_load()?;
+            f(&mut config);
+            let pool = PgPool::connect_lazy(&config.server.db_url)?;
+            Self::try_new(config, pool)
+        }
+
+        // state on a test database
+        pub async fn new_for_test_with_db(
+            f: impl FnOnce(&mut AppConfig),
+        ) -> Result<(TestPg, Self)> {
+            let (tdb, mut config) = test_config().await?;
+            f(&mut config);
+            let pool = tdb.get_pool().await;
+            Ok((tdb, Self::try_new(config, pool)?))
+        }
+    }
+
+    // the users of the chat_server fixtures, all members of workspace 1
+    pub(crate) async fn test_config() -> Result<(TestPg, AppConfig)> {
+        let mut config = AppConfig::try_load()?;
+        let pos = config.server.db_url.rfind('/').expect("invalid db url");
+        let tdb = TestPg::new(
+            config.server.db_url[..pos].to_string(),
+            Path::new("../migrations"),
+        );
+        let pool = tdb.get_pool().await;
+        pool.execute(include_str!("../../chat_server/fixtures/test.sql"))
+            .await?;
+        config.server.db_url = tdb.url();
+        Ok((tdb, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::test_config;
+    use super::*;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use chat_server::{EncodingKey, User};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn get_router_should_work() -> Result<()> {
+        let (_tdb, config) = test_config().await?;
+        let app = get_router(config).await?;
+
+        let req = Request::builder().uri("/").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder().uri("/events").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::builder().uri("/readyz").body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_should_tell_sse_clients_to_reconnect() -> Result<()> {
+        let (_tdb, mut config) = test_config().await?;
+        config.server.metrics_port = None;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, config, async {
+            rx.await.ok();
+        }));
+
+        let token = test_token(1)?;
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = format!(
+            "GET /events?access_token={token} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: test\r\n\r\n"
+        );
+        stream.write_all(req.as_bytes()).await?;
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await?;
+        assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+
+        tx.send(()).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        assert!(res.contains("event: Reconnect"));
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn events_should_reject_deactivated_members() -> Result<()> {
+        let (tdb, config) = test_config().await?;
+        sqlx::query("UPDATE workspace_members SET deactivated_at = now() WHERE user_id = 2")
+            .execute(&tdb.get_pool().await)
+            .await?;
+        let app = get_router(config).await?;
+
+        let uri = format!("/events?access_token={}", test_token(2)?);
+        let req = Request::builder().uri(uri).body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let uri = format!("/events?access_token={}", test_token(1)?);
+        let req = Request::builder()
+            .uri(uri)
+            .header("User-Agent", "test")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    fn test_token(id: i64) -> Result<String> {
+        let ek = EncodingKey::load(include_str!("../../chat_server/fixtures/encoding.pem"))?;
+        let token = ek.encode(User {
+            id,
+            ws_id: 1,
+            fullname: "Test".to_string(),
+            email: format!("user{id}@test.org"),
+            password_hash: None,
+            created_at: chrono::Utc::now(),
+        })?;
+        Ok(token)
+    }
 }
@@ -1,22 +1,21 @@
 use anyhow::Result;
 
-use notify_server::get_router;
+use chat_server::{init_tracing, shutdown_signal};
+use notify_server::{serve, AppConfig};
 use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter, warn};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use tracing::{info, warn};
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
-    tracing_subscriber::registry().with(layer).init();
-
-    warn!("Prepare Starting to load {} config", env!("CARGO_PKG_NAME"));
-    let addr = format!("0.0.0.0:{}", 6687);
+    let config = AppConfig::try_load()?;
+    let _guard = init_tracing(&config.log, &config.telemetry, env!("CARGO_PKG_NAME"))?;
+    warn!("Loaded {} config", env!("CARGO_PKG_NAME"));
+    let addr = format!("0.0.0.0:{}", config.server.port);
 
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on {}", addr);
 
-    let app = get_router();
-
-    axum::serve(listener, app.into_make_service()).await?;
+    serve(listener, config, shutdown_signal()).await?;
+    info!("Server stopped");
     Ok(())
 }
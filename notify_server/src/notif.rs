@@ -0,0 +1,152 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use chat_server::{set_traceparent, AppEvent, Notification, NOTIFY_CHANNEL};
+use futures::StreamExt;
+use sqlx::postgres::PgListener;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{replay::StreamEvent, AppState};
+
+// notifications sent to ourselves by the readiness check
+const PROBE_CHANNEL: &str = "notify_server_probe";
+
+/// listen to the chat server notifications and dispatch them to the connected users
+pub async fn setup_pg_listener(db_url: &str, state: AppState) -> Result<()> {
+    let mut listener = PgListener::connect(db_url).await?;
+    listener.listen_all([NOTIFY_CHANNEL, PROBE_CHANNEL]).await?;
+    info!("Listening to postgres channel {}", NOTIFY_CHANNEL);
+
+    let mut stream = listener.into_stream();
+    tokio::spawn(async move {
+        while let Some(notif) = stream.next().await {
+            let notif = match notif {
+                Ok(notif) => notif,
+                Err(e) => {
+                    warn!("Failed to receive notification: {}", e);
+                    continue;
+                }
+            };
+            if notif.channel() == PROBE_CHANNEL {
+                let _ = state.probe.send(notif.payload().to_string());
+                continue;
+            }
+            match serde_json::from_str::<Notification>(notif.payload()) {
+                Ok(notification) => {
+                    let span = info_span!("dispatch", event = notification.event.name());
+                    if let Some(traceparent) = &notification.traceparent {
+                        set_traceparent(&span, traceparent);
+                    }
+                    state.dispatch(notification).instrument(span).await;
+                }
+                Err(e) => warn!("Failed to parse notification {}: {}", notif.payload(), e),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl AppState {
+    /// push the event to the streams of its users under the id the chat server published it
+    /// with, every replica receives it and streams it under the same id
+    pub(crate) async fn dispatch(&self, notification: Notification) {
+        let Some(id) = notification.id else {
+            warn!("Notification {} without an id", notification.event.name());
+            return;
+        };
+        let event = Arc::new(StreamEvent {
+            id: id as _,
+            event: notification.event,
+        });
+        self.event_id.fetch_max(event.id, Ordering::SeqCst);
+        let mut delivered = 0;
+        for user_id in &notification.user_ids {
+            if let Some(stream) = self.users.get(&(*user_id as u64)) {
+                stream.push(event.clone());
+                delivered += 1;
+            }
+        }
+        metrics::counter!("notify_events_fanned_out_total", "event" => event.event.name())
+            .increment(delivered);
+        // dropping the stream closes the open connections after this last event, the token is
+        // rejected when the client reconnects
+        if let AppEvent::MembershipRevoked(revoked) = &event.event {
+            self.users.remove(&(revoked.user_id as u64));
+        }
+        if self.config.replay.outbox {
+            self.save_to_outbox(&event, notification.user_ids).await;
+        }
+    }
+
+    /// send a notification to the probe channel and wait for the listener to receive it
+    pub(crate) async fn check_listener(&self) -> Result<()> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+        let nonce = format!("{}-{}", std::process::id(), nanos);
+        let mut rx = self.probe.subscribe();
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(PROBE_CHANNEL)
+            .bind(&nonce)
+            .execute(&self.pool)
+            .await?;
+        while rx.recv().await? != nonce {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_server::{MembershipEvent, Message};
+
+    #[tokio::test]
+    async fn dispatch_should_work() -> Result<()> {
+        let state = AppState::new_for_test(|_| {})?;
+        let mut sub = state.subscribe(1, None).await;
+
+        let message = Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 2,
+            content: "hello".to_string(),
+            files: vec![],
+            created_at: chrono::Utc::now(),
+        };
+        let event = AppEvent::NewMessage(message);
+        let notification = Notification {
+            id: Some(42),
+            ..Notification::new(vec![1, 3], event.clone())
+        };
+        state.dispatch(notification).await;
+        let received = sub.rx.recv().await?;
+        assert_eq!(received.event, event);
+        assert_eq!(received.id, 42);
+        assert_eq!(state.last_event_id(), 42);
+
+        // users which never connected have no stream
+        assert!(state.users.get(&3).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dispatch_should_close_revoked_streams() -> Result<()> {
+        let state = AppState::new_for_test(|_| {})?;
+        let mut sub = state.subscribe(1, None).await;
+
+        let event = AppEvent::MembershipRevoked(MembershipEvent {
+            ws_id: 1,
+            user_id: 1,
+        });
+        let notification = Notification {
+            id: Some(1),
+            ..Notification::new(vec![1], event.clone())
+        };
+        state.dispatch(notification).await;
+        assert_eq!(sub.rx.recv().await?.event, event);
+        assert!(sub.rx.recv().await.is_err());
+        assert!(state.users.get(&1).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use chat_server::{AppEvent, Notification, PresenceEvent, PresenceStatus};
+use tracing::warn;
+
+use crate::AppState;
+
+// connected users without heartbeat for this long become away
+const AWAY_AFTER_SECS: f64 = 300.0;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// every instance refreshes the connections of its users at each sweep, the connections of an
+// instance gone without a clean shutdown expire after this long
+const EXPIRE_AFTER_SECS: f64 = 90.0;
+// a user is marked offline only if it doesn't reconnect within this delay
+const OFFLINE_GRACE: Duration = Duration::from_secs(5);
+
+/// an active sse or websocket connection, the user goes offline when its last connection to any
+/// instance is dropped
+pub(crate) struct Connection {
+    state: AppState,
+    user_id: u64,
+    transport: &'static str,
+}
+
+impl AppState {
+    pub(crate) async fn set_presence(&self, user_id: u64, status: PresenceStatus) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        self.update_presence(&mut tx, user_id, status).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // record the connection of user to this instance, and set it online
+    async fn connect_presence(&self, user_id: u64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO presence_connections (user_id, instance_id)
+            VALUES ($1, $2::uuid)
+            ON CONFLICT (user_id, instance_id) DO UPDATE SET updated_at = now()
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(&self.instance_id)
+        .execute(&self.pool)
+        .await?;
+        self.set_presence(user_id, PresenceStatus::Online).await
+    }
+
+    // drop the connection of user to this instance, it goes offline unless another instance
+    // still has one. The presence row is locked first, so a connection recorded meanwhile is
+    // either seen here or sets the user online after
+    async fn disconnect_presence(&self, user_id: u64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT 1 FROM user_presence WHERE user_id = $1 FOR UPDATE")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM presence_connections WHERE user_id = $1 AND instance_id = $2::uuid",
+        )
+        .bind(user_id as i64)
+        .bind(&self.instance_id)
+        .execute(&mut *tx)
+        .await?;
+        let connected: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM presence_connections
+                WHERE user_id = $1 AND updated_at >= now() - make_interval(secs => $2)
+            )
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(EXPIRE_AFTER_SECS)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !connected {
+            self.update_presence(&mut tx, user_id, PresenceStatus::Offline)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn update_presence(
+        &self,
+        tx: &mut sqlx::PgConnection,
+        user_id: u64,
+        status: PresenceStatus,
+    ) -> Result<()> {
+        let prev: Option<(PresenceStatus,)> =
+            sqlx::query_as("SELECT status FROM user_presence WHERE user_id = $1 FOR UPDATE")
+                .bind(user_id as i64)
+                .fetch_optional(&mut *tx)
+                .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO user_presence (user_id, status)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET status = EXCLUDED.status,
+                updated_at = now(),
+                last_active_at = CASE
+                    WHEN EXCLUDED.status = 'online' THEN now()
+                    ELSE user_presence.last_active_at
+                END
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(status)
+        .execute(&mut *tx)
+        .await?;
+        if prev.map(|(prev,)| prev) != Some(status) {
+            self.notify_presence(tx, user_id as _, status).await?;
+        }
+        Ok(())
+    }
+
+    // mark the idle online users as away
+    async fn sweep_away(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let users: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            UPDATE user_presence
+            SET status = 'away', updated_at = now()
+            WHERE status = 'online' AND last_active_at < now() - make_interval(secs => $1)
+            RETURNING user_id
+            "#,
+        )
+        .bind(AWAY_AFTER_SECS)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (user_id,) in users {
+            self.notify_presence(&mut tx, user_id, PresenceStatus::Away)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // presence changes are published to the members of every workspace of the user
+    async fn notify_presence(
+        &self,
+        tx: &mut sqlx::PgConnection,
+        user_id: i64,
+        status: PresenceStatus,
+    ) -> Result<()> {
+        let peers: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT user_id
+            FROM workspace_members
+            WHERE ws_id IN (SELECT ws_id FROM workspace_members WHERE user_id = $1)
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let user_ids = peers.into_iter().map(|(id,)| id).collect();
+        let event = PresenceEvent { user_id, status };
+        Notification::new(user_ids, AppEvent::PresenceChanged(event))
+            .publish(&mut *tx)
+            .await?;
+        Ok(())
+    }
+
+    // keep the connections of the users connected to this instance from expiring
+    async fn refresh_presence(&self) -> Result<()> {
+        let user_ids: Vec<i64> = self
+            .users
+            .iter()
+            .filter(|stream| stream.tx.receiver_count() > 0)
+            .map(|stream| *stream.key() as i64)
+            .collect();
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO presence_connections (user_id, instance_id)
+            SELECT user_id, $2::uuid FROM unnest($1::bigint[]) AS user_id
+            ON CONFLICT (user_id, instance_id) DO UPDATE SET updated_at = now()
+            "#,
+        )
+        .bind(user_ids)
+        .bind(&self.instance_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // no instance refreshed the connections of these users, they are gone
+    async fn sweep_offline(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM presence_connections WHERE updated_at < now() - make_interval(secs => $1)",
+        )
+        .bind(EXPIRE_AFTER_SECS)
+        .execute(&mut *tx)
+        .await?;
+        let users: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            UPDATE user_presence p
+            SET status = 'offline', updated_at = now()
+            WHERE status <> 'offline'
+                AND NOT EXISTS (SELECT 1 FROM presence_connections c WHERE c.user_id = p.user_id)
+            RETURNING user_id
+            "#,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for (user_id,) in users {
+            self.notify_presence(&mut tx, user_id, PresenceStatus::Offline)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn sweep_presence(&self) -> Result<()> {
+        self.refresh_presence().await?;
+        self.sweep_away().await?;
+        self.sweep_offline().await
+    }
+}
+
+pub(crate) fn spawn_presence_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = state.sweep_presence().await {
+                warn!("Failed to sweep presence: {}", e);
+            }
+        }
+    });
+}
+
+impl Connection {
+    pub(crate) fn new(state: AppState, user_id: u64, transport: &'static str) -> Self {
+        metrics::gauge!("notify_active_connections", "transport" => transport).increment(1);
+        let s = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = s.connect_presence(user_id).await {
+                warn!("Failed to set user {} online: {}", user_id, e);
+            }
+        });
+        Self {
+            state,
+            user_id,
+            transport,
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        metrics::gauge!("notify_active_connections", "transport" => self.transport).decrement(1);
+        let state = self.state.clone();
+        let user_id = self.user_id;
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        handle.spawn(async move {
+            tokio::time::sleep(OFFLINE_GRACE).await;
+            let connected = state.users.get(&user_id).is_some_and(|stream| {
+                // disconnected users' buffers are kept for the retention from now on
+                stream.touch();
+                stream.tx.receiver_count() > 0
+            });
+            if connected {
+                return;
+            }
+            if let Err(e) = state.disconnect_presence(user_id).await {
+                warn!("Failed to set user {} offline: {}", user_id, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sweep_presence_should_expire_unrefreshed_users() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with_db(|_| {}).await?;
+        state.set_presence(1, PresenceStatus::Online).await?;
+        state.set_presence(2, PresenceStatus::Online).await?;
+        sqlx::query("UPDATE user_presence SET updated_at = now() - interval '1 hour'")
+            .execute(&state.pool)
+            .await?;
+        // user 2 is still connected to this instance
+        let _sub = state.subscribe(2, None).await;
+
+        state.sweep_presence().await?;
+
+        let rows: Vec<(i64, PresenceStatus)> =
+            sqlx::query_as("SELECT user_id, status FROM user_presence ORDER BY user_id")
+                .fetch_all(&state.pool)
+                .await?;
+        assert_eq!(
+            rows,
+            vec![(1, PresenceStatus::Offline), (2, PresenceStatus::Online)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presence_should_count_connections_of_every_instance() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test_with_db(|_| {}).await?;
+        let other = AppState::try_new(state.config.clone(), state.pool.clone())?;
+        state.connect_presence(1).await?;
+        other.connect_presence(1).await?;
+
+        // still connected to the other instance
+        state.disconnect_presence(1).await?;
+        let status: PresenceStatus =
+            sqlx::query_scalar("SELECT status FROM user_presence WHERE user_id = 1")
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(status, PresenceStatus::Online);
+
+        other.disconnect_presence(1).await?;
+        let status: PresenceStatus =
+            sqlx::query_scalar("SELECT status FROM user_presence WHERE user_id = 1")
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(status, PresenceStatus::Offline);
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chat_server::AppEvent;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::{AppConfig, AppState};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// replay of the events missed by reconnecting clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplayConfig {
+    /// events kept in memory per user
+    pub buffer_size: usize,
+    /// more missed events than this and the client is asked to resync instead
+    pub max_replay: usize,
+    /// also persist events in the notify_outbox table, so replay survives restarts
+    pub outbox: bool,
+    /// how long (in seconds) disconnected users' buffers and outbox events are kept
+    pub retention: u64,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            buffer_size: 128,
+            max_replay: 1000,
+            outbox: false,
+            retention: 60 * 10,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct StreamEvent {
+    pub(crate) id: u64,
+    pub(crate) event: AppEvent,
+}
+
+/// the live channel and the replay buffer of a user, kept for a while after disconnection
+pub(crate) struct UserStream {
+    pub(crate) tx: broadcast::Sender<Arc<StreamEvent>>,
+    buffer: Mutex<ReplayBuffer>,
+    // last event id acknowledged by a websocket client, 0 if none
+    acked: AtomicU64,
+}
+
+struct ReplayBuffer {
+    events: VecDeque<Arc<StreamEvent>>,
+    capacity: usize,
+    // events with an id up to floor may not be in the buffer
+    floor: u64,
+    last_active: Instant,
+}
+
+pub(crate) struct Subscription {
+    pub(crate) rx: broadcast::Receiver<Arc<StreamEvent>>,
+    pub(crate) replay: Vec<Arc<StreamEvent>>,
+    // missed events can't be replayed, the client should reload its state
+    pub(crate) resync: bool,
+}
+
+impl AppState {
+    pub(crate) fn last_event_id(&self) -> u64 {
+        self.event_id.load(Ordering::SeqCst)
+    }
+
+    /// subscribe to the events of user, replaying the ones after last_id
+    pub(crate) async fn subscribe(&self, user_id: u64, last_id: Option<u64>) -> Subscription {
+        let (rx, mut replay, floor) = {
+            let stream = self
+                .users
+                .entry(user_id)
+                .or_insert_with(|| UserStream::new(&self.config, self.last_event_id()));
+            let mut buffer = stream.buffer.lock().expect("replay buffer poisoned");
+            buffer.last_active = Instant::now();
+            // subscribe while holding the buffer lock, so no event is missed or duplicated
+            let rx = stream.tx.subscribe();
+            let replay: Vec<_> = match last_id {
+                Some(id) => buffer
+                    .events
+                    .iter()
+                    .filter(|e| e.id > id)
+                    .cloned()
+                    .collect(),
+                None => vec![],
+            };
+            (rx, replay, buffer.floor)
+        };
+
+        let Some(last_id) = last_id else {
+            return Subscription::new(rx, replay, false);
+        };
+        if last_id > self.last_event_id() {
+            // an id we never issued, e.g. from before a restart without outbox
+            return Subscription::new(rx, vec![], true);
+        }
+        if last_id < floor {
+            match self.replay_from_outbox(user_id, last_id, floor).await {
+                Some(mut events) => {
+                    events.append(&mut replay);
+                    replay = events;
+                }
+                None => return Subscription::new(rx, vec![], true),
+            }
+        }
+        if replay.len() > self.config.replay.max_replay {
+            return Subscription::new(rx, vec![], true);
+        }
+        Subscription::new(rx, replay, false)
+    }
+
+    pub(crate) fn ack(&self, user_id: u64, id: u64) {
+        if let Some(stream) = self.users.get(&user_id) {
+            stream.acked.fetch_max(id, Ordering::SeqCst);
+        }
+    }
+
+    /// the last event id acknowledged by user, if its buffer is still around
+    pub(crate) fn acked(&self, user_id: u64) -> Option<u64> {
+        let stream = self.users.get(&user_id)?;
+        let id = stream.acked.load(Ordering::SeqCst);
+        (id > 0).then_some(id)
+    }
+
+    // the events of user in (from, to], None if the outbox can't cover the range
+    async fn replay_from_outbox(
+        &self,
+        user_id: u64,
+        from: u64,
+        to: u64,
+    ) -> Option<Vec<Arc<StreamEvent>>> {
+        if !self.config.replay.outbox || from < self.outbox_floor.load(Ordering::SeqCst) {
+            return None;
+        }
+        let ret: Result<Vec<(i64, Json<AppEvent>)>, _> = sqlx::query_as(
+            r#"
+            SELECT id, event
+            FROM notify_outbox
+            WHERE $1 = ANY(user_ids) AND id > $2 AND id <= $3
+            ORDER BY id
+            LIMIT $4
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(from as i64)
+        .bind(to as i64)
+        .bind(self.config.replay.max_replay as i64 + 1)
+        .fetch_all(&self.pool)
+        .await;
+        match ret {
+            Ok(rows) => Some(
+                rows.into_iter()
+                    .map(|(id, Json(event))| Arc::new(StreamEvent { id: id as _, event }))
+                    .collect(),
+            ),
+            Err(e) => {
+                warn!("Failed to replay events from outbox: {}", e);
+                None
+            }
+        }
+    }
+
+    /// every replica saves the events it receives, and an event split across payloads arrives
+    /// once per payload: the users of an id are merged into one row
+    pub(crate) async fn save_to_outbox(&self, event: &StreamEvent, user_ids: Vec<i64>) {
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO notify_outbox (id, user_ids, event)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE
+            SET user_ids = notify_outbox.user_ids || EXCLUDED.user_ids
+            WHERE NOT notify_outbox.user_ids @> EXCLUDED.user_ids
+            "#,
+        )
+        .bind(event.id as i64)
+        .bind(user_ids)
+        .bind(Json(&event.event))
+        .execute(&self.pool)
+        .await;
+        if let Err(e) = ret {
+            warn!("Failed to save event {} to outbox: {}", event.id, e);
+        }
+    }
+
+    /// start from the last id the chat server published, the events before it were never
+    /// streamed by this replica
+    pub(crate) async fn init_event_id(&self) -> Result<()> {
+        let (last,): (i64,) = sqlx::query_as("SELECT last_value FROM notify_event_id_seq")
+            .fetch_one(&self.pool)
+            .await?;
+        self.event_id.fetch_max(last as _, Ordering::SeqCst);
+        self.outbox_floor.fetch_max(last as _, Ordering::SeqCst);
+        if !self.config.replay.outbox {
+            return Ok(());
+        }
+        let (min, max): (Option<i64>, Option<i64>) =
+            sqlx::query_as("SELECT min(id), max(id) FROM notify_outbox")
+                .fetch_one(&self.pool)
+                .await?;
+        if let (Some(min), Some(max)) = (min, max) {
+            self.event_id.fetch_max(max as _, Ordering::SeqCst);
+            self.outbox_floor.store(min as u64 - 1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    // drop the buffers of users gone for longer than the retention, and the old outbox events
+    async fn sweep_replay(&self) -> Result<()> {
+        let retention = Duration::from_secs(self.config.replay.retention);
+        self.users.retain(|_, stream| {
+            stream.tx.receiver_count() > 0
+                || stream
+                    .buffer
+                    .lock()
+                    .expect("replay buffer poisoned")
+                    .last_active
+                    .elapsed()
+                    < retention
+        });
+        if !self.config.replay.outbox {
+            return Ok(());
+        }
+        let (pruned,): (Option<i64>,) = sqlx::query_as(
+            r#"
+            WITH pruned AS (
+                DELETE FROM notify_outbox
+                WHERE created_at < now() - make_interval(secs => $1)
+                RETURNING id
+            )
+            SELECT max(id) FROM pruned
+            "#,
+        )
+        .bind(self.config.replay.retention as f64)
+        .fetch_one(&self.pool)
+        .await?;
+        if let Some(pruned) = pruned {
+            self.outbox_floor.fetch_max(pruned as _, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn spawn_replay_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = state.sweep_replay().await {
+                warn!("Failed to sweep replay buffers: {}", e);
+            }
+        }
+    });
+}
+
+impl UserStream {
+    fn new(config: &AppConfig, floor: u64) -> Self {
+        let capacity = config.replay.buffer_size;
+        Self {
+            tx: broadcast::channel(config.stream.channel_capacity.max(1)).0,
+            buffer: Mutex::new(ReplayBuffer {
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                floor,
+                last_active: Instant::now(),
+            }),
+            acked: AtomicU64::new(0),
+        }
+    }
+
+    /// buffer the event and send it to the live streams
+    pub(crate) fn push(&self, event: Arc<StreamEvent>) {
+        let mut buffer = self.buffer.lock().expect("replay buffer poisoned");
+        while buffer.events.len() >= buffer.capacity {
+            let Some(evicted) = buffer.events.pop_front() else {
+                break;
+            };
+            buffer.floor = evicted.id;
+        }
+        buffer.events.push_back(event.clone());
+        // no receiver while the user is disconnected, the event is only buffered
+        let _ = self.tx.send(event);
+    }
+
+    pub(crate) fn touch(&self) {
+        self.buffer
+            .lock()
+            .expect("replay buffer poisoned")
+            .last_active = Instant::now();
+    }
+}
+
+impl Subscription {
+    fn new(
+        rx: broadcast::Receiver<Arc<StreamEvent>>,
+        replay: Vec<Arc<StreamEvent>>,
+        resync: bool,
+    ) -> Self {
+        Self { rx, replay, resync }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_server::{Notification, TypingEvent};
+
+    fn typing(chat_id: i64) -> AppEvent {
+        AppEvent::Typing(TypingEvent {
+            chat_id,
+            user_id: 2,
+        })
+    }
+
+    // the event id as published by the chat server
+    fn notification(id: i64, user_ids: Vec<i64>, event: AppEvent) -> Notification {
+        Notification {
+            id: Some(id),
+            ..Notification::new(user_ids, event)
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_should_replay_missed_events() -> Result<()> {
+        let state = AppState::new_for_test(|config| config.replay.buffer_size = 4)?;
+        let sub = state.subscribe(1, None).await;
+        assert!(sub.replay.is_empty());
+        drop(sub);
+
+        for chat_id in 1..=3 {
+            state
+                .dispatch(notification(chat_id, vec![1], typing(chat_id)))
+                .await;
+        }
+        let first = 1;
+
+        // reconnect after the first event
+        let sub = state.subscribe(1, Some(first)).await;
+        assert!(!sub.resync);
+        let replay: Vec<_> = sub.replay.iter().map(|e| e.event.clone()).collect();
+        assert_eq!(replay, vec![typing(2), typing(3)]);
+
+        // the first events are evicted from the buffer
+        for chat_id in 4..=6 {
+            state
+                .dispatch(notification(chat_id, vec![1], typing(chat_id)))
+                .await;
+        }
+        let sub = state.subscribe(1, Some(first)).await;
+        assert!(sub.resync);
+        assert!(sub.replay.is_empty());
+
+        // unknown ids require resync too
+        let sub = state.subscribe(1, Some(state.last_event_id() + 1)).await;
+        assert!(sub.resync);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dispatch_should_save_events_to_outbox_once() -> Result<()> {
+        let (_tdb, state) =
+            AppState::new_for_test_with_db(|config| config.replay.outbox = true).await?;
+        for chat_id in 1..=3 {
+            state
+                .dispatch(notification(chat_id, vec![1], typing(chat_id)))
+                .await;
+        }
+        // the same event received by another replica, and the other payload of a split event
+        state.dispatch(notification(3, vec![1], typing(3))).await;
+        state.dispatch(notification(3, vec![2], typing(3))).await;
+
+        let rows: Vec<(i64, Vec<i64>, Json<AppEvent>)> =
+            sqlx::query_as("SELECT id, user_ids, event FROM notify_outbox ORDER BY id")
+                .fetch_all(&state.pool)
+                .await?;
+        let rows: Vec<_> = rows.into_iter().map(|(id, u, e)| (id, u, e.0)).collect();
+        assert_eq!(
+            rows,
+            vec![
+                (1, vec![1], typing(1)),
+                (2, vec![1], typing(2)),
+                (3, vec![1, 2], typing(3))
+            ]
+        );
+        Ok(())
+    }
+}
@@ -1,33 +1,85 @@
-use std::{convert::Infallible, time::Duration};
+use std::{convert::Infallible, sync::Arc, time::Duration};
 
-use axum::response::{
-    sse::{Event, Sse},
-    Html, IntoResponse,
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{
+        sse::{Event, Sse},
+        Html, IntoResponse,
+    },
+    Extension,
 };
 
 use axum_extra::{headers, TypedHeader};
-use futures::{self, stream, Stream};
-use tokio_stream::StreamExt as _;
-use tracing::info;
+use chat_server::User;
+use futures::{stream, Stream};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt as _};
+use tracing::{info, warn};
+
+use crate::{presence::Connection, replay::StreamEvent, AppState};
 
 const INDEX_HTML: &str = include_str!("../index.html");
+const LAST_EVENT_ID: &str = "last-event-id";
+// sent when missed events can't be replayed, clients should reload their state
+const RESYNC_EVENT: &str = "Resync";
+// sent before the stream is closed on shutdown, clients should reconnect to another instance
+const RECONNECT_EVENT: &str = "Reconnect";
 
 pub(crate) async fn sse_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
     TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    headers: HeaderMap,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    info!("`{}` connected", user_agent.as_str());
+    let last_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    info!(
+        "User {} `{}` connected, last event id: {:?}",
+        user.id,
+        user_agent.as_str(),
+        last_id
+    );
+
+    let keep_alive = Duration::from_secs(state.config.stream.keep_alive);
+    let sub = state.subscribe(user.id as _, last_id).await;
+    let conn = Connection::new(state.clone(), user.id as _, "sse");
 
-    // A `Stream` that repeats an event every second
-    //
-    // You can also create streams from tokio channels using the wrappers in
-    // https://docs.rs/tokio-stream
-    let stream = stream::repeat_with(|| Event::default().data("hello, sse client!"))
-        .map(Ok)
-        .throttle(Duration::from_secs(1));
+    let stopping = state.stopping();
+    let resync = sub.resync.then(|| resync_event(&state));
+    let mut last_sent = sub.replay.last().map(|e| e.id).unwrap_or_default();
+    let replay = sub.replay.into_iter().map(to_sse_event);
+    let live = BroadcastStream::new(sub.rx).filter_map(move |event| {
+        // the connection lives as long as the stream
+        let _conn = &conn;
+        match event {
+            // already sent by the replay
+            Ok(event) if event.id <= last_sent => None,
+            Ok(event) => {
+                last_sent = event.id;
+                Some(to_sse_event(event))
+            }
+            Err(e) => {
+                warn!("Events lost for user {}: {}", user.id, e);
+                Some(resync_event(&state))
+            }
+        }
+    });
+    let live = futures::StreamExt::take_until(live, stopping);
+    let reconnect = stream::once(async {
+        Event::default()
+            .event(RECONNECT_EVENT)
+            .data(format!(r#"{{"event":"{RECONNECT_EVENT}"}}"#))
+    });
+    let stream = stream::iter(resync.into_iter().chain(replay))
+        .chain(live)
+        .chain(reconnect)
+        .map(Ok);
 
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
-            .interval(Duration::from_secs(1))
+            .interval(keep_alive)
             .text("keep-alive-text"),
     )
 }
@@ -35,3 +87,19 @@ pub(crate) async fn sse_handler(
 pub(crate) async fn index_handler() -> impl IntoResponse {
     Html(INDEX_HTML)
 }
+
+fn to_sse_event(event: Arc<StreamEvent>) -> Event {
+    let data = serde_json::to_string(&event.event).expect("event should be serializable");
+    Event::default()
+        .id(event.id.to_string())
+        .event(event.event.name())
+        .data(data)
+}
+
+// move the client's last event id forward, so that it doesn't ask for the lost events again
+fn resync_event(state: &AppState) -> Event {
+    Event::default()
+        .id(state.last_event_id().to_string())
+        .event(RESYNC_EVENT)
+        .data(format!(r#"{{"event":"{RESYNC_EVENT}"}}"#))
+}
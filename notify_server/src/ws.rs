@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::IntoResponse,
+    Extension,
+};
+use chat_server::{heartbeat_presence, AppEvent, Notification, TypingEvent, User};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{presence::Connection, replay::StreamEvent, AppState};
+
+/// version of the websocket protocol, bumped on every incompatible change of the frames
+pub const WS_PROTOCOL_VERSION: u32 = 1;
+
+// typing frames of a connection are forwarded at most once in this interval per chat
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// every websocket frame is a json text message: `{"v": 1, "type": "<frame type>", ...fields}`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WsEnvelope<T> {
+    pub v: u32,
+    #[serde(flatten)]
+    pub frame: T,
+}
+
+/// frames sent by the client
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// only receive the events of these chats (and the ones not bound to a chat) from now on
+    Subscribe {
+        chat_ids: Vec<i64>,
+    },
+    /// stop receiving the events of these chats
+    Unsubscribe {
+        chat_ids: Vec<i64>,
+    },
+    /// events up to id have been processed, a reconnection resumes after it
+    Ack {
+        id: u64,
+    },
+    /// tell the other members of the chat that the user is typing
+    Typing {
+        chat_id: i64,
+    },
+    Ping,
+}
+
+/// frames sent by the server
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Event {
+        id: u64,
+        event: AppEvent,
+    },
+    /// missed events can't be replayed, the client should reload its state
+    Resync {
+        id: u64,
+    },
+    Pong,
+    /// the server is shutting down and closes the connection, the client should reconnect
+    Reconnect,
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WsParams {
+    last_event_id: Option<u64>,
+}
+
+// events of the chats a connection receives
+#[derive(Debug, PartialEq)]
+enum ChatFilter {
+    All { except: HashSet<i64> },
+    Only(HashSet<i64>),
+}
+
+struct WsSession {
+    state: AppState,
+    user: User,
+    filter: ChatFilter,
+    last_sent: u64,
+    typing: HashMap<i64, Instant>,
+}
+
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(params): Query<WsParams>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state, user, params.last_event_id))
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState, user: User, last_id: Option<u64>) {
+    let user_id = user.id as u64;
+    let last_id = last_id.or_else(|| state.acked(user_id));
+    info!(
+        "User {} connected over websocket, last event id: {:?}",
+        user_id, last_id
+    );
+
+    let mut stopping = std::pin::pin!(state.stopping());
+    let mut sub = state.subscribe(user_id, last_id).await;
+    let _conn = Connection::new(state.clone(), user_id, "ws");
+    let mut session = WsSession::new(state, user);
+
+    if sub.resync && !send(&mut socket, session.resync()).await {
+        return;
+    }
+    for event in std::mem::take(&mut sub.replay) {
+        if let Some(frame) = session.event(event) {
+            if !send(&mut socket, frame).await {
+                return;
+            }
+        }
+    }
+
+    loop {
+        let frame = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => session.handle(&text).await,
+                // ping/pong are answered by axum, binary frames are not part of the protocol
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => None,
+                Some(Err(e)) => {
+                    warn!("Websocket error for user {}: {}", user_id, e);
+                    break;
+                }
+            },
+            event = sub.rx.recv() => match event {
+                Ok(event) => session.event(event),
+                Err(RecvError::Lagged(n)) => {
+                    warn!("{} events lost for user {}", n, user_id);
+                    Some(session.resync())
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = &mut stopping => {
+                if send(&mut socket, ServerFrame::Reconnect).await {
+                    let frame = CloseFrame {
+                        code: close_code::RESTART,
+                        reason: "server shutting down".into(),
+                    };
+                    let _ = socket.send(Message::Close(Some(frame))).await;
+                }
+                break;
+            }
+        };
+        if let Some(frame) = frame {
+            if !send(&mut socket, frame).await {
+                break;
+            }
+        }
+    }
+}
+
+// false if the socket is gone
+async fn send(socket: &mut WebSocket, frame: ServerFrame) -> bool {
+    let envelope = WsEnvelope {
+        v: WS_PROTOCOL_VERSION,
+        frame,
+    };
+    let text = serde_json::to_string(&envelope).expect("frame should be serializable");
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+impl WsSession {
+    fn new(state: AppState, user: User) -> Self {
+        Self {
+            state,
+            user,
+            filter: ChatFilter::default(),
+            last_sent: 0,
+            typing: HashMap::new(),
+        }
+    }
+
+    fn event(&mut self, event: Arc<StreamEvent>) -> Option<ServerFrame> {
+        // already sent by the replay
+        if event.id <= self.last_sent {
+            return None;
+        }
+        self.last_sent = event.id;
+        if !self.filter.allows(&event.event) {
+            return None;
+        }
+        Some(ServerFrame::Event {
+            id: event.id,
+            event: event.event.clone(),
+        })
+    }
+
+    fn resync(&mut self) -> ServerFrame {
+        self.last_sent = self.state.last_event_id();
+        ServerFrame::Resync { id: self.last_sent }
+    }
+
+    async fn handle(&mut self, text: &str) -> Option<ServerFrame> {
+        let envelope: WsEnvelope<ClientFrame> = match serde_json::from_str(text) {
+            Ok(envelope) => envelope,
+            Err(e) => return Some(error_frame(format!("invalid frame: {e}"))),
+        };
+        if envelope.v != WS_PROTOCOL_VERSION {
+            return Some(error_frame(format!(
+                "unsupported protocol version {}, expect {}",
+                envelope.v, WS_PROTOCOL_VERSION
+            )));
+        }
+        match envelope.frame {
+            ClientFrame::Subscribe { chat_ids } => self.filter.subscribe(chat_ids),
+            ClientFrame::Unsubscribe { chat_ids } => self.filter.unsubscribe(chat_ids),
+            ClientFrame::Ack { id } => self.state.ack(self.user.id as _, id),
+            ClientFrame::Ping => {
+                // the same heartbeat as the api of the sse clients, an away user is online again
+                if let Err(e) = heartbeat_presence(&self.state.pool, self.user.id).await {
+                    warn!(
+                        "Failed to record the heartbeat of user {}: {}",
+                        self.user.id, e
+                    );
+                }
+                return Some(ServerFrame::Pong);
+            }
+            ClientFrame::Typing { chat_id } => {
+                if let Err(e) = self.typing(chat_id).await {
+                    return Some(error_frame(e.to_string()));
+                }
+            }
+        }
+        None
+    }
+
+    async fn typing(&mut self, chat_id: i64) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if self
+            .typing
+            .get(&chat_id)
+            .is_some_and(|last| now.duration_since(*last) < TYPING_INTERVAL)
+        {
+            return Ok(());
+        }
+        // the members which are active in the workspace, like send_typing of the chat server
+        let members: Option<(Vec<i64>, Vec<i64>)> = sqlx::query_as(
+            r#"
+            SELECT c.members, ARRAY(
+                SELECT m.user_id
+                FROM workspace_members m
+                WHERE m.ws_id = c.ws_id AND m.user_id = ANY(c.members)
+                    AND m.deactivated_at IS NULL
+            )
+            FROM chats c
+            WHERE c.id = $1
+            "#,
+        )
+        .bind(chat_id)
+        .fetch_optional(&self.state.pool)
+        .await?;
+        let Some((_, active)) = members.filter(|(m, _)| m.contains(&self.user.id)) else {
+            anyhow::bail!("user {} is not member of chat {}", self.user.id, chat_id);
+        };
+        self.typing.insert(chat_id, now);
+
+        let receivers = active
+            .into_iter()
+            .filter(|id| *id != self.user.id)
+            .collect();
+        let event = TypingEvent {
+            chat_id,
+            user_id: self.user.id,
+        };
+        Notification::new(receivers, AppEvent::Typing(event))
+            .publish(&self.state.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+fn error_frame(message: String) -> ServerFrame {
+    ServerFrame::Error { message }
+}
+
+impl Default for ChatFilter {
+    fn default() -> Self {
+        ChatFilter::All {
+            except: HashSet::new(),
+        }
+    }
+}
+
+impl ChatFilter {
+    fn allows(&self, event: &AppEvent) -> bool {
+        match (self, event.chat_id()) {
+            (_, None) => true,
+            (ChatFilter::All { except }, Some(id)) => !except.contains(&id),
+            (ChatFilter::Only(chats), Some(id)) => chats.contains(&id),
+        }
+    }
+
+    // the first subscription narrows the events to the subscribed chats
+    fn subscribe(&mut self, chat_ids: Vec<i64>) {
+        match self {
+            ChatFilter::All { .. } => *self = ChatFilter::Only(chat_ids.into_iter().collect()),
+            ChatFilter::Only(chats) => chats.extend(chat_ids),
+        }
+    }
+
+    fn unsubscribe(&mut self, chat_ids: Vec<i64>) {
+        match self {
+            ChatFilter::All { except } => except.extend(chat_ids),
+            ChatFilter::Only(chats) => chats.retain(|id| !chat_ids.contains(id)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_server::{PresenceEvent, PresenceStatus, NOTIFY_CHANNEL};
+    use sqlx::postgres::PgListener;
+
+    #[test]
+    fn ws_frames_should_be_versioned() -> Result<()> {
+        let frame: WsEnvelope<ClientFrame> =
+            serde_json::from_str(r#"{"v":1,"type":"subscribe","chat_ids":[1,2]}"#)?;
+        assert_eq!(frame.v, WS_PROTOCOL_VERSION);
+        assert_eq!(
+            frame.frame,
+            ClientFrame::Subscribe {
+                chat_ids: vec![1, 2]
+            }
+        );
+
+        let frame: WsEnvelope<ClientFrame> = serde_json::from_str(r#"{"v":1,"type":"ping"}"#)?;
+        assert_eq!(frame.frame, ClientFrame::Ping);
+
+        let envelope = WsEnvelope {
+            v: WS_PROTOCOL_VERSION,
+            frame: ServerFrame::Resync { id: 42 },
+        };
+        assert_eq!(
+            serde_json::to_string(&envelope)?,
+            r#"{"v":1,"type":"resync","id":42}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chat_filter_should_work() {
+        let typing = |chat_id| {
+            AppEvent::Typing(TypingEvent {
+                chat_id,
+                user_id: 2,
+            })
+        };
+        let presence = AppEvent::PresenceChanged(PresenceEvent {
+            user_id: 2,
+            status: PresenceStatus::Online,
+        });
+
+        let mut filter = ChatFilter::default();
+        assert!(filter.allows(&typing(1)));
+        filter.unsubscribe(vec![1]);
+        assert!(!filter.allows(&typing(1)));
+        assert!(filter.allows(&typing(2)));
+
+        filter.subscribe(vec![1, 3]);
+        assert!(filter.allows(&typing(1)));
+        assert!(!filter.allows(&typing(2)));
+        filter.unsubscribe(vec![3]);
+        assert!(!filter.allows(&typing(3)));
+        // events not bound to a chat are always delivered
+        assert!(filter.allows(&presence));
+    }
+
+    #[tokio::test]
+    async fn typing_should_skip_deactivated_members() -> Result<()> {
+        let (tdb, state) = AppState::new_for_test_with_db(|_| {}).await?;
+        sqlx::query("UPDATE workspace_members SET deactivated_at = now() WHERE user_id = 3")
+            .execute(&state.pool)
+            .await?;
+        let mut listener = PgListener::connect(&tdb.url()).await?;
+        listener.listen(NOTIFY_CHANNEL).await?;
+        let user = User {
+            id: 1,
+            ws_id: 1,
+            fullname: "Alice".to_string(),
+            email: "alice@test.org".to_string(),
+            password_hash: None,
+            created_at: chrono::Utc::now(),
+        };
+        let mut session = WsSession::new(state, user);
+
+        // chat 4 is the group of users 1, 2 and 3
+        session.typing(4).await?;
+        let notification: Notification = serde_json::from_str(listener.recv().await?.payload())?;
+        assert_eq!(notification.user_ids, vec![2]);
+        Ok(())
+    }
+}